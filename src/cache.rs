@@ -0,0 +1,164 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use config::Config;
+use errors::*;
+use flock::{self, FileLock, Filesystem};
+use gc::{self, Entry, Policy};
+use util::{self, CopyMode};
+
+/// A sysroot cache shared across projects
+///
+/// Each entry lives in `$CACHE/$TRIPLE/$HASH`, where `$HASH` is the sysroot hash computed by
+/// `sysroot::hash`.
+pub struct Cache {
+    path: Filesystem,
+}
+
+impl Cache {
+    /// Returns the cache configured through `XBUILD_SYSROOT_CACHE` or the `sysroot_cache`
+    /// metadata key, if any
+    pub fn from_env(config: &Config) -> Result<Option<Cache>> {
+        let path = if let Some(path) = env::var_os("XBUILD_SYSROOT_CACHE") {
+            PathBuf::from(path)
+        } else if config.sysroot_cache {
+            default_path()?
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(Cache {
+            path: Filesystem::new(path),
+        }))
+    }
+
+    fn lock(&self, triple: &str, hash: u64) -> Result<FileLock> {
+        let what = "the sysroot cache";
+        flock::chain_lock_err(
+            self.path
+                .join(triple)
+                .open_rw(format!("{:016x}.lock", hash), what),
+            what,
+            || format!("couldn't lock the sysroot cache in {}", self.path.display()),
+        )
+    }
+
+    /// Places the cached sysroot for `triple` and `hash` into `dst`
+    ///
+    /// Returns `false` if the cache has no such entry.
+    pub fn fetch(&self, triple: &str, hash: u64, dst: &Path) -> Result<bool> {
+        let lock = self.lock(triple, hash)?;
+        let entry = lock.parent().join(format!("{:016x}", hash));
+        if !entry.is_dir() {
+            return Ok(false);
+        }
+
+        // Symlinks would break once the entry is evicted from the cache
+        let mode = match CopyMode::from_env()? {
+            CopyMode::Auto | CopyMode::Symlink => CopyMode::LinkOrCopy,
+            mode => mode,
+        };
+        util::link_r(&entry, dst, mode)?;
+        gc::touch(&lock.parent().join(format!("{:016x}.last-used", hash)))?;
+
+        Ok(true)
+    }
+
+    /// Publishes the sysroot in `src` as the entry for `triple` and `hash`
+    ///
+    /// The entry is first copied into a temporary directory and then renamed, so other
+    /// processes never observe a partially written entry.
+    pub fn publish(&self, triple: &str, hash: u64, src: &Path) -> Result<()> {
+        let lock = self.lock(triple, hash)?;
+        let entry = lock.parent().join(format!("{:016x}", hash));
+        if entry.is_dir() {
+            return Ok(());
+        }
+
+        let tmp = lock
+            .parent()
+            .join(format!(".tmp-{:016x}-{}", hash, process::id()));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)
+                .chain_err(|| format!("couldn't remove {}", tmp.display()))?;
+        }
+        util::cp_r(src, &tmp)?;
+        fs::rename(&tmp, &entry)
+            .chain_err(|| format!("couldn't move {} to {}", tmp.display(), entry.display()))?;
+        gc::touch(&lock.parent().join(format!("{:016x}.last-used", hash)))
+    }
+
+    /// Evicts the entries selected by `policy`
+    pub fn gc(&self, policy: &Policy) -> Result<Vec<Entry>> {
+        let root = self.path.as_path_unlocked();
+        let mut keys = vec![];
+        let mut entries = vec![];
+        for triple in read_dirs(root)? {
+            for entry in read_dirs(&root.join(&triple))? {
+                if let Ok(hash) = u64::from_str_radix(&entry, 16) {
+                    let path = root.join(&triple).join(&entry);
+                    entries.push(Entry {
+                        name: format!("{}/{}", triple, entry),
+                        last_used: gc::last_used(&path.with_extension("last-used")),
+                        size: util::dir_size(&path),
+                    });
+                    keys.push((triple.clone(), hash));
+                }
+            }
+        }
+
+        let mut removed = vec![];
+        for i in policy.select(&entries, gc::now()) {
+            let (ref triple, hash) = keys[i];
+            let lock = self.lock(triple, hash)?;
+            let entry = lock.parent().join(format!("{:016x}", hash));
+            fs::remove_dir_all(&entry)
+                .chain_err(|| format!("couldn't remove {}", entry.display()))?;
+            fs::remove_file(entry.with_extension("last-used")).ok();
+            removed.push(Entry {
+                name: format!("{} in the sysroot cache", entries[i].name),
+                last_used: entries[i].last_used,
+                size: entries[i].size,
+            });
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Returns the names of the subdirectories of `dir`, ignoring hidden ones
+fn read_dirs(dir: &Path) -> Result<Vec<String>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut dirs = vec![];
+    for entry in dir
+        .read_dir()
+        .chain_err(|| format!("couldn't read {}", dir.display()))?
+    {
+        let entry = entry.chain_err(|| format!("couldn't read {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_dir() && !name.starts_with('.') {
+            dirs.push(name);
+        }
+    }
+    Ok(dirs)
+}
+
+/// `$XDG_CACHE_HOME/cargo-xbuild`, falling back to `~/.cache/cargo-xbuild` (or
+/// `%LOCALAPPDATA%\cargo-xbuild` on Windows)
+fn default_path() -> Result<PathBuf> {
+    if let Some(cache) = env::var_os("XDG_CACHE_HOME") {
+        Ok(PathBuf::from(cache).join("cargo-xbuild"))
+    } else if let Some(home) = env::var_os("HOME") {
+        Ok(PathBuf::from(home).join(".cache").join("cargo-xbuild"))
+    } else if let Some(local) = env::var_os("LOCALAPPDATA") {
+        Ok(PathBuf::from(local).join("cargo-xbuild"))
+    } else {
+        Err("couldn't determine the default sysroot cache directory; \
+             set `XBUILD_SYSROOT_CACHE` to choose one")?
+    }
+}
@@ -1,60 +1,164 @@
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
+use std::fs;
 use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Stdio};
 use std::{env, fmt};
 
+use cargo_metadata;
+use serde_json;
 use toml::Value;
 
 use cli::Args;
+use config::SysrootProfile;
 use errors::*;
 use extensions::CommandExt;
-use util;
+use rustc::Toolchain;
+use status::{self, StatusEvent};
+use sysroot::SYSROOT_CRATES;
+use {rustc, util};
 use xargo::Home;
 
+#[derive(Clone)]
 pub struct Rustflags {
     flags: Vec<String>,
 }
 
 impl Rustflags {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
+    pub fn new(flags: Vec<String>) -> Rustflags {
+        Rustflags {
+            flags: dedup_codegen_flags(flags),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[String] {
+        &self.flags
+    }
+
+    /// Appends `flags`, which aren't deduplicated
+    pub fn push(&mut self, flags: &[&str]) {
+        self.flags.extend(flags.iter().map(|f| (*f).to_owned()));
+    }
+
+    /// The unstable `-Z` options among these flags, e.g. `unstable-options`
+    pub fn unstable_options(&self) -> Vec<&str> {
+        let mut options = vec![];
         let mut flags = self.flags.iter();
+        while let Some(flag) = flags.next() {
+            if flag == "-Z" {
+                options.extend(flags.next().map(|o| &**o));
+            } else if let Some(option) = flag.strip_prefix("-Z") {
+                options.push(option);
+            }
+        }
+        options
+    }
 
+    /// The value of a `--sysroot` among these flags, given as `--sysroot path` or
+    /// `--sysroot=path`
+    pub fn sysroot(&self) -> Option<&str> {
+        let mut flags = self.flags.iter();
         while let Some(flag) = flags.next() {
-            if flag == "-C" {
-                if let Some(next) = flags.next() {
-                    if next.starts_with("link-arg=") || next.starts_with("link-args=") {
-                        // don't hash linker arguments
-                    } else {
-                        flag.hash(hasher);
-                        next.hash(hasher);
-                    }
-                } else {
-                    flag.hash(hasher);
+            if flag == "--sysroot" {
+                return flags.next().map(|s| &**s);
+            } else if let Some(sysroot) = flag.strip_prefix("--sysroot=") {
+                return Some(sysroot);
+            }
+        }
+        None
+    }
+
+    /// The arguments that these flags pass to the linker through `-C link-arg` and
+    /// `-C link-args`
+    pub fn link_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for (flag, value) in normalize_flags(&self.flags) {
+            match (&*flag, value) {
+                ("-C", Some(ref value)) if value.starts_with("link-arg=") => {
+                    args.push(value["link-arg=".len()..].to_owned())
                 }
-            } else {
-                flag.hash(hasher);
+                ("-C", Some(ref value)) if value.starts_with("link-args=") => {
+                    args.extend(split_flags(&value["link-args=".len()..]))
+                }
+                _ => {}
             }
         }
+        args
     }
 
-    /// Stringifies these flags for Xargo consumption
-    pub fn for_xargo(&self, home: &Home) -> Result<String> {
-        let sysroot = format!("{}", home.display());
-        if env::var_os("XBUILD_ALLOW_SYSROOT_SPACES").is_none() && sysroot.contains(" ") {
-            return Err(format!("Sysroot must not contain spaces!\n\
-            See issue https://github.com/rust-lang/cargo/issues/6139\n\n\
-            The sysroot is `{}`.\n\n\
-            To override this error, you can set the `XBUILD_ALLOW_SYSROOT_SPACES`\
-            environment variable.", sysroot).into());
+    /// The flags, each with its value, in sorted order
+    ///
+    /// The flags are normalized first, so that e.g. `-Copt-level=3` and `-C opt-level=3`
+    /// compare alike, and a flag like `--cfg` is kept together with its value. Sorting makes
+    /// the result independent of the order in which the environment and `.cargo/config`
+    /// contributed the flags. The occurrences of an option in `REPEATABLE_CODEGEN_OPTIONS` keep
+    /// their order, which matters e.g. for `-C target-feature=+sse2 -C target-feature=-sse2`.
+    fn sorted(&self) -> Vec<(String, Option<String>)> {
+        let mut sorted = vec![];
+        let mut flags = normalize_flags(&self.flags).into_iter().peekable();
+        while let Some((flag, mut value)) = flags.next() {
+            let takes_value = value.is_none()
+                && flag.starts_with('-')
+                && !flag.contains('=')
+                && flags
+                    .peek()
+                    .is_some_and(|(next, v)| v.is_none() && !next.starts_with('-'));
+            if takes_value {
+                value = flags.next().map(|(next, _)| next);
+            }
+            sorted.push((flag, value));
         }
+        sorted.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+        sorted
+    }
+
+    /// Hashes the flags that affect the compiled sysroot
+    ///
+    /// The flags are hashed in sorted order (see `sorted`). Search paths (`-L`) and the options
+    /// in `UNHASHED_OPTIONS` are skipped, so that changing them doesn't rebuild the sysroot.
+    pub fn hash<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        for (flag, value) in self.sorted() {
+            let option = value.as_ref().map(|v| v.split('=').next().unwrap_or(""));
+            let unhashed = match (&*flag, option) {
+                ("-L", _) => true,
+                ("-C", Some(option)) | ("-Z", Some(option)) => UNHASHED_OPTIONS.contains(&option),
+                _ => false,
+            };
+            if unhashed {
+                continue;
+            }
+            flag.hash(hasher);
+            if let Some(value) = value {
+                value.hash(hasher);
+            }
+        }
+    }
+
+    /// All flags in sorted order (see `sorted`), for recording them in the fingerprint of the
+    /// sysroot
+    pub fn to_sorted_string(&self) -> String {
+        self.sorted()
+            .into_iter()
+            .map(|(flag, value)| match value {
+                Some(value) if flag.len() == 2 => format!("{}{}", flag, value),
+                Some(value) => format!("{} {}", flag, value),
+                None => flag,
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// These flags plus the `--sysroot` of `home`, for the `cargo` that builds the crate
+    pub fn for_xargo(&self, home: &Home) -> Vec<String> {
         let mut flags = self.flags.clone();
         flags.push("--sysroot".to_owned());
-        flags.push(sysroot);
-        Ok(flags.join(" "))
+        flags.push(format!("{}", home.display()));
+        flags
     }
 }
 
@@ -64,103 +168,700 @@ impl fmt::Display for Rustflags {
     }
 }
 
-pub fn rustflags(config: Option<&Config>, target: &str) -> Result<Rustflags> {
-    flags(config, target, "rustflags").map(|fs| Rustflags { flags: fs })
+pub fn rustflags(
+    config: Option<&Config>,
+    target: &str,
+    cfgs: &[String],
+    quiet: bool,
+) -> Result<Rustflags> {
+    flags(config, target, cfgs, "rustflags", quiet, merge_flags()).map(Rustflags::new)
+}
+
+/// Returns the flags for `rustdoc`, which are collected like those of `rustflags`
+pub fn rustdocflags(
+    config: Option<&Config>,
+    target: &str,
+    cfgs: &[String],
+    quiet: bool,
+) -> Result<Rustflags> {
+    flags(config, target, cfgs, "rustdocflags", quiet, merge_flags()).map(Rustflags::new)
 }
 
 /// Returns the flags for `tool` (e.g. rustflags)
 ///
-/// This looks into the environment and into `.cargo/config`
-fn flags(config: Option<&Config>, target: &str, tool: &str) -> Result<Vec<String>> {
-    if let Some(t) = env::var_os(tool.to_uppercase()) {
-        return Ok(t
-            .to_string_lossy()
-            .split_whitespace()
-            .map(|w| w.to_owned())
-            .collect());
-    }
-
-    if let Some(config) = config.as_ref() {
-        let mut build = false;
-        if let Some(array) = config
-            .table
-            .lookup(&format!("target.{}.{}", target, tool))
-            .or_else(|| {
-                build = true;
-                config.table.lookup(&format!("build.{}", tool))
-            })
-        {
-            let mut flags = vec![];
-
-            let mut error = false;
-            if let Some(array) = array.as_slice() {
-                for value in array {
-                    if let Some(flag) = value.as_str() {
-                        flags.push(flag.to_owned());
-                    } else {
-                        error = true;
-                        break;
-                    }
-                }
+/// This looks into the environment and into `.cargo/config`. Like in cargo,
+/// `CARGO_ENCODED_RUSTFLAGS` (whose flags are separated by `0x1f`, so that they may contain
+/// spaces) takes precedence over `RUSTFLAGS` (or the `[env]` table), which replaces the flags
+/// of `.cargo/config`; unless `quiet` is set, a warning is printed when this masks different
+/// flags. With `merge`, the flags of `.cargo/config` are appended to those of the environment
+/// instead.
+fn flags(
+    config: Option<&Config>,
+    target: &str,
+    cfgs: &[String],
+    tool: &str,
+    quiet: bool,
+    merge: bool,
+) -> Result<Vec<String>> {
+    let var = tool.to_uppercase();
+    let encoded_var = format!("CARGO_ENCODED_{}", var);
+    let config_var = match config {
+        Some(config) => config.env(&var)?,
+        None => None,
+    };
+
+    // The process environment takes precedence over the `[env]` table, unless the entry is
+    // marked as `force`
+    let value = match config_var {
+        Some(ref config_var) if config_var.force => Some((&var, split_flags(&config_var.value))),
+        _ => match env::var_os(&encoded_var) {
+            Some(ref t) if t.is_empty() => Some((&encoded_var, vec![])),
+            Some(t) => Some((
+                &encoded_var,
+                t.to_string_lossy()
+                    .split('\x1f')
+                    .map(|f| f.to_owned())
+                    .collect(),
+            )),
+            None => env::var_os(&var)
+                .map(|t| split_flags(&t.to_string_lossy()))
+                .or_else(|| config_var.map(|v| split_flags(&v.value)))
+                .map(|flags| (&var, flags)),
+        },
+    };
+
+    // `.cargo/config` is only read if its flags are used or may be masked
+    let read_config_flags = || -> Result<Vec<String>> {
+        let flags = config_flags(config, target, cfgs, tool)?;
+        if env::var_os("XBUILD_EXPAND_CONFIG_VARS").is_some_and(|v| v == "1") {
+            flags.iter().map(|flag| expand_vars(flag)).collect()
+        } else {
+            Ok(flags)
+        }
+    };
+    match value {
+        // Opt-in layering of the environment over `.cargo/config`
+        Some((_, mut flags)) if merge => {
+            // Conflicting `-C` flags are collapsed by `Rustflags::new`
+            flags.extend(read_config_flags()?);
+            Ok(flags)
+        }
+        Some((var, flags)) => {
+            // The masked flags don't matter for the build, so neither do their errors
+            let masked = !quiet
+                && !flags.is_empty()
+                && read_config_flags().is_ok_and(|config_flags| masks(&flags, &config_flags));
+            if masked {
+                writeln!(
+                    io::stderr(),
+                    "{} `{}` from the environment overrides the {} of .cargo/config",
+                    util::level("warning"),
+                    var,
+                    tool
+                )
+                .ok();
+            }
+            Ok(flags)
+        }
+        None => read_config_flags(),
+    }
+}
+
+/// Whether `XBUILD_MERGE_RUSTFLAGS` layers the environment over `.cargo/config`
+fn merge_flags() -> bool {
+    env::var_os("XBUILD_MERGE_RUSTFLAGS").is_some_and(|v| v == "1")
+}
+
+/// Whether the `flags` of the environment replace different `config_flags`
+fn masks(flags: &[String], config_flags: &[String]) -> bool {
+    !flags.is_empty() && !config_flags.is_empty() && flags != config_flags
+}
+
+/// Replaces each `${NAME}` in `flag` with the value of the environment variable `NAME`
+fn expand_vars(flag: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = flag;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| {
+                ErrorKind::InvalidConfig(format!(".cargo/config: unterminated `${{` in `{}`", flag))
+            })?;
+        let name = &rest[start + 2..end];
+        let value = env::var(name).map_err(|_| {
+            ErrorKind::InvalidConfig(format!(
+                ".cargo/config: environment variable `{}` in `{}` is not set",
+                name, flag
+            ))
+        })?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// `-C` and `-Z` options that only affect linking or where intermediate artifacts are kept, and
+/// thus not the `.rlib`s of the sysroot, see `Rustflags::hash`
+const UNHASHED_OPTIONS: &[&str] = &[
+    "incremental",
+    "link-arg",
+    "link-args",
+    "link-self-contained",
+    "linker",
+    "pre-link-arg",
+    "pre-link-args",
+];
+
+/// Splits `flags` into the flags that take a value (`-C`, `-Z` and `-L`) and their values
+///
+/// All spellings are recognized, e.g. `-Copt-level=3`, `-C opt-level=3`, `--codegen
+/// opt-level=3` and `--codegen=opt-level=3` all become `("-C", Some("opt-level=3"))`.
+fn normalize_flags(flags: &[String]) -> Vec<(String, Option<String>)> {
+    let mut normalized = vec![];
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let flag = if flag == "--codegen" {
+            "-C".to_owned()
+        } else if let Some(value) = flag.strip_prefix("--codegen=") {
+            format!("-C{}", value)
+        } else {
+            flag.clone()
+        };
+        let prefix = flag.get(..2).unwrap_or("").to_owned();
+        if prefix == "-C" || prefix == "-Z" || prefix == "-L" {
+            let value = match &flag[2..] {
+                "" => flags.next().cloned(),
+                value => Some(value.to_owned()),
+            };
+            normalized.push((prefix, value));
+        } else {
+            normalized.push((flag, None));
+        }
+    }
+    normalized
+}
+
+/// The key of a normalized flag for `Rustflags::sorted`: a repeatable `-C` option sorts by
+/// its name, so that a stable sort keeps its occurrences in order
+fn sort_key((flag, value): &(String, Option<String>)) -> (&str, Option<&str>) {
+    let value = value.as_ref().map(|v| &**v);
+    match value.map(|v| v.split('=').next().unwrap_or("")) {
+        Some(option) if flag == "-C" && REPEATABLE_CODEGEN_OPTIONS.contains(&option) => {
+            (flag, Some(option))
+        }
+        _ => (flag, value),
+    }
+}
+
+/// `-C` options that may be passed several times, with all occurrences taking effect
+const REPEATABLE_CODEGEN_OPTIONS: &[&str] = &[
+    "link-arg",
+    "link-args",
+    "llvm-args",
+    "passes",
+    "remark",
+    "target-feature",
+];
+
+/// Removes all but the last of the `-C` flags that set the same option, as `rustc` only uses
+/// the last one
+///
+/// `-C opt-level=2 -C opt-level=3` becomes `-C opt-level=3`. All spellings of the flag (`-C`,
+/// `-Ckey`, `--codegen key` and `--codegen=key`) are recognized. Options without a value,
+/// e.g. `-C lto`, and the options in `REPEATABLE_CODEGEN_OPTIONS` are kept as they are.
+fn dedup_codegen_flags(flags: Vec<String>) -> Vec<String> {
+    // Groups each flag with its value, e.g. `["-C", "opt-level=3"]`, along with the
+    // codegen option it sets, if any
+    let mut groups: Vec<(Option<String>, Vec<String>)> = vec![];
+    let mut flags = flags.into_iter();
+    while let Some(flag) = flags.next() {
+        let (option, group) = if flag == "-C" || flag == "--codegen" {
+            match flags.next() {
+                Some(value) => (Some(value.clone()), vec![flag, value]),
+                None => (None, vec![flag]),
+            }
+        } else if let Some(value) = flag.strip_prefix("--codegen=") {
+            (Some(value.to_owned()), vec![flag])
+        } else if let Some(value) = flag.strip_prefix("-C") {
+            (Some(value.to_owned()), vec![flag])
+        } else {
+            (None, vec![flag])
+        };
+        let key = option.and_then(|o| {
+            let mut parts = o.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            if parts.next().is_none() || REPEATABLE_CODEGEN_OPTIONS.contains(&key) {
+                None
             } else {
-                error = true;
-            }
-
-            if error {
-                if build {
-                    Err(format!(
-                        ".cargo/config: build.{} must be an array \
-                         of strings",
-                        tool
-                    ))?
-                } else {
-                    Err(format!(
-                        ".cargo/config: target.{}.{} must be an \
-                         array of strings",
-                        target, tool
-                    ))?
+                Some(key.to_owned())
+            }
+        });
+        groups.push((key, group));
+    }
+
+    let mut deduped = vec![];
+    for (i, (key, group)) in groups.iter().enumerate() {
+        let overridden = key.is_some() && groups[i + 1..].iter().any(|(k, _)| k == key);
+        if !overridden {
+            deduped.extend(group.iter().cloned());
+        }
+    }
+    deduped
+}
+
+fn split_flags(flags: &str) -> Vec<String> {
+    flags.split_whitespace().map(|w| w.to_owned()).collect()
+}
+
+/// Returns the flags for `tool` from `.cargo/config`
+///
+/// Like in cargo, the flags of `target.<triple>` come first, followed by the flags of all
+/// `target.'cfg(..)'` sections that match `cfgs`, the output of `rustc --print cfg`, in the
+/// order of their expressions rather than of the file, so that the result is deterministic.
+/// `build.<tool>` is only used if none of these sections sets `tool`. The environment
+/// variables `CARGO_TARGET_<TRIPLE>_<TOOL>` and `CARGO_BUILD_<TOOL>` add to `target.<triple>`
+/// and `build`, respectively, even without a `.cargo/config`.
+fn config_flags(
+    config: Option<&Config>,
+    target: &str,
+    cfgs: &[String],
+    tool: &str,
+) -> Result<Vec<String>> {
+    let env_flags = |var: &str| env::var(var).ok().map(|t| split_flags(&t));
+    let build_var = format!("CARGO_BUILD_{}", tool.to_uppercase());
+
+    let mut flags = vec![];
+    let mut found = false;
+    if let Some(array) = config.and_then(|c| c.table.lookup(&format!("target.{}.{}", target, tool)))
+    {
+        found = true;
+        flags.extend(string_array(
+            array,
+            &format!("target.{}.{}", target, tool),
+            config.map_or(".cargo/config", |c| c.source(&["target", target, tool])),
+        )?);
+    }
+    if let Some(env) = env_flags(&target_env_var(target, tool)) {
+        found = true;
+        flags.extend(env);
+    }
+    let config = match config {
+        Some(config) => config,
+        None if found => return Ok(flags),
+        None => return Ok(env_flags(&build_var).unwrap_or_default()),
+    };
+    if let Some(targets) = config.table.lookup("target").and_then(|t| t.as_table()) {
+        for (expr, table) in targets {
+            if rustc::cfg_matches(expr, cfgs) != Some(true) {
+                continue;
+            }
+            if let Some(array) = table.lookup(tool) {
+                found = true;
+                flags.extend(string_array(
+                    array,
+                    &format!("target.'{}'.{}", expr, tool),
+                    config.source(&["target", expr, tool]),
+                )?);
+            }
+        }
+    }
+
+    if found {
+        return Ok(flags);
+    }
+    if let Some(array) = config.table.lookup(&format!("build.{}", tool)) {
+        flags = string_array(
+            array,
+            &format!("build.{}", tool),
+            config.source(&["build", tool]),
+        )?;
+    }
+    flags.extend(env_flags(&build_var).unwrap_or_default());
+    Ok(flags)
+}
+
+/// Converts the value of `key` in `.cargo/config` into a list of strings
+///
+/// Like in cargo, a single string is split at whitespace. `source` is the file that sets the
+/// value, see `Config::source`.
+fn string_array(value: &Value, key: &str, source: &str) -> Result<Vec<String>> {
+    if let Some(s) = value.as_str() {
+        return Ok(s.split_whitespace().map(|s| s.to_owned()).collect());
+    }
+    match value.as_slice() {
+        Some(array) if array.iter().all(|v| v.as_str().is_some()) => Ok(array
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(|s| s.to_owned())
+            .collect()),
+        _ => Err(ErrorKind::ConfigError(
+            source.into(),
+            key.to_owned(),
+            "must be a string or an array of strings".to_owned(),
+        ))?,
+    }
+}
+
+/// Sets the variables of the `[env]` table on `cmd`, except for the ones in `skip`
+///
+/// Variables that are already set in the environment are only overridden if they are marked
+/// as `force`.
+pub fn apply_env(config: Option<&Config>, cmd: &mut Command, skip: &[&str]) -> Result<()> {
+    if let Some(config) = config {
+        for (name, var) in config.env_vars()? {
+            if skip.contains(&&*name) || (!var.force && env::var_os(&name).is_some()) {
+                continue;
+            }
+            cmd.env(name, var.value);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the name of the environment variable that overrides `target.<triple>.<key>`
+pub fn target_env_var(triple: &str, key: &str) -> String {
+    format!(
+        "CARGO_TARGET_{}_{}",
+        triple.to_uppercase().replace(['-', '.'], "_"),
+        key.to_uppercase()
+    )
+}
+
+/// Passes `target.<triple>.linker` and `target.<triple>.ar` to `cmd`, unless they are already
+/// set in the environment
+///
+/// The child `cargo` doesn't necessarily see the same `.cargo/config`, e.g. when building the
+/// sysroot, but the sysroot crates must be built with the same tools as the crate itself.
+pub fn apply_target_tools(config: Option<&Config>, cmd: &mut Command, triple: &str) -> Result<()> {
+    if let Some(config) = config {
+        for key in &["linker", "ar"] {
+            if let Some(tool) = config.target_path(triple, key)? {
+                let var = target_env_var(triple, key);
+                if env::var_os(&var).is_none() {
+                    cmd.env(var, tool);
                 }
-            } else {
-                Ok(flags)
             }
-        } else {
-            Ok(vec![])
         }
-    } else {
-        Ok(vec![])
     }
+    Ok(())
+}
+
+/// Passes `build.rustc-wrapper` and `build.rustc-workspace-wrapper` to `cmd`, unless
+/// `RUSTC_WRAPPER` and `RUSTC_WORKSPACE_WRAPPER` are already set in the environment
+///
+/// Like for `apply_target_tools`, the sysroot build doesn't necessarily see the same
+/// `.cargo/config`, but a wrapper like `sccache` should cache it too.
+pub fn apply_rustc_wrappers(config: Option<&Config>, cmd: &mut Command) -> Result<()> {
+    if let Some(config) = config {
+        for &(key, var) in &[
+            ("build.rustc-wrapper", "RUSTC_WRAPPER"),
+            ("build.rustc-workspace-wrapper", "RUSTC_WORKSPACE_WRAPPER"),
+        ] {
+            if let Some(wrapper) = config.path(key)? {
+                if env::var_os(var).is_none() {
+                    cmd.env(var, wrapper);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The version of the `cargo` that builds the crate, e.g. `1.78.0`, as `cargo -V` reports it
+pub fn version(toolchain: &Toolchain, verbose: bool) -> Result<String> {
+    let out = toolchain.cargo().arg("-V").run_and_get_stdout(verbose)?;
+    // e.g. `cargo 1.78.0 (54d8815d0 2024-03-26)`
+    match out.split_whitespace().nth(1) {
+        Some(version) => Ok(version.to_owned()),
+        None => bail!("couldn't parse the version of cargo from `{}`", out.trim()),
+    }
+}
+
+/// Whether the cargo of `version` understands `CARGO_ENCODED_RUSTFLAGS`, which cargo 1.55
+/// introduced
+pub fn supports_encoded_flags(version: &str) -> bool {
+    let mut parts = version
+        .split(['.', '-'])
+        .map(|part| part.parse::<u64>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0)) >= (1, 55)
+}
+
+/// `cargo metadata` of the package at the `--manifest-path` of `args`, or of the current
+/// directory, with its dependencies if `deps` is set
+///
+/// The dependencies are resolved with the `--offline`, `--locked` and `--frozen` flags of
+/// `args`, like the build itself. The errors of cargo, e.g. about an invalid `Cargo.toml`, are
+/// shown on stderr.
+pub fn metadata(
+    toolchain: &Toolchain,
+    args: &Args,
+    deps: bool,
+) -> Result<cargo_metadata::Metadata> {
+    let mut cmd = toolchain.cargo();
+    cmd.arg("metadata");
+    if !deps {
+        cmd.arg("--no-deps");
+    }
+    cmd.args(["--format-version", "1"]);
+    if let Some(manifest_path) = args.manifest_path() {
+        cmd.arg("--manifest-path").arg(manifest_path);
+    }
+    cmd.args(args.resolve_flags());
+    let out = cmd.stderr(Stdio::inherit()).run_and_get_stdout(false)?;
+    serde_json::from_str(&out).chain_err(|| "couldn't parse the output of `cargo metadata`")
 }
 
-pub fn run(args: &Args, verbose: bool) -> Result<ExitStatus> {
-    let cargo = std::env::var("CARGO").unwrap_or("cargo".to_string());
-    Command::new(cargo)
-        .arg("build")
-        .args(args.all())
-        .run_and_get_status(verbose)
+pub fn run(
+    toolchain: &Toolchain,
+    args: &Args,
+    command_name: &str,
+    config: Option<&Config>,
+    artifacts: Option<&mut Vec<PathBuf>>,
+    verbose: bool,
+) -> Result<ExitStatus> {
+    let mut cmd = toolchain.cargo();
+    cmd.arg(command_name).args(args.all());
+    apply_env(config, &mut cmd, &[])?;
+    status::emit(StatusEvent::CargoInvocation {
+        command: format!("{:?}", cmd),
+    });
+    let status = match artifacts {
+        Some(artifacts) => {
+            let (status, files) = cmd.run_and_get_artifacts(verbose)?;
+            artifacts.extend(files);
+            status
+        }
+        None => cmd.run_and_get_status(verbose)?,
+    };
+    status::emit(StatusEvent::CargoFinish { status });
+    Ok(status)
 }
 
 #[derive(Debug)]
 pub struct Config {
     parent_path: PathBuf,
     table: Value,
+    /// The merged files and overrides, from the highest to the lowest precedence
+    layers: Vec<Layer>,
+}
+
+/// A config file or a `--config` override that is part of a `Config`
+#[derive(Debug)]
+struct Layer {
+    /// The file or the override, for error messages
+    source: String,
+    /// The directory that relative paths are resolved against
+    dir: PathBuf,
+    table: Value,
+}
+
+/// An entry of the `[env]` table of `.cargo/config`
+pub struct EnvVar {
+    pub value: String,
+    /// Whether the entry overrides a variable that is already set in the environment
+    pub force: bool,
 }
 
 impl Config {
+    /// The merged files and `--config` overrides, from the highest to the lowest precedence
+    pub fn sources(&self) -> Vec<String> {
+        self.layers.iter().map(|l| l.source.clone()).collect()
+    }
+
+    /// Returns the keys that are set both in `target.<triple>` and in a `target.'cfg(..)'`
+    /// section that matches the target, together with the `cfg(..)` expression
+    ///
+    /// Only `target.<triple>` is read for these keys, which isn't obvious when both are set.
+    /// `rustflags` are combined from all matching sections, so they aren't reported.
+    pub fn ambiguous_target_keys(&self, triple: &str, cfgs: &[String]) -> Vec<(String, String)> {
+        let targets = match self.table.lookup("target").and_then(|t| t.as_table()) {
+            Some(targets) => targets,
+            None => return vec![],
+        };
+        let literal = match targets.get(triple).and_then(|t| t.as_table()) {
+            Some(literal) => literal,
+            None => return vec![],
+        };
+
+        let mut ambiguous = vec![];
+        for (expr, table) in targets {
+            if rustc::cfg_matches(expr, cfgs) != Some(true) {
+                continue;
+            }
+            if let Some(table) = table.as_table() {
+                for key in table
+                    .keys()
+                    .filter(|k| *k != "rustflags" && literal.contains_key(*k))
+                {
+                    ambiguous.push((expr.clone(), key.clone()));
+                }
+            }
+        }
+        ambiguous
+    }
+
+    /// Returns `target.<triple>.<key>` as a path, if configured
+    pub fn target_path(&self, triple: &str, key: &str) -> Result<Option<String>> {
+        self.path(&format!("target.{}.{}", triple, key))
+    }
+
+    /// Returns the value of the dotted `key` as a path, if configured
+    ///
+    /// Like in cargo, a relative path that contains a slash is relative to the parent directory
+    /// of the `.cargo` directory, otherwise it's looked up in `PATH`.
+    pub fn path(&self, key: &str) -> Result<Option<String>> {
+        let value = match self.table.lookup(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let keys = key.split('.').collect::<Vec<_>>();
+        let path = value.as_str().ok_or_else(|| {
+            ErrorKind::ConfigError(
+                self.source(&keys).into(),
+                key.to_owned(),
+                "must be a string".to_owned(),
+            )
+        })?;
+        if path.contains('/') && Path::new(path).is_relative() {
+            let path = self.definition_dir(&keys).join(path);
+            Ok(Some(path.display().to_string()))
+        } else {
+            Ok(Some(path.to_owned()))
+        }
+    }
+
+    /// Returns the `target.<triple>.runner` command, if configured
+    pub fn runner(&self, triple: &str) -> Result<Option<String>> {
+        let runner = match self.table.lookup(&format!("target.{}.runner", triple)) {
+            Some(runner) => runner,
+            None => return Ok(None),
+        };
+
+        if let Some(runner) = runner.as_str() {
+            return Ok(Some(runner.to_owned()));
+        }
+        match runner.as_slice() {
+            Some(array) if array.iter().all(|v| v.as_str().is_some()) => Ok(Some(
+                array
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )),
+            _ => Err(ErrorKind::ConfigError(
+                self.source(&["target", triple, "runner"]).into(),
+                format!("target.{}.runner", triple),
+                "must be a string or an array of strings".to_owned(),
+            ))?,
+        }
+    }
+
+    /// Looks up `name` in the `[env]` table
+    ///
+    /// Entries marked as `relative` are resolved against the directory containing `.cargo`.
+    pub fn env(&self, name: &str) -> Result<Option<EnvVar>> {
+        match self.table.lookup("env").and_then(|env| env.as_table()) {
+            Some(env) => match env.get(name) {
+                Some(value) => self.env_var(name, value).map(Some),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Returns all entries of the `[env]` table
+    pub fn env_vars(&self) -> Result<Vec<(String, EnvVar)>> {
+        let mut vars = vec![];
+        if let Some(env) = self.table.lookup("env").and_then(|env| env.as_table()) {
+            for (name, value) in env {
+                vars.push((name.clone(), self.env_var(name, value)?));
+            }
+        }
+        Ok(vars)
+    }
+
+    fn env_var(&self, name: &str, value: &Value) -> Result<EnvVar> {
+        let error = || {
+            ErrorKind::ConfigError(
+                self.source(&["env", name]).into(),
+                format!("env.{}", name),
+                "must be a string or a table with a string `value`".to_owned(),
+            )
+        };
+
+        let (value, force, relative) = match *value {
+            Value::String(ref value) => (value.clone(), false, false),
+            Value::Table(ref table) => (
+                table
+                    .get("value")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(&error)?
+                    .to_owned(),
+                table.get("force").and_then(|v| v.as_bool()).unwrap_or(false),
+                table.get("relative").and_then(|v| v.as_bool()).unwrap_or(false),
+            ),
+            _ => Err(error())?,
+        };
+
+        let value = if relative {
+            self.definition_dir(&["env", name])
+                .join(value)
+                .display()
+                .to_string()
+        } else {
+            value
+        };
+
+        Ok(EnvVar { value, force })
+    }
+
+    /// The layer with the highest precedence that sets the value at `keys`
+    fn layer(&self, keys: &[&str]) -> Option<&Layer> {
+        self.layers.iter().find(|layer| {
+            keys.iter()
+                .try_fold(&layer.table, |value, key| {
+                    value.as_table().and_then(|t| t.get(*key))
+                })
+                .is_some()
+        })
+    }
+
+    /// The directory that relative paths in the value at `keys` are resolved against, i.e. the
+    /// parent directory of the `.cargo` directory of the file that sets it
+    fn definition_dir(&self, keys: &[&str]) -> &Path {
+        self.layer(keys)
+            .map(|layer| &*layer.dir)
+            .unwrap_or(&self.parent_path)
+    }
+
+    /// The file (or `--config` override) that sets the value at `keys`, for error messages
+    pub fn source(&self, keys: &[&str]) -> &str {
+        self.layer(keys)
+            .map(|layer| &*layer.source)
+            .unwrap_or(".cargo/config")
+    }
+
     pub fn target(&self) -> Result<Option<String>> {
         if let Some(v) = self.table.lookup("build.target") {
             let target = v
                 .as_str()
-                .ok_or_else(|| format!(".cargo/config: build.target must be a string"))?;
-            if target.ends_with(".json") {
-                let target_path = self.parent_path.join(target);
-                let canonicalized = target_path.canonicalize().map_err(|err| {
-                    format!(
-                        "target JSON file {} does not exist: {}",
-                        target_path.display(),
-                        err
+                .ok_or_else(|| {
+                    ErrorKind::ConfigError(
+                        self.source(&["build", "target"]).into(),
+                        "build.target".to_owned(),
+                        "must be a string".to_owned(),
                     )
                 })?;
-                let as_string = canonicalized
+            if target.ends_with(".json") {
+                let target_path = self.definition_dir(&["build", "target"]).join(target);
+                let canonicalized = target_path
+                    .canonicalize()
+                    .chain_err(|| ErrorKind::TargetSpecNotFound(target_path.clone()))?;
+                let as_string = util::simplify_path(canonicalized)
                     .into_os_string()
                     .into_string()
                     .map_err(|err| format!("target path not valid utf8: {:?}", err))?;
@@ -174,34 +875,298 @@ impl Config {
     }
 }
 
-pub fn config() -> Result<Option<Config>> {
+/// Reads and merges the `.cargo/config.toml` (or `.cargo/config`) files of `dir` and all of its
+/// parents and of `$CARGO_HOME`, and layers the `--config` `overrides` on top of them
+///
+/// Like in cargo, files in deeper directories take precedence, and arrays like `rustflags` are
+/// concatenated with the values of deeper directories last. The files of the workspace `root`
+/// and its parents are merged as well, which matters for members outside of the directory of
+/// the workspace root.
+///
+/// Like in cargo, an override is either a `key=value` pair in TOML syntax or the path to an
+/// additional config file.
+pub fn config(dir: &Path, root: Option<&Path>, overrides: &[String]) -> Result<Option<Config>> {
     let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
 
-    if let Some(p) = util::search(&cd, ".cargo/config") {
-        Ok(Some(Config {
-            parent_path: p.to_owned(),
-            table: util::parse(&p.join(".cargo/config"))?,
-        }))
+    // Ordered by precedence, the deepest directory first
+    let mut files: Vec<(PathBuf, PathBuf)> = vec![];
+    {
+        let mut add = |dir: &Path, parent: &Path| {
+            if let Some(file) = config_file(dir) {
+                if !files.iter().any(|(f, _)| *f == file) {
+                    files.push((file, parent.to_owned()));
+                }
+            }
+        };
+        let roots = root.map(|root| util::search_all(root, ".cargo"));
+        for parent in util::search_all(dir, ".cargo")
+            .into_iter()
+            .chain(roots.into_iter().flatten())
+        {
+            add(&parent.join(".cargo"), parent);
+        }
+        // `$CARGO_HOME` need not be called `.cargo`, its files are read directly
+        if let Some(home) = cargo_home() {
+            add(&home, home.parent().unwrap_or(&home));
+        }
+    }
+
+    if files.is_empty() && overrides.is_empty() {
+        return Ok(None);
+    }
+
+    let mut layers = files
+        .iter()
+        .map(|(file, parent)| {
+            Ok(Layer {
+                source: file.display().to_string(),
+                dir: parent.clone(),
+                table: util::parse(file)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Merged from the lowest precedence up, so that the arrays of deeper directories come last
+    let mut table = Value::Table(BTreeMap::new());
+    for layer in layers.iter().rev() {
+        merge(&mut table, layer.table.clone());
+    }
+    for value in overrides {
+        let path = cd.join(value);
+        let layer = if !value.contains('=') && path.is_file() {
+            let dir = path.parent().and_then(Path::parent).unwrap_or(&cd);
+            Layer {
+                source: path.display().to_string(),
+                dir: dir.to_owned(),
+                table: util::parse(&path)?,
+            }
+        } else {
+            Layer {
+                source: format!("--config `{}`", value),
+                dir: cd.clone(),
+                table: parse_override(value)?,
+            }
+        };
+        merge(&mut table, layer.table.clone());
+        layers.insert(0, layer);
+    }
+
+    Ok(Some(Config {
+        parent_path: files
+            .first()
+            .map(|(_, parent)| parent.clone())
+            .unwrap_or_else(|| dir.to_owned()),
+        table,
+        layers,
+    }))
+}
+
+/// Returns the config file in the `.cargo` directory `dir`
+///
+/// Like cargo, this prefers the legacy `config` over `config.toml` if both exist, with a warning
+/// unless one is a symlink to the other.
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    let legacy = dir.join("config");
+    let file = dir.join("config.toml");
+    if legacy.is_file() {
+        let symlink = fs::read_link(&legacy)
+            .map(|target| target == file || target == Path::new("config.toml"))
+            .unwrap_or(false);
+        if file.is_file() && !symlink {
+            writeln!(
+                io::stderr(),
+                "{} both `{}` and `{}` exist. Using `{}`",
+                util::level("warning"),
+                legacy.display(),
+                file.display(),
+                legacy.display()
+            )
+            .ok();
+        }
+        Some(legacy)
+    } else if file.is_file() {
+        Some(file)
     } else {
-        Ok(None)
+        None
+    }
+}
+
+/// The `CARGO_HOME` directory, `~/.cargo` by default
+pub fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))
+        .or_else(|| env::var_os("USERPROFILE").map(|home| Path::new(&home).join(".cargo")))
+}
+
+/// Parses a `--config` override like `target.'cfg(unix)'.runner = "foo"` into a table
+///
+/// Our TOML parser predates dotted keys, so the key is split here and only the value is
+/// parsed as TOML.
+fn parse_override(value: &str) -> Result<Value> {
+    let error = || ErrorKind::InvalidConfig(format!("invalid --config override `{}`", value));
+
+    // Split at the first `=` that isn't quoted
+    let mut quote = None;
+    let mut split = None;
+    for (i, c) in value.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '=') => {
+                split = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let split = split.ok_or_else(&error)?;
+
+    let mut keys = vec![];
+    let mut key = String::new();
+    let mut quote = None;
+    for c in value[..split].trim().chars() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '.') => keys.push(std::mem::take(&mut key).trim().to_owned()),
+            _ => key.push(c),
+        }
+    }
+    keys.push(key.trim().to_owned());
+    if keys.iter().any(|k| k.is_empty()) {
+        Err(error())?;
+    }
+
+    let table = util::parse_str(&format!("v = {}", &value[split + 1..]), value)?;
+    let mut result = table
+        .as_table()
+        .and_then(|t| t.get("v"))
+        .cloned()
+        .ok_or_else(&error)?;
+    for key in keys.into_iter().rev() {
+        let mut map = BTreeMap::new();
+        map.insert(key, result);
+        result = Value::Table(map);
+    }
+    Ok(result)
+}
+
+/// Merges `other`, which takes precedence, into `base` like cargo: tables are merged, arrays
+/// are concatenated, and other values are replaced
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (&mut Value::Table(ref mut base), Value::Table(other)) => {
+            for (key, value) in other {
+                match base.entry(key) {
+                    Entry::Occupied(mut entry) => merge(entry.get_mut(), value),
+                    Entry::Vacant(entry) => {
+                        entry.insert(value);
+                    }
+                }
+            }
+        }
+        (&mut Value::Array(ref mut base), Value::Array(other)) => base.extend(other),
+        (base, other) => *base = other,
     }
 }
 
 pub struct Profile<'t> {
+    name: &'static str,
     table: &'t Value,
 }
 
 impl<'t> Profile<'t> {
+    /// Returns the parts of the profile that apply to the sysroot crates
+    ///
+    /// `build-override` only affects build scripts and `package.<name>` overrides only affect
+    /// the sysroot if they target one of the sysroot crates (or all packages through `*`).
+    /// `debug-assertions` are meant for the crate itself, the sysroot keeps the defaults.
+    fn sysroot_table(&self) -> Value {
+        let mut v = self.table.clone();
+
+        if let Value::Table(ref mut table) = v {
+            table.remove("build-override");
+            table.remove("debug-assertions");
+
+            let mut remove_package = false;
+            if let Some(&mut Value::Table(ref mut packages)) = table.get_mut("package") {
+                let unrelated = packages
+                    .keys()
+                    .filter(|name| *name != "*" && !SYSROOT_CRATES.contains(&&***name))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for name in unrelated {
+                    packages.remove(&name);
+                }
+                remove_package = packages.is_empty();
+            }
+            if remove_package {
+                table.remove("package");
+            }
+        }
+
+        v
+    }
+
+    /// The `panic` strategy of the profile, if set
+    pub fn panic(&self) -> Option<&'t str> {
+        self.table.lookup("panic").and_then(|p| p.as_str())
+    }
+
     pub fn hash<H>(&self, hasher: &mut H)
     where
         H: Hasher,
     {
-        let mut v = self.table.clone();
+        // Only these keys affect the compiled `.rlib`s. Others like `lto`, `incremental`, or
+        // `strip` only matter for the final artifacts or the build process.
+        const HASHED_KEYS: &[&str] = &[
+            "opt-level",
+            "debug",
+            "overflow-checks",
+            "codegen-units",
+            "panic",
+        ];
+
+        fn retain(table: &mut BTreeMap<String, Value>) {
+            let ignored = table
+                .keys()
+                .filter(|key| !HASHED_KEYS.contains(&&***key))
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in ignored {
+                table.remove(&key);
+            }
+        }
+
+        let mut v = self.sysroot_table();
 
-        // Don't include `lto` in the hash because it doesn't affect compilation
-        // of `.rlib`s
         if let Value::Table(ref mut table) = v {
-            table.remove("lto");
+            let mut packages = table.remove("package");
+            retain(table);
+
+            if let Some(Value::Table(ref mut packages)) = packages {
+                for package in packages.values_mut() {
+                    if let Value::Table(ref mut package) = *package {
+                        retain(package);
+                    }
+                }
+                // Overrides that only set ignored keys don't affect the sysroot either
+                let empty = packages
+                    .iter()
+                    .filter(|&(_, package)| package.as_table().is_some_and(|t| t.is_empty()))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                for name in empty {
+                    packages.remove(&name);
+                }
+            }
+            match packages {
+                Some(Value::Table(ref packages)) if packages.is_empty() => {}
+                Some(packages) => {
+                    table.insert("package".to_owned(), packages);
+                }
+                None => {}
+            }
 
             // don't hash an empty map
             if table.is_empty() {
@@ -218,7 +1183,7 @@ impl<'t> fmt::Display for Profile<'t> {
         let mut map = BTreeMap::new();
         map.insert("profile".to_owned(), {
             let mut map = BTreeMap::new();
-            map.insert("release".to_owned(), self.table.clone());
+            map.insert(self.name.to_owned(), self.sysroot_table());
             Value::Table(map)
         });
 
@@ -231,14 +1196,731 @@ pub struct Toml {
 }
 
 impl Toml {
-    /// `profile.release` part of `Cargo.toml`
-    pub fn profile(&self) -> Option<Profile> {
+    /// Looks up the value at the dotted `path`
+    pub fn lookup(&self, path: &str) -> Option<&Value> {
+        self.table.lookup(path)
+    }
+
+    /// `profile.<name>` part of `Cargo.toml`
+    pub fn profile(&self, profile: SysrootProfile) -> Option<Profile<'_>> {
         self.table
-            .lookup("profile.release")
-            .map(|t| Profile { table: t })
+            .lookup(&format!("profile.{}", profile.name()))
+            .map(|t| Profile {
+                name: profile.name(),
+                table: t,
+            })
     }
 }
 
+/// Parses the `Cargo.toml` in `root`, which should be the workspace root as reported by
+/// `cargo metadata`
+///
+/// Like in cargo, only the `[profile]` sections of the workspace root apply, see
+/// `sets_profile`.
 pub fn toml(root: &Path) -> Result<Toml> {
     util::parse(&root.join("Cargo.toml")).map(|t| Toml { table: t })
 }
+
+/// Whether the `manifest` of a workspace member has a `[profile]` section, which cargo ignores
+pub fn sets_profile(manifest: &Path) -> Result<bool> {
+    util::parse(manifest).map(|t| t.lookup("profile").is_some())
+}
+
+/// Creates a `Toml` that only contains the given `[profile.release]` section
+pub fn toml_from_release_profile(profile: Option<&str>) -> Result<Toml> {
+    let mut contents = String::from("[profile.release]\n");
+    if let Some(profile) = profile {
+        contents.push_str(profile);
+    }
+    util::parse_str(&contents, "release profile").map(|t| Toml { table: t })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::hash::Hasher;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use tempdir::TempDir;
+    use toml::Value;
+
+    use config::SysrootProfile;
+    use util;
+
+    use super::{
+        apply_env, apply_rustc_wrappers, apply_target_tools, config_flags, expand_vars, flags,
+        masks, sets_profile, supports_encoded_flags, toml, toml_from_release_profile, Config,
+        Rustflags,
+    };
+
+    const TARGET: &str = "thumbv7m-none-eabi";
+
+    fn config(toml: &str) -> Config {
+        Config {
+            parent_path: PathBuf::from("/project"),
+            table: util::parse_str(toml, "config").unwrap(),
+            layers: vec![],
+        }
+    }
+
+    // Each test uses its own variable (the upper-cased tool name), as the tests run in parallel
+
+    #[test]
+    fn env_table_sets_flags() {
+        let config = config("[env]\nXBUILD_TEST_TABLE_FLAGS = \"-C opt-level=1\"");
+        let flags = flags(
+            Some(&config),
+            TARGET,
+            &[],
+            "xbuild_test_table_flags",
+            true,
+            false,
+        )
+        .unwrap();
+        assert_eq!(flags, ["-C", "opt-level=1"]);
+    }
+
+    #[test]
+    fn environment_overrides_env_table() {
+        env::set_var("XBUILD_TEST_UNFORCED_FLAGS", "-C opt-level=2");
+        let config = config("[env]\nXBUILD_TEST_UNFORCED_FLAGS = \"-C opt-level=1\"");
+        let flags = flags(
+            Some(&config),
+            TARGET,
+            &[],
+            "xbuild_test_unforced_flags",
+            true,
+            false,
+        )
+        .unwrap();
+        assert_eq!(flags, ["-C", "opt-level=2"]);
+    }
+
+    #[test]
+    fn forced_env_table_overrides_environment() {
+        env::set_var("XBUILD_TEST_FORCED_FLAGS", "-C opt-level=2");
+        let config = config(
+            "[env]\nXBUILD_TEST_FORCED_FLAGS = { value = \"-C opt-level=1\", force = true }",
+        );
+        let flags = flags(
+            Some(&config),
+            TARGET,
+            &[],
+            "xbuild_test_forced_flags",
+            true,
+            false,
+        )
+        .unwrap();
+        assert_eq!(flags, ["-C", "opt-level=1"]);
+    }
+
+    #[test]
+    fn apply_env_sets_env_table() {
+        env::set_var("XBUILD_TEST_SET", "outer");
+        env::set_var("XBUILD_TEST_FORCED", "outer");
+        let config = config(
+            "[env]
+             XBUILD_TEST_PLAIN = \"plain\"
+             XBUILD_TEST_SET = \"inner\"
+             XBUILD_TEST_FORCED = { value = \"inner\", force = true }
+             XBUILD_TEST_RELATIVE = { value = \"tools\", relative = true }
+             XBUILD_TEST_SKIPPED = \"skipped\"",
+        );
+        let mut cmd = Command::new("true");
+        apply_env(Some(&config), &mut cmd, &["XBUILD_TEST_SKIPPED"]).unwrap();
+
+        let mut envs = cmd
+            .get_envs()
+            .map(|(k, v)| (k.to_owned(), v.map(OsStr::to_owned)))
+            .collect::<Vec<_>>();
+        envs.sort();
+        let expected = [
+            ("XBUILD_TEST_FORCED", "inner".to_owned()),
+            ("XBUILD_TEST_PLAIN", "plain".to_owned()),
+            ("XBUILD_TEST_RELATIVE", "/project/tools".to_owned()),
+        ];
+        assert_eq!(
+            envs,
+            expected
+                .iter()
+                .map(|&(k, ref v)| (k.into(), Some(v.into())))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn runner_is_string_or_array() {
+        let config = config(
+            "[target.thumbv7m-none-eabi]\nrunner = [\"qemu-arm\", \"-cpu\", \"cortex-m3\"]\n\
+             [target.x86_64-unknown-linux-gnu]\nrunner = \"valgrind\"",
+        );
+        assert_eq!(
+            config.runner(TARGET).unwrap(),
+            Some("qemu-arm -cpu cortex-m3".to_owned())
+        );
+        assert_eq!(
+            config.runner("x86_64-unknown-linux-gnu").unwrap(),
+            Some("valgrind".to_owned())
+        );
+        assert_eq!(config.runner("riscv32imac-unknown-none-elf").unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_runner_is_an_error() {
+        let config = config("[target.thumbv7m-none-eabi]\nrunner = 1");
+        assert!(config.runner(TARGET).is_err());
+    }
+
+    fn profile_hash(profile: &str) -> u64 {
+        let toml = toml_from_release_profile(Some(profile)).unwrap();
+        let mut hasher = DefaultHasher::new();
+        if let Some(profile) = toml.profile(SysrootProfile::Release) {
+            profile.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn profile_hash_ignores_unrelated_keys() {
+        let base = profile_hash("opt-level = 3");
+        for edit in &[
+            "lto = true",
+            "strip = true",
+            "incremental = true",
+            "debug-assertions = true",
+            "[profile.release.build-override]\nopt-level = 0",
+            "[profile.release.package.my-crate]\nopt-level = 0",
+            "[profile.release.package.core]\nlto = true",
+        ] {
+            assert_eq!(
+                profile_hash(&format!("opt-level = 3\n{}", edit)),
+                base,
+                "{}",
+                edit
+            );
+        }
+        assert_eq!(profile_hash("lto = true"), profile_hash(""));
+    }
+
+    #[test]
+    fn profile_hash_covers_codegen_keys() {
+        let base = profile_hash("opt-level = 3");
+        for edit in &[
+            "debug = true",
+            "overflow-checks = true",
+            "codegen-units = 1",
+            "panic = \"abort\"",
+            "[profile.release.package.core]\nopt-level = 0",
+            "[profile.release.package.\"*\"]\nopt-level = 0",
+        ] {
+            assert_ne!(
+                profile_hash(&format!("opt-level = 3\n{}", edit)),
+                base,
+                "{}",
+                edit
+            );
+        }
+        assert_ne!(profile_hash("opt-level = 2"), base);
+    }
+
+    #[test]
+    fn toml_reads_the_profile_of_the_workspace_root() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::write(
+            td.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n[profile.release]\nopt-level = 1\n",
+        )
+        .unwrap();
+
+        let toml = toml(td.path()).unwrap();
+        assert!(toml.profile(SysrootProfile::Release).is_some());
+        assert!(toml.profile(SysrootProfile::Dev).is_none());
+        assert_eq!(
+            toml.lookup("profile.release.opt-level")
+                .and_then(|v| v.as_integer()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn ambiguous_target_keys_lists_keys_of_both_sections() {
+        let config = config(
+            "[target.thumbv7m-none-eabi]
+             runner = \"qemu-arm\"
+             linker = \"arm-none-eabi-ld\"
+             rustflags = [\"-C\", \"opt-level=1\"]
+             [target.'cfg(target_os = \"none\")']
+             runner = \"probe-run\"
+             rustflags = [\"-C\", \"opt-level=2\"]
+             [target.'cfg(unix)']
+             linker = \"cc\"",
+        );
+        let cfgs = vec!["target_os=\"none\"".to_owned()];
+        assert_eq!(
+            config.ambiguous_target_keys(TARGET, &cfgs),
+            [("cfg(target_os = \"none\")".to_owned(), "runner".to_owned())]
+        );
+        assert!(config
+            .ambiguous_target_keys("riscv32imac-unknown-none-elf", &cfgs)
+            .is_empty());
+    }
+
+    #[test]
+    fn config_flags_combine_matching_target_sections() {
+        let config = config(
+            "[build]
+             rustflags = [\"-C\", \"opt-level=1\"]
+             [target.thumbv7m-none-eabi]
+             rustflags = [\"-C\", \"link-arg=-Tlink.x\"]
+             [target.'cfg(target_os = \"none\")']
+             rustflags = \"-C target-cpu=cortex-m3\"
+             [target.'cfg(unix)']
+             rustflags = [\"-C\", \"target-cpu=native\"]",
+        );
+        let cfgs = vec!["target_os=\"none\"".to_owned()];
+        assert_eq!(
+            config_flags(Some(&config), TARGET, &cfgs, "rustflags").unwrap(),
+            ["-C", "link-arg=-Tlink.x", "-C", "target-cpu=cortex-m3"]
+        );
+        // `build.rustflags` only apply if no target section sets any
+        assert_eq!(
+            config_flags(Some(&config), "x86_64-unknown-uefi", &[], "rustflags").unwrap(),
+            ["-C", "opt-level=1"]
+        );
+    }
+
+    #[test]
+    fn config_flags_dont_depend_on_the_order_of_the_cfg_sections() {
+        let unix = "[target.'cfg(unix)']\nrustflags = [\"-C\", \"target-cpu=native\"]\n";
+        let none = "[target.'cfg(target_os = \"none\")']\nrustflags = [\"--cfg\", \"bare\"]\n";
+        let cfgs = vec!["unix".to_owned(), "target_os=\"none\"".to_owned()];
+        let flags = |toml: String| config_flags(Some(&config(&toml)), TARGET, &cfgs, "rustflags");
+        assert_eq!(
+            flags(format!("{}{}", unix, none)).unwrap(),
+            flags(format!("{}{}", none, unix)).unwrap()
+        );
+    }
+
+    #[test]
+    fn config_flags_are_strings_or_arrays() {
+        let tool = "xbuild_test_string_flags";
+        for toml in &[
+            "[target.thumbv7m-none-eabi]\nxbuild_test_string_flags = \"-C  link-arg=-nostartfiles\"",
+            "[target.thumbv7m-none-eabi]
+             xbuild_test_string_flags = [\"-C\", \"link-arg=-nostartfiles\"]",
+            "[build]\nxbuild_test_string_flags = \"-C link-arg=-nostartfiles\"",
+        ] {
+            assert_eq!(
+                config_flags(Some(&config(toml)), TARGET, &[], tool).unwrap(),
+                ["-C", "link-arg=-nostartfiles"]
+            );
+        }
+
+        for toml in &[
+            "[build]\nxbuild_test_string_flags = 1",
+            "[target.thumbv7m-none-eabi]\nxbuild_test_string_flags = [[\"-g\"]]",
+        ] {
+            let err = config_flags(Some(&config(toml)), TARGET, &[], tool).unwrap_err();
+            assert!(err
+                .to_string()
+                .ends_with("xbuild_test_string_flags must be a string or an array of strings"));
+        }
+    }
+
+    #[test]
+    fn target_tools_reach_the_sysroot_build_environment() {
+        let config = config(
+            "[target.thumbv7m-none-eabi]
+             linker = \"tools/arm-ld\"
+             ar = \"arm-none-eabi-ar\"",
+        );
+        let mut cmd = Command::new("cargo");
+        apply_target_tools(Some(&config), &mut cmd, TARGET).unwrap();
+        let envs = cmd
+            .get_envs()
+            .map(|(k, v)| (k.to_owned(), v.map(|v| v.to_owned())))
+            .collect::<Vec<_>>();
+        assert!(envs.contains(&(
+            "CARGO_TARGET_THUMBV7M_NONE_EABI_LINKER".into(),
+            Some("/project/tools/arm-ld".into())
+        )));
+        assert!(envs.contains(&(
+            "CARGO_TARGET_THUMBV7M_NONE_EABI_AR".into(),
+            Some("arm-none-eabi-ar".into())
+        )));
+
+        let mut cmd = Command::new("cargo");
+        apply_target_tools(Some(&config), &mut cmd, "riscv32imac-unknown-none-elf").unwrap();
+        assert_eq!(cmd.get_envs().count(), 0);
+    }
+
+    #[test]
+    fn rustc_wrapper_reaches_the_sysroot_build_environment() {
+        let config = config(
+            "[build]
+             rustc-wrapper = \"sccache\"
+             rustc-workspace-wrapper = \"tools/lint\"",
+        );
+        let mut cmd = Command::new("cargo");
+        apply_rustc_wrappers(Some(&config), &mut cmd).unwrap();
+        let envs = cmd
+            .get_envs()
+            .map(|(k, v)| (k.to_owned(), v.map(|v| v.to_owned())))
+            .collect::<Vec<_>>();
+        // The environment of the process wins, and is inherited as is
+        for &(var, wrapper) in &[
+            ("RUSTC_WRAPPER", "sccache"),
+            ("RUSTC_WORKSPACE_WRAPPER", "/project/tools/lint"),
+        ] {
+            let expected = match env::var_os(var) {
+                Some(_) => None,
+                None => Some((var.into(), Some(wrapper.into()))),
+            };
+            assert_eq!(envs.iter().find(|&(k, _)| k == var).cloned(), expected);
+        }
+
+        let mut cmd = Command::new("cargo");
+        apply_rustc_wrappers(None, &mut cmd).unwrap();
+        assert_eq!(cmd.get_envs().count(), 0);
+    }
+
+    #[test]
+    fn encoded_flags_need_cargo_1_55() {
+        assert!(supports_encoded_flags("1.55.0"));
+        assert!(supports_encoded_flags("1.80.0-nightly"));
+        assert!(supports_encoded_flags("2.0.0"));
+        assert!(!supports_encoded_flags("1.54.0"));
+        assert!(!supports_encoded_flags("1.9.0"));
+    }
+
+    #[test]
+    fn masked_config_flags_are_not_evaluated() {
+        env::set_var("XBUILD_TEST_MASKING_FLAGS", "-C opt-level=2");
+        // Not a valid value, but masked by the environment
+        let masked = config("[target.thumbv7m-none-eabi]\nxbuild_test_masking_flags = 1");
+        for &quiet in &[true, false] {
+            let flags = flags(
+                Some(&masked),
+                TARGET,
+                &[],
+                "xbuild_test_masking_flags",
+                quiet,
+                false,
+            );
+            assert_eq!(flags.unwrap(), ["-C", "opt-level=2"]);
+        }
+        let unmasked = config("[target.thumbv7m-none-eabi]\nxbuild_test_unmasked_flags = 1");
+        assert!(flags(
+            Some(&unmasked),
+            TARGET,
+            &[],
+            "xbuild_test_unmasked_flags",
+            true,
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn masking_requires_both_sources_to_differ() {
+        let env = vec!["-C".to_owned(), "opt-level=2".to_owned()];
+        let config = vec!["-C".to_owned(), "opt-level=1".to_owned()];
+        assert!(masks(&env, &config));
+        assert!(!masks(&env, &env));
+        assert!(!masks(&env, &[]));
+        assert!(!masks(&[], &config));
+    }
+
+    #[test]
+    fn merged_flags_append_config_flags() {
+        env::set_var("XBUILD_TEST_MERGED_FLAGS", "-C opt-level=3 -C debuginfo=2");
+        let config = config(
+            "[target.thumbv7m-none-eabi]
+             xbuild_test_merged_flags = [\"-C\", \"opt-level=1\", \"-C\", \"target-cpu=cortex-m3\"]",
+        );
+        let flags = flags(
+            Some(&config),
+            TARGET,
+            &[],
+            "xbuild_test_merged_flags",
+            true,
+            true,
+        );
+        let flags = flags.unwrap();
+        assert_eq!(
+            flags,
+            [
+                "-C",
+                "opt-level=3",
+                "-C",
+                "debuginfo=2",
+                "-C",
+                "opt-level=1",
+                "-C",
+                "target-cpu=cortex-m3"
+            ]
+        );
+        // The last `-C opt-level` wins
+        assert_eq!(
+            Rustflags::new(flags).as_slice(),
+            [
+                "-C",
+                "debuginfo=2",
+                "-C",
+                "opt-level=1",
+                "-C",
+                "target-cpu=cortex-m3"
+            ]
+        );
+    }
+
+    #[test]
+    fn repeatable_codegen_options_are_kept() {
+        let flags = Rustflags::new(
+            vec![
+                "-C",
+                "target-feature=+sse2",
+                "-Copt-level=1",
+                "-C",
+                "target-feature=-sse2",
+                "--codegen=link-arg=-Tlink.x",
+                "--codegen",
+                "opt-level=3",
+                "-C",
+                "link-arg=--nmagic",
+            ]
+            .into_iter()
+            .map(|f| f.to_owned())
+            .collect(),
+        );
+        assert_eq!(
+            flags.as_slice(),
+            [
+                "-C",
+                "target-feature=+sse2",
+                "-C",
+                "target-feature=-sse2",
+                "--codegen=link-arg=-Tlink.x",
+                "--codegen",
+                "opt-level=3",
+                "-C",
+                "link-arg=--nmagic"
+            ]
+        );
+    }
+
+    #[test]
+    fn order_of_repeatable_codegen_options_is_hashed() {
+        let hash = |flags: &[&str]| {
+            let mut hasher = DefaultHasher::new();
+            Rustflags::new(flags.iter().map(|f| (*f).to_owned()).collect()).hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(
+            hash(&["-C", "opt-level=1", "-C", "debuginfo=2"]),
+            hash(&["-C", "debuginfo=2", "-C", "opt-level=1"])
+        );
+        assert_ne!(
+            hash(&["-C", "target-feature=+sse2", "-C", "target-feature=-sse2"]),
+            hash(&["-C", "target-feature=-sse2", "-C", "target-feature=+sse2"])
+        );
+    }
+
+    #[test]
+    fn config_override_changes_target() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::create_dir(td.path().join(".cargo")).unwrap();
+        fs::write(
+            td.path().join(".cargo/config"),
+            "[build]\ntarget = \"thumbv6m-none-eabi\"\n",
+        )
+        .unwrap();
+
+        let config = super::config(td.path(), None, &[]).unwrap().unwrap();
+        assert_eq!(
+            config.target().unwrap().as_deref(),
+            Some("thumbv6m-none-eabi")
+        );
+        let overrides = vec!["build.target=\"thumbv7m-none-eabi\"".to_owned()];
+        let config = super::config(td.path(), None, &overrides).unwrap().unwrap();
+        assert_eq!(config.target().unwrap().as_deref(), Some(TARGET));
+    }
+
+    #[test]
+    fn config_falls_back_to_workspace_root() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let root = td.path().join("workspace");
+        let member = td.path().join("member");
+        fs::create_dir_all(root.join(".cargo")).unwrap();
+        fs::create_dir(&member).unwrap();
+        fs::write(
+            root.join(".cargo/config"),
+            "[build]\ntarget = \"thumbv7m-none-eabi\"\n",
+        )
+        .unwrap();
+
+        let config = super::config(&member, Some(&root), &[]).unwrap().unwrap();
+        assert_eq!(config.target().unwrap().as_deref(), Some(TARGET));
+    }
+
+    #[test]
+    fn deeper_config_files_take_precedence() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let inner = td.path().join("inner");
+        fs::create_dir_all(td.path().join(".cargo")).unwrap();
+        fs::create_dir_all(inner.join(".cargo")).unwrap();
+        fs::write(
+            td.path().join(".cargo/config.toml"),
+            "[xbuild-test]\nflags = [\"outer\"]\nvalue = \"outer\"\nouter = true\n",
+        )
+        .unwrap();
+        fs::write(
+            inner.join(".cargo/config.toml"),
+            "[xbuild-test]\nflags = [\"inner\"]\nvalue = \"inner\"\n",
+        )
+        .unwrap();
+
+        let config = super::config(&inner, None, &["xbuild-test.value=\"cli\"".to_owned()])
+            .unwrap()
+            .unwrap();
+        let flags = config.table.lookup("xbuild-test.flags").unwrap();
+        let flags = flags
+            .as_slice()
+            .unwrap()
+            .iter()
+            .map(|f| f.as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(flags, ["outer", "inner"]);
+        assert_eq!(
+            config.table.lookup("xbuild-test.outer"),
+            Some(&Value::Boolean(true))
+        );
+        assert_eq!(
+            config.table.lookup("xbuild-test.value"),
+            Some(&Value::String("cli".to_owned()))
+        );
+
+        let config = super::config(&inner, None, &[]).unwrap().unwrap();
+        assert_eq!(
+            config.table.lookup("xbuild-test.value"),
+            Some(&Value::String("inner".to_owned()))
+        );
+    }
+
+    #[test]
+    fn profiles_of_members_are_ignored() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::create_dir(td.path().join("member")).unwrap();
+        fs::write(
+            td.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n[profile.release]\nopt-level = 1\n",
+        )
+        .unwrap();
+        let member = td.path().join("member/Cargo.toml");
+        fs::write(
+            &member,
+            "[package]\nname = \"member\"\nversion = \"0.0.0\"\n[profile.release]\nopt-level = 3\n",
+        )
+        .unwrap();
+
+        let toml = toml(td.path()).unwrap();
+        assert_eq!(
+            toml.lookup("profile.release.opt-level")
+                .and_then(|v| v.as_integer()),
+            Some(1)
+        );
+        assert!(sets_profile(&member).unwrap());
+        fs::write(
+            &member,
+            "[package]\nname = \"member\"\nversion = \"0.0.0\"\n",
+        )
+        .unwrap();
+        assert!(!sets_profile(&member).unwrap());
+    }
+
+    #[test]
+    fn config_vars_are_expanded() {
+        env::set_var("XBUILD_TEST_SYSROOT_LIBS", "/opt/libs");
+        assert_eq!(
+            expand_vars("link-arg=-L${XBUILD_TEST_SYSROOT_LIBS}/${XBUILD_TEST_SYSROOT_LIBS}")
+                .unwrap(),
+            "link-arg=-L/opt/libs//opt/libs"
+        );
+        assert_eq!(expand_vars("opt-level=3").unwrap(), "opt-level=3");
+    }
+
+    #[test]
+    fn undefined_config_vars_are_errors() {
+        env::remove_var("XBUILD_TEST_UNDEFINED_LIBS");
+        let err = expand_vars("link-arg=-L${XBUILD_TEST_UNDEFINED_LIBS}").unwrap_err();
+        assert!(err.to_string().contains("`XBUILD_TEST_UNDEFINED_LIBS`"));
+        assert!(expand_vars("link-arg=-L${XBUILD_TEST_UNDEFINED_LIBS").is_err());
+    }
+
+    #[test]
+    fn merged_flags_hash_alike_in_any_order() {
+        let config = config(
+            "[target.thumbv7m-none-eabi]
+             xbuild_test_shuffled_flags = [\"--cfg\", \"board\", \"-C\", \"debuginfo=2\"]",
+        );
+        let runs = [
+            "-C opt-level=3 --cfg foo -Z share-generics",
+            "-Zshare-generics --cfg foo -Copt-level=3",
+        ];
+        let merged = runs
+            .iter()
+            .map(|run| {
+                env::set_var("XBUILD_TEST_SHUFFLED_FLAGS", run);
+                let flags = flags(
+                    Some(&config),
+                    TARGET,
+                    &[],
+                    "xbuild_test_shuffled_flags",
+                    true,
+                    true,
+                );
+                let flags = Rustflags::new(flags.unwrap());
+                let mut hasher = DefaultHasher::new();
+                flags.hash(&mut hasher);
+                (hasher.finish(), flags.to_sorted_string())
+            })
+            .collect::<Vec<_>>();
+        env::remove_var("XBUILD_TEST_SHUFFLED_FLAGS");
+        assert_eq!(merged[0], merged[1]);
+    }
+
+    #[test]
+    fn only_flags_that_affect_the_sysroot_change_the_hash() {
+        let hash = |flags: &[&str]| {
+            let mut hasher = DefaultHasher::new();
+            Rustflags::new(flags.iter().map(|f| (*f).to_owned()).collect()).hash(&mut hasher);
+            hasher.finish()
+        };
+        let base = ["-C", "opt-level=2"];
+        // (added flags, whether they change the hash)
+        let edits: &[(&[&str], bool)] = &[
+            (&["-C", "link-arg=-Tlink.x"], false),
+            (&["-Clink-arg=-Tlink.x"], false),
+            (&["--codegen", "link-args=-Tlink.x -nostartfiles"], false),
+            (&["-Clinker=rust-lld"], false),
+            (&["-C", "link-self-contained=yes"], false),
+            (&["-Zpre-link-arg=-Tpre.x"], false),
+            (&["-Z", "pre-link-args=-Tpre.x"], false),
+            (&["-L", "native=/libs"], false),
+            (&["-Lnative=/libs"], false),
+            (&["-Cincremental=/tmp/incremental"], false),
+            (&["-C", "target-cpu=cortex-m4"], true),
+            (&["-Ctarget-feature=+crt-static"], true),
+            (&["-Zmir-opt-level=0"], true),
+            (&["--cfg", "feature=\"std\""], true),
+        ];
+        for &(added, changes) in edits {
+            let edited = base.iter().chain(added).cloned().collect::<Vec<_>>();
+            assert_eq!(hash(&base) != hash(&edited), changes, "{:?}", added);
+        }
+    }
+}
@@ -5,6 +5,19 @@ pub struct Args {
     all: Vec<String>,
     target: Option<String>,
     manifest_path: Option<PathBuf>,
+    packages: Vec<String>,
+    config: Vec<String>,
+    color: Option<String>,
+    auto_install_rust_src: bool,
+    use_build_std: bool,
+    allow_stable: bool,
+    locked_sysroot: bool,
+    sysroot_only: Vec<String>,
+    timings: bool,
+    verify_sysroot: bool,
+    env_script: Option<String>,
+    emit_build_std_config: Option<Option<PathBuf>>,
+    log_json: Option<String>,
 }
 
 impl Args {
@@ -13,11 +26,166 @@ impl Args {
     }
 
     pub fn target(&self) -> Option<&str> {
-        self.target.as_ref().map(|s| &**s)
+        self.target.as_deref()
     }
 
     pub fn manifest_path(&self) -> Option<&Path> {
-        self.manifest_path.as_ref().map(|s| &**s)
+        self.manifest_path.as_deref()
+    }
+
+    /// The packages selected through `-p`/`--package`
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+
+    /// The `--config` overrides of `.cargo/config` values
+    pub fn config(&self) -> &[String] {
+        &self.config
+    }
+
+    /// The value of `--color`, which is also passed to cargo
+    pub fn color(&self) -> Option<&str> {
+        self.color.as_deref()
+    }
+
+    /// Whether `--auto-install-rust-src` was passed
+    pub fn auto_install_rust_src(&self) -> bool {
+        self.auto_install_rust_src
+    }
+
+    /// Whether `--use-build-std` was passed
+    pub fn use_build_std(&self) -> bool {
+        self.use_build_std
+    }
+
+    /// Whether `--allow-stable` was passed
+    pub fn allow_stable(&self) -> bool {
+        self.allow_stable
+    }
+
+    /// Whether `--locked-sysroot` was passed
+    pub fn locked_sysroot(&self) -> bool {
+        self.locked_sysroot
+    }
+
+    /// The sysroot crates selected through `--sysroot-only`, or an empty list for all of them
+    pub fn sysroot_only(&self) -> &[String] {
+        &self.sysroot_only
+    }
+
+    /// Whether `--xbuild-timings` was passed
+    pub fn timings(&self) -> bool {
+        self.timings
+    }
+
+    /// Whether `--verify-sysroot` was passed
+    pub fn verify_sysroot(&self) -> bool {
+        self.verify_sysroot
+    }
+
+    /// The shell of `--env-script`, `posix` if none is given
+    pub fn env_script(&self) -> Option<&str> {
+        self.env_script.as_deref()
+    }
+
+    /// Whether `--emit-build-std-config` was passed, and the file it names, if any
+    pub fn emit_build_std_config(&self) -> Option<Option<&Path>> {
+        self.emit_build_std_config
+            .as_ref()
+            .map(|path| path.as_ref().map(|p| &**p))
+    }
+
+    /// The file of `--log-json`, `-` for stdout
+    pub fn log_json(&self) -> Option<&str> {
+        self.log_json.as_deref()
+    }
+
+    /// The flags of the arguments that restrict how cargo resolves the dependencies, which
+    /// `cargo metadata` needs too
+    pub fn resolve_flags(&self) -> Vec<&str> {
+        self.all
+            .iter()
+            .take_while(|a| *a != "--")
+            .filter(|a| *a == "--offline" || *a == "--locked" || *a == "--frozen")
+            .map(|a| &**a)
+            .collect()
+    }
+
+    /// Inserts `arg` into the arguments that are passed to cargo, before a `--` separator
+    pub fn insert(&mut self, arg: &str) {
+        let index = self
+            .all
+            .iter()
+            .position(|a| a == "--")
+            .unwrap_or(self.all.len());
+        self.all.insert(index, arg.to_owned());
+    }
+
+    /// Replaces the value of `--target` in the arguments that are passed to cargo
+    pub fn replace_target(&mut self, triple: &str) {
+        self.replace("--target", triple);
+        self.target = Some(triple.to_owned());
+    }
+
+    /// Replaces the value of `--manifest-path` in the arguments that are passed to cargo
+    pub fn replace_manifest_path(&mut self, path: &Path) {
+        self.replace("--manifest-path", &path.display().to_string());
+        self.manifest_path = Some(path.to_owned());
+    }
+
+    fn replace(&mut self, flag: &str, new: &str) {
+        let prefix = format!("{}=", flag);
+        let mut args = self.all.iter_mut();
+        while let Some(arg) = args.next() {
+            if arg == flag {
+                if let Some(value) = args.next() {
+                    *value = new.to_owned();
+                }
+            } else if arg.starts_with(&prefix) {
+                *arg = format!("{}{}", prefix, new);
+            }
+        }
+    }
+
+    /// The value of `--message-format`, if given
+    fn message_format(&self) -> Option<&str> {
+        let mut args = self.all.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--message-format" {
+                return args.next().map(|s| &**s);
+            } else if arg.starts_with("--message-format=") {
+                return arg.split_once('=').map(|(_, v)| v);
+            }
+        }
+        None
+    }
+
+    /// Whether `--message-format` selects one of cargo's JSON formats
+    pub fn message_format_json(&self) -> bool {
+        self.message_format()
+            .is_some_and(|f| f.split(',').any(|f| f.starts_with("json")))
+    }
+
+    /// Makes cargo emit its JSON messages, while it keeps rendering the diagnostics to stderr
+    ///
+    /// A JSON format that is already requested is kept as it is. `--message-format short` is
+    /// replaced with the JSON format that renders short diagnostics, and `human` with the one
+    /// that renders them in full.
+    pub fn request_json_messages(&mut self) {
+        let format = self.message_format().map(|f| f.to_owned());
+        match format {
+            None => self.insert("--message-format=json-render-diagnostics"),
+            Some(_) if self.message_format_json() => {}
+            Some(ref format) if format.split(',').any(|f| f == "short") => self.replace(
+                "--message-format",
+                "json-render-diagnostics,json-diagnostic-short",
+            ),
+            Some(_) => self.replace("--message-format", "json-render-diagnostics"),
+        }
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.all.iter().any(|a| a == "--quiet" || a == "-q")
     }
 
     pub fn verbose(&self) -> bool {
@@ -39,38 +207,261 @@ pub fn args(command_name: &str) -> Result<(Command, Args), String> {
     let command = match all.first().map(|s| s.as_str()) {
         Some("-h") | Some("--help") => Command::Help,
         Some("-v") | Some("--version") => Command::Version,
+        Some("--gc-sysroots") => Command::GcSysroots,
+        Some("--print") if all.get(1).map(|s| s.as_str()) == Some("cfg") => Command::PrintCfg,
+        Some("--print=cfg") => Command::PrintCfg,
+        Some("--print") if all.get(1).map(|s| s.as_str()) == Some("target-spec") => {
+            Command::PrintTargetSpec
+        }
+        Some("--print=target-spec") => Command::PrintTargetSpec,
+        Some("--print") if all.get(1).map(|s| s.as_str()) == Some("version-meta") => {
+            Command::PrintVersionMeta
+        }
+        Some("--print=version-meta") => Command::PrintVersionMeta,
+        Some("--print") if all.get(1).map(|s| s.as_str()) == Some("sysroot-info") => {
+            Command::PrintSysrootInfo
+        }
+        Some("--print=sysroot-info") => Command::PrintSysrootInfo,
         _ => Command::Build,
     };
 
+    Ok((command, parse(all)))
+}
+
+/// Parses the arguments that are passed to cargo
+pub fn parse(mut all: Vec<String>) -> Args {
+    let auto_install_rust_src = take_flag(&mut all, "--auto-install-rust-src");
+    let use_build_std = take_flag(&mut all, "--use-build-std");
+    let allow_stable = take_flag(&mut all, "--allow-stable");
+    let timings = take_flag(&mut all, "--xbuild-timings");
+    let locked_sysroot = take_flag(&mut all, "--locked-sysroot");
+    let verify_sysroot = take_flag(&mut all, "--verify-sysroot");
+    // The values are optional, so e.g. `--env-script fish` isn't accepted
+    let env_script = if take_flag(&mut all, "--env-script") {
+        Some("posix".to_owned())
+    } else {
+        take_option(&mut all, "--env-script")
+    };
+    let emit_build_std_config = if take_flag(&mut all, "--emit-build-std-config") {
+        Some(None)
+    } else {
+        take_option(&mut all, "--emit-build-std-config").map(|path| Some(PathBuf::from(path)))
+    };
+    let log_json = take_option(&mut all, "--log-json");
+    let sysroot_only = take_option(&mut all, "--sysroot-only")
+        .map(|crates| {
+            crates
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(|c| c.trim().to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+
     let mut target = None;
     let mut manifest_path = None;
+    let mut packages = vec![];
+    let mut config = vec![];
+    let mut color = None;
     {
-        let mut args = all.iter();
+        // The arguments after `--` belong to the program or the tests
+        let mut args = all.iter().take_while(|a| *a != "--");
         while let Some(arg) = args.next() {
             if arg == "--target" {
                 target = args.next().map(|s| s.to_owned());
             } else if arg.starts_with("--target=") {
-                target = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
+                target = arg.split_once('=').map(|(_, v)| v.to_owned());
             }
             if arg == "--manifest-path" {
                 manifest_path = args.next().map(|s| s.to_owned());
             } else if arg.starts_with("--manifest-path=") {
-                manifest_path = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
+                manifest_path = arg.split_once('=').map(|(_, v)| v.to_owned());
+            }
+            if arg == "-p" || arg == "--package" {
+                packages.extend(args.next().cloned());
+            } else if arg.starts_with("--package=") {
+                packages.extend(arg.split_once('=').map(|(_, v)| v.to_owned()));
+            } else if arg.starts_with("-p") && arg.len() > 2 {
+                packages.push(arg[2..].to_owned());
+            }
+            if arg == "--config" {
+                config.extend(args.next().cloned());
+            } else if arg.starts_with("--config=") {
+                config.extend(arg.split_once('=').map(|(_, v)| v.to_owned()));
+            }
+            if arg == "--color" {
+                color = args.next().cloned();
+            } else if arg.starts_with("--color=") {
+                color = arg.split_once('=').map(|(_, v)| v.to_owned());
             }
         }
     }
 
-    let args = Args {
-        all: all,
-        target: target,
+    let mut args = Args {
+        all,
+        target,
         manifest_path: manifest_path.as_ref().map(PathBuf::from),
+        packages,
+        config,
+        color,
+        auto_install_rust_src,
+        use_build_std,
+        allow_stable,
+        locked_sysroot,
+        sysroot_only,
+        timings,
+        verify_sysroot,
+        env_script,
+        emit_build_std_config,
+        log_json,
     };
-    Ok((command, args))
+
+    // cargo only accepts the path to a `Cargo.toml`, but it's convenient to pass the directory
+    // of the crate
+    if let Some(path) = args
+        .manifest_path()
+        .filter(|p| p.is_dir())
+        .map(|p| p.join("Cargo.toml"))
+    {
+        args.replace_manifest_path(&path);
+    }
+
+    args
+}
+
+/// Removes `flag`, which is handled by cargo-xbuild itself, from the arguments before a `--`
+/// separator
+fn take_flag(all: &mut Vec<String>, flag: &str) -> bool {
+    let end = all.iter().position(|a| a == "--").unwrap_or(all.len());
+    match all[..end].iter().position(|a| a == flag) {
+        Some(index) => {
+            all.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes `flag` and its value, given as `flag value` or `flag=value`, which is handled by
+/// cargo-xbuild itself, from the arguments before a `--` separator
+fn take_option(all: &mut Vec<String>, flag: &str) -> Option<String> {
+    let end = all.iter().position(|a| a == "--").unwrap_or(all.len());
+    let prefix = format!("{}=", flag);
+    let index = all[..end]
+        .iter()
+        .position(|a| a == flag || a.starts_with(&prefix))?;
+    let arg = all.remove(index);
+    if arg == flag {
+        if index < end - 1 {
+            Some(all.remove(index))
+        } else {
+            None
+        }
+    } else {
+        Some(arg[prefix.len()..].to_owned())
+    }
 }
 
 #[derive(Clone, PartialEq)]
 pub enum Command {
     Build,
+    GcSysroots,
     Help,
+    PrintCfg,
+    PrintSysrootInfo,
+    PrintTargetSpec,
+    PrintVersionMeta,
     Version,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    fn json_args(args: &[&str]) -> Vec<String> {
+        let mut args = parse(args.iter().map(|a| (*a).to_owned()).collect());
+        args.request_json_messages();
+        args.all().to_vec()
+    }
+
+    #[test]
+    fn json_messages_are_requested() {
+        assert_eq!(
+            json_args(&["--release", "--", "-v"]),
+            [
+                "--release",
+                "--message-format=json-render-diagnostics",
+                "--",
+                "-v"
+            ]
+        );
+    }
+
+    #[test]
+    fn json_message_formats_are_kept() {
+        assert_eq!(
+            json_args(&["--message-format", "json"]),
+            ["--message-format", "json"]
+        );
+        assert_eq!(
+            json_args(&["--message-format=json-diagnostic-short"]),
+            ["--message-format=json-diagnostic-short"]
+        );
+    }
+
+    #[test]
+    fn human_message_formats_are_replaced() {
+        assert_eq!(
+            json_args(&["--message-format", "short"]),
+            [
+                "--message-format",
+                "json-render-diagnostics,json-diagnostic-short"
+            ]
+        );
+        assert_eq!(
+            json_args(&["--message-format=human"]),
+            ["--message-format=json-render-diagnostics"]
+        );
+    }
+
+    #[test]
+    fn packages_after_the_separator_belong_to_the_program() {
+        let args = parse(
+            ["-p", "kernel", "--", "-p", "8080", "--package=x"]
+                .iter()
+                .map(|a| (*a).to_owned())
+                .collect(),
+        );
+        assert_eq!(args.packages(), ["kernel"]);
+    }
+
+    #[test]
+    fn config_overrides_are_collected() {
+        let args = parse(
+            [
+                "--config",
+                "build.target=\"thumbv7m-none-eabi\"",
+                "--release",
+                "--config=.cargo/ci.toml",
+            ]
+            .iter()
+            .map(|a| (*a).to_owned())
+            .collect(),
+        );
+        assert_eq!(
+            args.config(),
+            ["build.target=\"thumbv7m-none-eabi\"", ".cargo/ci.toml"]
+        );
+    }
+
+    #[test]
+    fn config_after_the_separator_belongs_to_the_program() {
+        let args = parse(
+            ["--config=a.toml", "--", "--config", "app.toml"]
+                .iter()
+                .map(|a| (*a).to_owned())
+                .collect(),
+        );
+        assert_eq!(args.config(), ["a.toml"]);
+    }
+}
@@ -1,33 +1,593 @@
-use cargo_metadata;
+use cargo_metadata::{self, DependencyKind};
 use serde_json;
-use std::path::PathBuf;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Hash)]
+use toml;
+
+use cargo::{self, Toml};
+use cli;
+use errors::*;
+use rustc::Toolchain;
+use util;
+use BuildSettings;
+
+#[derive(Debug, PartialEq)]
 pub struct Config {
     pub memcpy: bool,
     pub sysroot_path: PathBuf,
+    pub sysroot_cache: bool,
+    pub sysroot_profile: SysrootProfile,
+    pub sysroot_features: Vec<String>,
+    /// A shell command that is run before each build of the sysroot, see `sysroot::run_hook`
+    pub sysroot_pre_build: Option<String>,
+    /// A shell command that is run after each build of the sysroot, see `sysroot::run_hook`
+    pub sysroot_post_build: Option<String>,
+    /// Whether the sysroot may be built with a stable or beta toolchain through
+    /// `RUSTC_BOOTSTRAP`, see `--allow-stable`
+    pub allow_stable: bool,
+}
+
+// Neither the global cache nor `allow_stable` affect the compiled sysroot
+impl Hash for Config {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.memcpy.hash(state);
+        self.sysroot_path.hash(state);
+        self.sysroot_profile.hash(state);
+        self.sysroot_features.hash(state);
+        self.sysroot_pre_build.hash(state);
+        self.sysroot_post_build.hash(state);
+    }
+}
+
+/// The cargo profile used for building the sysroot crates
+#[derive(Debug, Clone, Copy, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SysrootProfile {
+    Dev,
+    Release,
+}
+
+impl SysrootProfile {
+    /// Name of the profile in `Cargo.toml`
+    pub fn name(self) -> &'static str {
+        match self {
+            SysrootProfile::Dev => "dev",
+            SysrootProfile::Release => "release",
+        }
+    }
+
+    /// Name of the directory in `target/<triple>` that contains the artifacts of the profile
+    pub fn dir(self) -> &'static str {
+        match self {
+            SysrootProfile::Dev => "debug",
+            SysrootProfile::Release => "release",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Default)]
+impl Default for SysrootProfile {
+    #[cfg(feature = "dev")]
+    fn default() -> SysrootProfile {
+        SysrootProfile::Dev
+    }
+
+    #[cfg(not(feature = "dev"))]
+    fn default() -> SysrootProfile {
+        SysrootProfile::Release
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
 struct ParseConfig {
     pub memcpy: Option<bool>,
     pub sysroot_path: Option<String>,
+    pub sysroot_cache: Option<bool>,
+    pub sysroot_profile: Option<SysrootProfile>,
+    pub sysroot_features: Option<Vec<String>>,
+    pub sysroot_pre_build: Option<String>,
+    pub sysroot_post_build: Option<String>,
+    pub target_path: Option<Vec<String>>,
+    #[serde(alias = "expose-build-info")]
+    pub expose_build_info: Option<bool>,
 }
 
 impl Config {
-    pub fn from_metadata(metadata: &cargo_metadata::Metadata) -> Result<Config, serde_json::Error> {
-        let package_metadata = metadata.packages.first().map(|p| &p.metadata);
-        let crate_metadata = package_metadata
-            .as_ref()
-            .and_then(|m| m.get("cargo-xbuild"));
-        let config = match crate_metadata {
-            Some(json) => serde_json::from_value(json.clone())?,
-            None => ParseConfig::default(),
-        };
+    /// Reads the configuration of the selected `packages`, using the
+    /// `workspace.metadata.cargo-xbuild` table of the workspace root as defaults for the keys
+    /// the packages don't set
+    ///
+    /// `ctoml` is the parsed `Cargo.toml` of the workspace root, see `cargo::toml`.
+    ///
+    /// Without `-p`, the package of `manifest_path` (or of the `Cargo.toml` found from the
+    /// current directory) is selected. For a virtual manifest, all workspace members are built
+    /// and thus selected. All selected packages share one sysroot, so their configurations
+    /// must agree. The keys that don't affect the sysroot are returned as `BuildSettings`.
+    pub fn from_metadata(
+        metadata: &cargo_metadata::Metadata,
+        ctoml: &Toml,
+        packages: &[String],
+        manifest_path: Option<&Path>,
+    ) -> Result<(Config, BuildSettings)> {
+        let workspace = workspace_config(ctoml)?;
 
-        Ok(Config {
+        let selected = selected_packages(metadata, ctoml, packages, manifest_path)?;
+
+        let mut config: Option<(&str, (Config, BuildSettings))> = None;
+        for package in selected {
+            let package_config = Config::new(package_config(package)?.or(workspace.clone()));
+            match config {
+                Some((first, ref first_config)) => {
+                    if *first_config != package_config {
+                        Err(ErrorKind::InvalidConfig(format!(
+                            "packages `{}` and `{}` request conflicting sysroot settings in \
+                             package.metadata.cargo-xbuild, but share one sysroot",
+                            first, package.name
+                        )))?;
+                    }
+                }
+                None => config = Some((&package.name, package_config)),
+            }
+        }
+
+        Ok(config
+            .map(|(_, config)| config)
+            .unwrap_or_else(|| Config::new(workspace)))
+    }
+
+    fn new(config: ParseConfig) -> (Config, BuildSettings) {
+        let settings = BuildSettings {
+            target_path: config
+                .target_path
+                .unwrap_or_default()
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            expose_build_info: config.expose_build_info.unwrap_or(false),
+            ..BuildSettings::default()
+        };
+        let config = Config {
             memcpy: config.memcpy.unwrap_or(true),
             sysroot_path: PathBuf::from(config.sysroot_path.unwrap_or("target/sysroot".into())),
+            sysroot_cache: config.sysroot_cache.unwrap_or(false),
+            sysroot_profile: config.sysroot_profile.unwrap_or_default(),
+            // The order of the features doesn't matter, but they're hashed and passed to cargo
+            sysroot_features: {
+                let mut features = config.sysroot_features.unwrap_or_default();
+                features.sort();
+                features.dedup();
+                features
+            },
+            sysroot_pre_build: config.sysroot_pre_build,
+            sysroot_post_build: config.sysroot_post_build,
+            allow_stable: false,
+        };
+        (config, settings)
+    }
+}
+
+/// Whether any package that the build compiles has a build script or is a proc macro, which
+/// are compiled for the host
+///
+/// `metadata` only lists the workspace members, so the whole graph is only resolved if they
+/// have dependencies outside the workspace. If that fails, e.g. offline, the host crates are
+/// assumed to be needed. Dev-dependencies aren't built, so they don't count.
+pub fn host_crates(
+    toolchain: &Toolchain,
+    metadata: &cargo_metadata::Metadata,
+    args: &cli::Args,
+) -> bool {
+    let members = &metadata.packages;
+    if members.iter().any(is_host_crate) {
+        return true;
+    }
+    if !has_external_deps(members) {
+        return false;
+    }
+    cargo::metadata(toolchain, args, true)
+        .map(|graph| any_built_host_crate(&graph, members))
+        .unwrap_or(true)
+}
+
+/// Whether `members` depend on packages outside the workspace, other than through
+/// dev-dependencies: on a registry or a git repository, or on a path that isn't a member
+fn has_external_deps(members: &[cargo_metadata::Package]) -> bool {
+    members.iter().any(|package| {
+        package.dependencies.iter().any(|dep| {
+            dep.kind != DependencyKind::Development
+                && (dep.source.is_some() || !members.iter().any(|m| m.name == dep.name))
+        })
+    })
+}
+
+fn is_host_crate(package: &cargo_metadata::Package) -> bool {
+    package.targets.iter().any(|target| {
+        target
+            .kind
+            .iter()
+            .any(|kind| kind == "custom-build" || kind == "proc-macro")
+    })
+}
+
+/// Whether a package of the resolved `graph` that `members` depend on, other than through
+/// dev-dependencies, is a host crate
+fn any_built_host_crate(
+    graph: &cargo_metadata::Metadata,
+    members: &[cargo_metadata::Package],
+) -> bool {
+    let resolve = match graph.resolve {
+        Some(ref resolve) => resolve,
+        None => return graph.packages.iter().any(is_host_crate),
+    };
+    let find = |id: &str| graph.packages.iter().find(|p| p.id == id);
+
+    let mut queue = members.iter().map(|m| &*m.id).collect::<Vec<_>>();
+    let mut seen = queue.clone();
+    while let Some(id) = queue.pop() {
+        let (package, node) = match (find(id), resolve.nodes.iter().find(|n| n.id == id)) {
+            (Some(package), Some(node)) => (package, node),
+            _ => continue,
+        };
+        if is_host_crate(package) {
+            return true;
+        }
+        // The resolve doesn't tell the kinds, but the declared dependencies of `package` do
+        for dep in &node.dependencies {
+            let built = find(dep).is_none_or(|dep| {
+                package
+                    .dependencies
+                    .iter()
+                    .any(|d| d.name == dep.name && d.kind != DependencyKind::Development)
+            });
+            if built && !seen.contains(&&**dep) {
+                seen.push(dep);
+                queue.push(dep);
+            }
+        }
+    }
+    false
+}
+
+/// Selects the packages whose configuration applies, see `Config::from_metadata`
+///
+/// `ctoml` is the parsed `Cargo.toml` of the workspace root, whose `workspace.default-members`
+/// are selected for a virtual manifest. `metadata` only lists the workspace members, so `-p`
+/// specs of other packages of the dependency graph, which cargo accepts as well, don't select
+/// anything here (see `check_packages`).
+pub fn selected_packages<'m>(
+    metadata: &'m cargo_metadata::Metadata,
+    ctoml: &Toml,
+    packages: &[String],
+    manifest_path: Option<&Path>,
+) -> Result<Vec<&'m cargo_metadata::Package>> {
+    Ok(if packages.is_empty() {
+        let manifest = match manifest_path {
+            Some(path) => Some(path.to_owned()),
+            None => {
+                let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+                util::search(&cd, "Cargo.toml").map(|p| p.join("Cargo.toml"))
+            }
+        };
+        let manifest = manifest.and_then(|m| m.canonicalize().ok());
+        match metadata.packages.iter().find(|p| {
+            manifest.is_some() && Path::new(&p.manifest_path).canonicalize().ok() == manifest
+        }) {
+            Some(package) => vec![package],
+            None => default_members(metadata, ctoml),
+        }
+    } else {
+        packages
+            .iter()
+            .filter_map(|spec| find_package(&metadata.packages, spec))
+            .collect()
+    })
+}
+
+/// Checks that the `-p` specs of `args` that aren't workspace members name packages of the
+/// dependency graph
+///
+/// cargo would reject them too, but only after the sysroot was built.
+pub fn check_packages(
+    toolchain: &Toolchain,
+    metadata: &cargo_metadata::Metadata,
+    args: &cli::Args,
+) -> Result<()> {
+    let others = args
+        .packages()
+        .iter()
+        .filter(|spec| find_package(&metadata.packages, spec).is_none())
+        .collect::<Vec<_>>();
+    if others.is_empty() {
+        return Ok(());
+    }
+    let graph = cargo::metadata(toolchain, args, true).chain_err(|| "`cargo metadata` failed")?;
+    match others
+        .into_iter()
+        .find(|spec| find_package(&graph.packages, spec).is_none())
+    {
+        Some(spec) => bail!(
+            "package ID specification `{}` did not match any packages",
+            spec
+        ),
+        None => Ok(()),
+    }
+}
+
+/// The package of `packages` that the package ID `spec` names
+fn find_package<'m>(
+    packages: &'m [cargo_metadata::Package],
+    spec: &str,
+) -> Option<&'m cargo_metadata::Package> {
+    // Package ID specs may include a version, e.g. `kernel:0.1.0` or `kernel@0.1.0`
+    let name = spec.split([':', '@']).next().unwrap_or(spec);
+    packages.iter().find(|p| p.name == name)
+}
+
+/// The members that cargo builds for the virtual manifest of the workspace root `ctoml`, i.e.
+/// the `workspace.default-members` if set, and all members otherwise
+fn default_members<'m>(
+    metadata: &'m cargo_metadata::Metadata,
+    ctoml: &Toml,
+) -> Vec<&'m cargo_metadata::Package> {
+    let root = Path::new(&metadata.workspace_root);
+    let manifests = match ctoml
+        .lookup("workspace.default-members")
+        .and_then(|m| m.as_slice())
+    {
+        Some(members) => members
+            .iter()
+            .filter_map(|m| m.as_str())
+            .filter_map(|m| root.join(m).join("Cargo.toml").canonicalize().ok())
+            .collect::<Vec<_>>(),
+        None => return metadata.packages.iter().collect(),
+    };
+    metadata
+        .packages
+        .iter()
+        .filter(|p| {
+            Path::new(&p.manifest_path)
+                .canonicalize()
+                .map(|m| manifests.contains(&m))
+                .unwrap_or(false)
         })
+        .collect()
+}
+
+/// Reads the `package.metadata.cargo-xbuild` table of `package`
+fn package_config(package: &cargo_metadata::Package) -> Result<ParseConfig> {
+    match package.metadata.get("cargo-xbuild") {
+        Some(json) => serde_json::from_value(json.clone()).chain_err(|| {
+            ErrorKind::InvalidConfig(format!(
+                "parsing package.metadata.cargo-xbuild section of `{}` failed",
+                package.name
+            ))
+        }),
+        None => Ok(ParseConfig::default()),
+    }
+}
+
+impl ParseConfig {
+    /// Fills the keys that aren't set in `self` from `defaults`
+    fn or(self, defaults: ParseConfig) -> ParseConfig {
+        ParseConfig {
+            memcpy: self.memcpy.or(defaults.memcpy),
+            sysroot_path: self.sysroot_path.or(defaults.sysroot_path),
+            sysroot_cache: self.sysroot_cache.or(defaults.sysroot_cache),
+            sysroot_profile: self.sysroot_profile.or(defaults.sysroot_profile),
+            sysroot_features: self.sysroot_features.or(defaults.sysroot_features),
+            sysroot_pre_build: self.sysroot_pre_build.or(defaults.sysroot_pre_build),
+            sysroot_post_build: self.sysroot_post_build.or(defaults.sysroot_post_build),
+            target_path: self.target_path.or(defaults.target_path),
+            expose_build_info: self.expose_build_info.or(defaults.expose_build_info),
+        }
+    }
+}
+
+/// Reads the `workspace.metadata.cargo-xbuild` table of the workspace root manifest
+fn workspace_config(ctoml: &Toml) -> Result<ParseConfig> {
+    match ctoml.lookup("workspace.metadata.cargo-xbuild") {
+        Some(table) => serde_json::from_value(to_json(table)).chain_err(|| {
+            ErrorKind::InvalidConfig(
+                "parsing workspace.metadata.cargo-xbuild section failed".into(),
+            )
+        }),
+        None => Ok(ParseConfig::default()),
+    }
+}
+
+/// Converts a TOML value into the equivalent JSON value, so that it can be deserialized
+fn to_json(value: &toml::Value) -> serde_json::Value {
+    match *value {
+        toml::Value::String(ref s) | toml::Value::Datetime(ref s) => {
+            serde_json::Value::String(s.clone())
+        }
+        toml::Value::Integer(i) => serde_json::Value::from(i),
+        toml::Value::Float(f) => serde_json::Value::from(f),
+        toml::Value::Boolean(b) => serde_json::Value::Bool(b),
+        toml::Value::Array(ref a) => serde_json::Value::Array(a.iter().map(to_json).collect()),
+        toml::Value::Table(ref t) => {
+            serde_json::Value::Object(t.iter().map(|(k, v)| (k.clone(), to_json(v))).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::slice;
+
+    use cargo_metadata::Metadata;
+    use serde_json;
+    use tempdir::TempDir;
+
+    use rustc::Toolchain;
+    use {cargo, cli};
+
+    use super::{
+        any_built_host_crate, check_packages, has_external_deps, host_crates, selected_packages,
+    };
+
+    /// Creates a workspace with the root manifest `root` and the members `members`, and the
+    /// metadata that `cargo metadata --no-deps` reports for it
+    fn workspace(root: &str, members: &[&str]) -> (TempDir, Metadata) {
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::write(td.path().join("Cargo.toml"), root).unwrap();
+        let mut packages = vec![];
+        for member in members {
+            let dir = td.path().join(member);
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("Cargo.toml"), "").unwrap();
+            packages.push(format!(
+                r#"{{"name": "{0}", "version": "0.1.0", "id": "{0} 0.1.0", "source": null,
+                    "dependencies": [], "targets": [], "features": {{}},
+                    "manifest_path": "{1}"}}"#,
+                member,
+                dir.join("Cargo.toml").display()
+            ));
+        }
+        let metadata = serde_json::from_str(&format!(
+            r#"{{"packages": [{}], "workspace_members": [], "resolve": null,
+                "workspace_root": "{}", "target_directory": "", "version": 1}}"#,
+            packages.join(", "),
+            td.path().display()
+        ))
+        .unwrap();
+        (td, metadata)
+    }
+
+    fn names(metadata: &Metadata, manifest: &Path) -> Vec<String> {
+        let ctoml = cargo::toml(Path::new(&metadata.workspace_root)).unwrap();
+        selected_packages(metadata, &ctoml, &[], Some(manifest))
+            .unwrap()
+            .iter()
+            .map(|p| p.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn virtual_manifest_selects_default_members() {
+        let (td, metadata) = workspace(
+            "[workspace]\nmembers = [\"a\", \"b\"]\ndefault-members = [\"b\"]\n",
+            &["a", "b"],
+        );
+        assert_eq!(names(&metadata, &td.path().join("Cargo.toml")), ["b"]);
+        assert_eq!(names(&metadata, &td.path().join("a/Cargo.toml")), ["a"]);
+    }
+
+    #[test]
+    fn virtual_manifest_selects_all_members() {
+        let (td, metadata) = workspace("[workspace]\nmembers = [\"a\", \"b\"]\n", &["a", "b"]);
+        assert_eq!(names(&metadata, &td.path().join("Cargo.toml")), ["a", "b"]);
+    }
+
+    #[test]
+    fn packages_outside_the_workspace_select_no_configuration() {
+        let (td, metadata) = workspace("[workspace]\nmembers = [\"a\", \"b\"]\n", &["a", "b"]);
+        let ctoml = cargo::toml(td.path()).unwrap();
+        let specs = vec!["b@0.1.0".to_owned(), "libc".to_owned()];
+        let selected = selected_packages(&metadata, &ctoml, &specs, None).unwrap();
+        assert_eq!(selected.iter().map(|p| &*p.name).collect::<Vec<_>>(), ["b"]);
+
+        // Members are found without resolving the dependency graph
+        let args = cli::parse(vec!["-p".to_owned(), "a".to_owned()]);
+        check_packages(&Toolchain::default(), &metadata, &args).unwrap();
+    }
+
+    /// A package with a target of `kind` and registry dependencies of the given kinds
+    fn package(name: &str, kind: &str, deps: &[(&str, &str)]) -> String {
+        let deps = deps
+            .iter()
+            .map(|&(dep, kind)| {
+                format!(
+                    r#"{{"name": "{}", "source": "registry+https://github.com/rust-lang/crates.io-index",
+                        "req": "^0.1", "kind": "{}", "optional": false,
+                        "uses_default_features": true, "features": [], "target": null}}"#,
+                    dep, kind
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            r#"{{"name": "{0}", "version": "0.1.0", "id": "{0} 0.1.0", "source": null,
+                "dependencies": [{1}], "targets": [{{"name": "{0}", "kind": ["{2}"],
+                "src_path": ""}}], "features": {{}}, "manifest_path": ""}}"#,
+            name,
+            deps.join(", "),
+            kind
+        )
+    }
+
+    /// The metadata of `packages`, with a resolve that lists the `(package, dependencies)`
+    /// of `nodes`
+    fn metadata(packages: &[String], nodes: &[(&str, &[&str])]) -> Metadata {
+        let nodes = nodes
+            .iter()
+            .map(|&(id, deps)| {
+                let deps = deps.iter().map(|d| format!("\"{} 0.1.0\"", d));
+                format!(
+                    r#"{{"id": "{} 0.1.0", "dependencies": [{}]}}"#,
+                    id,
+                    deps.collect::<Vec<_>>().join(", ")
+                )
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_str(&format!(
+            r#"{{"packages": [{}], "workspace_members": [], "resolve": {{"nodes": [{}]}},
+                "workspace_root": "", "target_directory": "", "version": 1}}"#,
+            packages.join(", "),
+            nodes.join(", ")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn build_script_needs_the_host_crates() {
+        let members = metadata(&[package("app", "custom-build", &[])], &[]);
+        let args = cli::parse(vec![]);
+        assert!(host_crates(&Toolchain::default(), &members, &args));
+
+        // A build script of a dependency counts too
+        let app = package("app", "lib", &[("dep", "normal")]);
+        let members = metadata(slice::from_ref(&app), &[]);
+        let graph = metadata(
+            &[app, package("dep", "custom-build", &[])],
+            &[("app", &["dep"]), ("dep", &[])],
+        );
+        assert!(any_built_host_crate(&graph, &members.packages));
+    }
+
+    #[test]
+    fn path_dependency_outside_the_workspace_is_resolved() {
+        let registry = r#""registry+https://github.com/rust-lang/crates.io-index""#;
+        let app = package("app", "lib", &[("dep", "normal")]).replace(registry, "null");
+        let members = metadata(slice::from_ref(&app), &[]);
+        assert!(has_external_deps(&members.packages));
+        let graph = metadata(
+            &[app.clone(), package("dep", "proc-macro", &[])],
+            &[("app", &["dep"]), ("dep", &[])],
+        );
+        assert!(any_built_host_crate(&graph, &members.packages));
+
+        // Path dependencies on other members are listed already
+        let members = metadata(&[app, package("dep", "lib", &[])], &[]);
+        assert!(!has_external_deps(&members.packages));
+    }
+
+    #[test]
+    fn pure_no_std_lib_skips_the_host_crates() {
+        let members = metadata(&[package("app", "lib", &[])], &[]);
+        let args = cli::parse(vec![]);
+        assert!(!host_crates(&Toolchain::default(), &members, &args));
+
+        // Dev-dependencies aren't built, so `cargo metadata` isn't even run for them
+        let app = package("app", "lib", &[("derive", "dev")]);
+        let members = metadata(slice::from_ref(&app), &[]);
+        assert!(!host_crates(&Toolchain::default(), &members, &args));
+        let graph = metadata(
+            &[app, package("derive", "proc-macro", &[])],
+            &[("app", &["derive"]), ("derive", &[])],
+        );
+        assert!(!any_built_host_crate(&graph, &members.packages));
     }
 }
@@ -1,3 +1,150 @@
 #![allow(unknown_lints)]
 #![allow(unused_doc_comments)]
-error_chain!();
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+error_chain! {
+    errors {
+        MissingRustSrc(toolchain: Option<String>) {
+            description("`rust-src` component not found")
+            display("{}", match *toolchain {
+                Some(ref toolchain) => format!("`rust-src` component not found. Run `rustup \
+                    component add rust-src --toolchain {}` or pass `--auto-install-rust-src`.",
+                    toolchain),
+                None => "`rust-src` component not found. If this toolchain isn't managed by \
+                    rustup, set the `XARGO_RUST_SRC` env variable to the `src` directory of the \
+                    Rust source instead.".to_owned(),
+            })
+        }
+        SysrootContainsSpaces(sysroot: String) {
+            description("sysroot must not contain spaces")
+            display("Sysroot must not contain spaces with this toolchain!\n\
+                     Cargo only supports spaces in rustflags through \
+                     `CARGO_ENCODED_RUSTFLAGS`, which requires Rust 1.55 or newer.\n\n\
+                     The sysroot is `{}`.\n\n\
+                     To override this error, you can set the `XBUILD_ALLOW_SYSROOT_SPACES`\
+                     environment variable.", sysroot)
+        }
+        InvalidConfig(msg: String) {
+            description("invalid configuration")
+            display("{}", msg)
+        }
+        ConfigError(path: PathBuf, key: String, msg: String) {
+            description("invalid value in .cargo/config")
+            display("{}: {} {}", path.display(), key, msg)
+        }
+        LockTimeout(msg: String) {
+            description("timed out waiting for a file lock")
+            display("timed out waiting for file lock on {}", msg)
+        }
+        TargetSpecNotFound(path: PathBuf) {
+            description("target specification not found")
+            display("target specification {} does not exist", path.display())
+        }
+        CommandFailed(command: String, status: ExitStatus) {
+            description("command failed")
+            display("`{}` failed with exit code: {:?}", command, status.code())
+        }
+        CargoFailed(status: ExitStatus) {
+            description("cargo failed")
+            display("cargo failed with exit code: {:?}", status.code())
+        }
+        SysrootBuildFailed(krate: String) {
+            description("building the sysroot failed")
+            display("building the sysroot crate `{}` failed", krate)
+        }
+        SysrootTargetMismatch(sysroot: PathBuf, triple: String, found: Vec<String>) {
+            description("the sysroot wasn't built for the target")
+            display("the sysroot {} contains no artifacts for `{}`{}", sysroot.display(), triple,
+                if found.is_empty() {
+                    String::new()
+                } else {
+                    format!(", only for `{}`", found.join("`, `"))
+                })
+        }
+        SysrootNotUsed(msg: String) {
+            description("the crate wasn't compiled against the sysroot")
+            display("{}", msg)
+        }
+        SysrootOutdated(msg: String) {
+            description("the sysroot is outdated, but can't be rebuilt")
+            display("{}", msg)
+        }
+        SysrootHookFailed(kind: String, hook: String) {
+            description("a sysroot hook failed")
+            display("the sysroot {} hook `{}` failed", kind, hook)
+        }
+    }
+}
+
+impl Error {
+    /// The exit code of `cargo-xbuild` for this error, see `--help`
+    pub fn exit_code(&self) -> i32 {
+        match *self.kind() {
+            ErrorKind::SysrootBuildFailed(..) | ErrorKind::SysrootHookFailed(..) => 102,
+            ErrorKind::InvalidConfig(..)
+            | ErrorKind::ConfigError(..)
+            | ErrorKind::TargetSpecNotFound(..)
+            | ErrorKind::SysrootContainsSpaces(..) => 103,
+            ErrorKind::MissingRustSrc(..) => 104,
+            ErrorKind::LockTimeout(..) => 105,
+            ErrorKind::SysrootOutdated(..) => 106,
+            // Like cargo itself, see `exit_like` for termination by a signal
+            ErrorKind::CargoFailed(ref status) => status.code().unwrap_or(1),
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn missing_rust_src_names_toolchain() {
+        let err = Error::from(ErrorKind::MissingRustSrc(Some("nightly".into())));
+        assert!(err
+            .to_string()
+            .contains("rustup component add rust-src --toolchain nightly"));
+
+        let err = Error::from(ErrorKind::MissingRustSrc(None));
+        assert!(err.to_string().contains("XARGO_RUST_SRC"));
+    }
+
+    #[test]
+    fn target_spec_not_found_names_path() {
+        let err = Error::from(ErrorKind::TargetSpecNotFound(PathBuf::from("foo.json")));
+        assert_eq!(
+            err.to_string(),
+            "target specification foo.json does not exist"
+        );
+    }
+
+    #[test]
+    fn config_error_names_file_and_key() {
+        let err = Error::from(ErrorKind::ConfigError(
+            PathBuf::from("/project/.cargo/config"),
+            "build.target".to_owned(),
+            "must be a string".to_owned(),
+        ));
+        assert_eq!(
+            err.to_string(),
+            "/project/.cargo/config: build.target must be a string"
+        );
+        assert_eq!(err.exit_code(), 103);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cargo_failed_keeps_exit_code() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let err = Error::from(ErrorKind::CargoFailed(ExitStatus::from_raw(101 << 8)));
+        assert_eq!(err.exit_code(), 101);
+        // Terminated by SIGKILL
+        let err = Error::from(ErrorKind::CargoFailed(ExitStatus::from_raw(9)));
+        assert_eq!(err.exit_code(), 1);
+    }
+}
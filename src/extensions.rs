@@ -1,12 +1,22 @@
-use std::io;
-use std::io::Write;
-use std::process::{Command, ExitStatus};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+use serde_json::{self, Value};
 
 use errors::*;
 
 pub trait CommandExt {
     fn run(&mut self, verbose: bool) -> Result<()>;
+    fn run_prefixed(&mut self, prefix: &str, verbose: bool) -> Result<()>;
     fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus>;
+    fn run_and_get_artifacts(&mut self, verbose: bool) -> Result<(ExitStatus, Vec<PathBuf>)>;
+    fn run_and_get_invocations(
+        &mut self,
+        show: bool,
+        verbose: bool,
+    ) -> Result<(ExitStatus, Vec<String>)>;
     fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String>;
 }
 
@@ -18,11 +28,47 @@ impl CommandExt for Command {
         if status.success() {
             Ok(())
         } else {
-            Err(format!(
-                "`{:?}` failed with exit code: {:?}",
-                self,
-                status.code()
-            ))?
+            Err(ErrorKind::CommandFailed(format!("{:?}", self), status))?
+        }
+    }
+
+    /// Runs the command to completion, prefixing each line of its output with `prefix`
+    ///
+    /// Both stdout and stderr of the command are written to stderr.
+    fn run_prefixed(&mut self, prefix: &str, verbose: bool) -> Result<()> {
+        fn forward<R: Read>(prefix: &str, output: R) {
+            for line in BufReader::new(output).lines() {
+                match line {
+                    Ok(line) => writeln!(io::stderr(), "{} {}", prefix, line).ok(),
+                    Err(_) => break,
+                };
+            }
+        }
+
+        if verbose {
+            writeln!(io::stderr(), "{} + {:?}", prefix, self).ok();
+        }
+
+        let mut child = self
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        let stdout = child.stdout.take().unwrap();
+        let stdout_prefix = prefix.to_owned();
+        let stdout = thread::spawn(move || forward(&stdout_prefix, stdout));
+        forward(prefix, child.stderr.take().unwrap());
+        stdout.join().ok();
+
+        let status = child
+            .wait()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(ErrorKind::CommandFailed(format!("{:?}", self), status))?
         }
     }
 
@@ -36,6 +82,92 @@ impl CommandExt for Command {
             .chain_err(|| format!("couldn't execute `{:?}`", self))
     }
 
+    /// Runs a `cargo` command with `--message-format=json-render-diagnostics` to completion and
+    /// returns the files of the `compiler-artifact` messages
+    ///
+    /// Lines of stdout that aren't JSON messages, e.g. the output of `cargo run`, are written
+    /// to stderr.
+    fn run_and_get_artifacts(&mut self, verbose: bool) -> Result<(ExitStatus, Vec<PathBuf>)> {
+        if verbose {
+            writeln!(io::stderr(), "+ {:?}", self).ok();
+        }
+
+        let mut child = self
+            .stdout(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        let mut artifacts = vec![];
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match serde_json::from_str::<Value>(&line) {
+                Ok(ref message) if message.get("reason").is_some() => {
+                    if message.get("reason").and_then(|r| r.as_str()) == Some("compiler-artifact") {
+                        let files = message.get("filenames").and_then(|f| f.as_array());
+                        for file in files.into_iter().flatten().filter_map(|f| f.as_str()) {
+                            artifacts.push(PathBuf::from(file));
+                        }
+                    }
+                }
+                _ => {
+                    writeln!(io::stderr(), "{}", line).ok();
+                }
+            }
+        }
+
+        let status = child
+            .wait()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+        Ok((status, artifacts))
+    }
+
+    /// Runs a verbose `cargo` command to completion and returns the `rustc` invocations of its
+    /// `Running` lines
+    ///
+    /// The other lines of stderr are forwarded to stderr, and the `Running` and `Fresh` lines
+    /// that `--verbose` adds only if `show` is set.
+    fn run_and_get_invocations(
+        &mut self,
+        show: bool,
+        verbose: bool,
+    ) -> Result<(ExitStatus, Vec<String>)> {
+        if verbose {
+            writeln!(io::stderr(), "+ {:?}", self).ok();
+        }
+
+        let mut child = self
+            .stderr(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        let mut invocations = vec![];
+        for line in BufReader::new(child.stderr.take().unwrap()).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let plain = strip_colors(&line);
+            let plain = plain.trim_start();
+            if plain.starts_with("Running `") && plain.contains("--crate-name") {
+                invocations.push(plain["Running `".len()..].trim_end_matches('`').to_owned());
+                if !show {
+                    continue;
+                }
+            } else if plain.starts_with("Fresh ") && !show {
+                continue;
+            }
+            writeln!(io::stderr(), "{}", line).ok();
+        }
+
+        let status = child
+            .wait()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+        Ok((status, invocations))
+    }
+
     /// Runs the command to completion and returns its stdout
     fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String> {
         if verbose {
@@ -50,11 +182,48 @@ impl CommandExt for Command {
             Ok(String::from_utf8(out.stdout)
                 .chain_err(|| format!("`{:?}` output was not UTF-8", self))?)
         } else {
-            Err(format!(
-                "`{:?}` failed with exit code: {:?}",
-                self,
-                out.status.code()
-            ))?
+            Err(ErrorKind::CommandFailed(format!("{:?}", self), out.status))?
         }
     }
 }
+
+/// Removes the ANSI escape sequences that color the status of cargo's messages
+fn strip_colors(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip to the final byte of the sequence, e.g. the `m` of `\u{1b}[1;32m`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::CommandExt;
+
+    #[test]
+    fn run_prefixed_reports_failure() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2; exit 3"]);
+        let err = cmd.run_prefixed("[test]", false).unwrap_err();
+        assert!(err.to_string().contains("exit code: Some(3)"));
+    }
+
+    #[test]
+    fn run_prefixed_succeeds() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2"]);
+        cmd.run_prefixed("[test]", false).unwrap();
+    }
+}
@@ -1,14 +1,19 @@
 //! Copy paste of Cargo's src/util/flock.rs with modifications to not depend on
 //! other Cargo stuff
 
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::path::{Display, Path, PathBuf};
-use std::{fs, io};
+use std::time::{Duration, Instant};
+use std::{env, fs, io, process, thread};
 
 use fs2;
 use fs2::FileExt;
 
+use errors::*;
+use status::{self, StatusEvent};
+
 #[derive(PartialEq)]
 enum State {
     Exclusive,
@@ -18,6 +23,7 @@ enum State {
 pub struct FileLock {
     file: File,
     path: PathBuf,
+    state: State,
 }
 
 impl FileLock {
@@ -29,11 +35,15 @@ impl FileLock {
         &self.path
     }
 
-    pub fn remove_siblings(&self) -> io::Result<()> {
+    /// Removes the entries next to the lock file for which `remove` returns `true`
+    pub fn remove_siblings<F>(&self, remove: F) -> io::Result<()>
+    where
+        F: Fn(&OsStr) -> bool,
+    {
         let path = self.path();
         for entry in path.parent().unwrap().read_dir()? {
             let entry = entry?;
-            if Some(&entry.file_name()[..]) == path.file_name() {
+            if Some(&entry.file_name()[..]) == path.file_name() || !remove(&entry.file_name()) {
                 continue;
             }
             let kind = entry.file_type()?;
@@ -53,7 +63,7 @@ pub struct Filesystem {
 
 impl Filesystem {
     pub fn new(path: PathBuf) -> Filesystem {
-        Filesystem { path: path }
+        Filesystem { path }
     }
 
     pub fn join<T>(&self, other: T) -> Filesystem
@@ -95,49 +105,134 @@ impl Filesystem {
         msg: &str,
     ) -> io::Result<FileLock> {
         let path = self.path.join(path);
-
         let f = opts.open(&path).or_else(|e| {
-            if e.kind() == io::ErrorKind::NotFound && state == State::Exclusive {
+            if e.kind() == io::ErrorKind::NotFound {
                 create_dir_all(path.parent().unwrap())?;
+                if state == State::Shared {
+                    // The read-only `opts` can't create the lock file. Neither can anyone else in
+                    // a read-only directory, e.g. of a prebuilt sysroot, so locking the directory
+                    // itself is as good there
+                    if let Err(e) = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(&path)
+                    {
+                        return if cfg!(unix) {
+                            File::open(path.parent().unwrap()).map_err(|_| e)
+                        } else {
+                            Err(e)
+                        };
+                    }
+                }
                 opts.open(&path)
             } else {
                 Err(e)
             }
         })?;
 
+        // Shared locks are `flock(LOCK_SH)` on Unix and `LockFileEx` without
+        // `LOCKFILE_EXCLUSIVE_LOCK` on Windows, both of which admit any number of readers
+        let timeout = timeout()?;
         match state {
-            State::Exclusive => {
-                acquire(msg, &path, &|| f.try_lock_exclusive(), &|| {
-                    f.lock_exclusive()
-                })?;
-            }
-            State::Shared => {
-                acquire(msg, &path, &|| f.try_lock_shared(), &|| f.lock_shared())?;
-            }
+            State::Exclusive => acquire(msg, &path, &|| f.try_lock_exclusive(), timeout)?,
+            State::Shared => acquire(msg, &path, &|| f.try_lock_shared(), timeout)?,
+        }
+
+        if state == State::Exclusive {
+            // Lets processes waiting for this lock report who holds it
+            f.set_len(0)
+                .and_then(|_| write!(&f, "{}", process::id()))
+                .ok();
         }
 
         Ok(FileLock {
             file: f,
-            path: path,
+            path,
+            state,
         })
     }
 
-    pub fn display(&self) -> Display {
+    pub fn display(&self) -> Display<'_> {
         self.path.display()
     }
+
+    pub fn as_path_unlocked(&self) -> &Path {
+        &self.path
+    }
 }
 
 impl Drop for FileLock {
     fn drop(&mut self) {
+        if self.state == State::Exclusive {
+            self.file.set_len(0).ok();
+        }
         self.file.unlock().ok();
     }
 }
 
+/// Like `chain_err`, but reports an expired `XBUILD_LOCK_TIMEOUT` as `LockTimeout`
+pub fn chain_lock_err<T, F>(result: io::Result<T>, what: &str, context: F) -> Result<T>
+where
+    F: FnOnce() -> String,
+{
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+            Err(ErrorKind::LockTimeout(what.to_owned()))?
+        }
+        result => result.chain_err(context),
+    }
+}
+
+/// The time after which waiting for a lock is given up, from `XBUILD_LOCK_TIMEOUT` in seconds
+fn timeout() -> io::Result<Option<Duration>> {
+    match env::var("XBUILD_LOCK_TIMEOUT") {
+        Ok(secs) => match secs.parse() {
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "XBUILD_LOCK_TIMEOUT must be a number of seconds (found `{}`)",
+                    secs
+                ),
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// The process that holds the exclusive lock on `path`, if known
+///
+/// A process that is killed while holding the lock leaves its process ID behind, and shared
+/// holders don't record theirs, so only a running process is reported.
+fn holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok())
+        .filter(|&pid| is_running(pid))
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let signaled = unsafe { ::libc::kill(pid as i32, 0) == 0 };
+    signaled || io::Error::last_os_error().raw_os_error() == Some(::libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// Acquires a lock through `try`, polling while another process holds it
+///
+/// While waiting, a `LockWait` event is emitted after a second and then every ten seconds,
+/// and a `LockAcquired` event once the lock is acquired. Waiting fails with `TimedOut` after
+/// `timeout`, see `XBUILD_LOCK_TIMEOUT`.
 fn acquire(
     msg: &str,
     path: &Path,
-    try: &Fn() -> io::Result<()>,
-    block: &Fn() -> io::Result<()>,
+    try: &dyn Fn() -> io::Result<()>,
+    timeout: Option<Duration>,
 ) -> io::Result<()> {
     #[cfg(all(target_os = "linux", not(target_env = "musl")))]
     fn is_on_nfs_mount(path: &Path) -> bool {
@@ -163,30 +258,63 @@ fn acquire(
         false
     }
 
-    if is_on_nfs_mount(path) {
-        return Ok(());
+    #[cfg(unix)]
+    fn is_unsupported(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(::libc::ENOLCK) || e.raw_os_error() == Some(::libc::EOPNOTSUPP)
     }
 
-    match try() {
-        Ok(_) => return Ok(()),
+    #[cfg(not(unix))]
+    fn is_unsupported(_e: &io::Error) -> bool {
+        false
+    }
+
+    // Some NFS mounts don't support locking at all, so there it's only attempted
+    let on_nfs = is_on_nfs_mount(path);
+    let acquired = || match try() {
+        Ok(_) => Ok(true),
+        Err(ref e) if on_nfs && is_unsupported(e) => Ok(true),
         #[cfg(target_os = "macos")]
-        Err(ref e) if e.raw_os_error() == Some(::libc::ENOTSUP) => return Ok(()),
+        Err(ref e) if e.raw_os_error() == Some(::libc::ENOTSUP) => Ok(true),
         Err(e) => {
-            if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
-                return Err(e);
+            if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                Ok(false)
+            } else {
+                Err(e)
             }
         }
+    };
+
+    if acquired()? {
+        return Ok(());
     }
 
-    writeln!(
-        io::stderr(),
-        "{:>12} waiting for file lock on {}",
-        "Blocking",
-        msg
-    )
-    .ok();
+    let start = Instant::now();
+    let mut report = Duration::from_secs(1);
+    loop {
+        thread::sleep(Duration::from_millis(100));
+        if acquired()? {
+            status::emit(StatusEvent::LockAcquired {
+                what: msg.to_owned(),
+                waited: start.elapsed(),
+            });
+            return Ok(());
+        }
 
-    block()
+        let waited = start.elapsed();
+        if timeout.is_some_and(|timeout| waited >= timeout) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out waiting for file lock on {}", msg),
+            ));
+        }
+        if waited >= report {
+            status::emit(StatusEvent::LockWait {
+                what: msg.to_owned(),
+                pid: holder(path),
+            });
+            report += Duration::from_secs(10);
+        }
+    }
 }
 
 fn create_dir_all(path: &Path) -> io::Result<()> {
@@ -210,3 +338,102 @@ fn create_dir(path: &Path) -> io::Result<()> {
         Err(e) => Err(e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use fs2::FileExt;
+    use tempdir::TempDir;
+
+    use errors::ErrorKind;
+    use status::{self, StatusEvent};
+
+    use super::{acquire, chain_lock_err, Filesystem};
+
+    #[test]
+    fn waiting_for_a_held_lock_is_reported_and_times_out() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let _held = Filesystem::new(td.path().to_owned())
+            .open_rw(".sentinel", "the sysroot")
+            .unwrap();
+        let path = td.path().join(".sentinel");
+        let file = File::open(&path).unwrap();
+
+        let mut waits = vec![];
+        let result = status::with_handler(
+            &mut |event: &StatusEvent| {
+                if let StatusEvent::LockWait { ref what, pid } = *event {
+                    waits.push((what.clone(), pid));
+                }
+            },
+            || {
+                acquire(
+                    "the sysroot",
+                    &path,
+                    &|| FileExt::try_lock_exclusive(&file),
+                    Some(Duration::from_millis(1500)),
+                )
+            },
+        );
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            waits,
+            [("the sysroot".to_owned(), Some(::std::process::id()))]
+        );
+
+        match *chain_lock_err::<(), _>(Err(err), "the sysroot", String::new)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::LockTimeout(ref what) => assert_eq!(what, "the sysroot"),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn free_lock_is_acquired_immediately() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let path = td.path().join(".sentinel");
+        let file = File::create(&path).unwrap();
+        let acquired = acquire(
+            "the sysroot",
+            &path,
+            &|| FileExt::try_lock_exclusive(&file),
+            Some(Duration::from_secs(0)),
+        );
+        acquired.unwrap();
+    }
+
+    #[test]
+    fn shared_locks_overlap() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let fs = Filesystem::new(td.path().to_owned());
+        let _reader = fs.open_ro(".sentinel", "the sysroot").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let other = Filesystem::new(td.path().to_owned());
+        thread::spawn(move || {
+            let _reader = other.open_ro(".sentinel", "the sysroot").unwrap();
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("the second reader waited for the first one");
+
+        // Writers still wait for the readers
+        let path = td.path().join(".sentinel");
+        let file = File::open(&path).unwrap();
+        let acquired = acquire(
+            "the sysroot",
+            &path,
+            &|| FileExt::try_lock_exclusive(&file),
+            Some(Duration::from_secs(0)),
+        );
+        assert_eq!(acquired.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
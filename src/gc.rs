@@ -0,0 +1,154 @@
+use std::env;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cache::Cache;
+use config::Config;
+use errors::*;
+use util;
+use xargo::Home;
+
+const DAY: u64 = 24 * 60 * 60;
+const MIB: u64 = 1024 * 1024;
+
+/// Limits on the sysroots that are kept around
+pub struct Policy {
+    /// Maximum time since the last use, in seconds
+    pub max_age: Option<u64>,
+    /// Maximum total size of all sysroots, in bytes
+    pub max_size: Option<u64>,
+}
+
+impl Policy {
+    /// Reads the policy from `XBUILD_SYSROOT_MAX_AGE` (in days, defaults to 30) and
+    /// `XBUILD_SYSROOT_MAX_SIZE` (in MiB, unlimited by default)
+    pub fn from_env() -> Result<Policy> {
+        fn var(name: &str) -> Result<Option<u64>> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("{} must be a non-negative integer", name).into()),
+                Err(_) => Ok(None),
+            }
+        }
+
+        Ok(Policy {
+            max_age: Some(var("XBUILD_SYSROOT_MAX_AGE")?.unwrap_or(30) * DAY),
+            max_size: var("XBUILD_SYSROOT_MAX_SIZE")?.map(|size| size * MIB),
+        })
+    }
+
+    /// Returns the indices of the entries to evict
+    ///
+    /// These are all entries that exceed the maximum age, and then the least recently used
+    /// entries until the remaining ones fit into the maximum size.
+    pub fn select(&self, entries: &[Entry], now: u64) -> Vec<usize> {
+        let mut order = (0..entries.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| entries[i].last_used);
+
+        let mut total = entries.iter().map(|e| e.size).sum::<u64>();
+        let mut evict = vec![];
+        for i in order {
+            let expired = self
+                .max_age
+                .is_some_and(|max| now.saturating_sub(entries[i].last_used) > max);
+            let too_large = self.max_size.is_some_and(|max| total > max);
+            if expired || too_large {
+                total -= entries[i].size;
+                evict.push(i);
+            }
+        }
+        evict
+    }
+}
+
+/// A sysroot that may be evicted
+pub struct Entry {
+    pub name: String,
+    pub last_used: u64,
+    pub size: u64,
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Records in `file` that a sysroot was used just now
+pub fn touch(file: &Path) -> Result<()> {
+    util::write(file, &now().to_string())
+}
+
+/// Reads the time recorded by `touch`, treating a missing or invalid file as "never"
+pub fn last_used(file: &Path) -> u64 {
+    util::read(file)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Evicts sysroots of `home` and of the global cache according to the `Policy` from the
+/// environment, never touching the sysroots of the triples in `keep`
+pub fn collect(home: &Home, config: &Config, keep: &[&str]) -> Result<Vec<Entry>> {
+    let policy = Policy::from_env()?;
+
+    let mut removed = home.gc(&policy, keep)?;
+    if let Some(cache) = Cache::from_env(config)? {
+        removed.extend(cache.gc(&policy)?);
+    }
+
+    Ok(removed)
+}
+
+/// Formats `bytes` in MiB
+pub fn human_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / MIB as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, last_used: u64, size: u64) -> Entry {
+        Entry {
+            name: name.to_owned(),
+            last_used,
+            size,
+        }
+    }
+
+    #[test]
+    fn select_evicts_expired_entries() {
+        let policy = Policy {
+            max_age: Some(10 * DAY),
+            max_size: None,
+        };
+        let now = 100 * DAY;
+        let entries = [
+            entry("old", 80 * DAY, MIB),
+            entry("new", 95 * DAY, MIB),
+            entry("never", 0, MIB),
+        ];
+        let mut evict = policy.select(&entries, now);
+        evict.sort();
+        assert_eq!(evict, vec![0, 2]);
+    }
+
+    #[test]
+    fn select_evicts_least_recently_used_beyond_size() {
+        let policy = Policy {
+            max_age: None,
+            max_size: Some(3 * MIB),
+        };
+        let entries = [
+            entry("a", 3, 2 * MIB),
+            entry("b", 1, 2 * MIB),
+            entry("c", 2, MIB),
+        ];
+        assert_eq!(policy.select(&entries, 4), vec![1]);
+    }
+}
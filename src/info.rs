@@ -0,0 +1,268 @@
+use std::fs;
+use std::hash::Hasher;
+use std::path::Path;
+
+use rustc_version::VersionMeta;
+use serde_json;
+use walkdir::WalkDir;
+
+use errors::*;
+use gc;
+use rustc::{Src, Target};
+use util;
+use {BuildContext, CompilationMode};
+
+/// The file next to the `lib` directory of a sysroot that records what it was built from
+pub const FILE: &str = "sysroot-info.json";
+
+/// What a sysroot was built from, see `--print sysroot-info`
+#[derive(Serialize)]
+pub struct SysrootInfo {
+    xbuild_version: &'static str,
+    /// When the sysroot was built, in seconds since the Unix epoch
+    built: u64,
+    /// The sysroot hash, as a string like in `.hash`
+    hash: String,
+    target: TargetInfo,
+    rustc: RustcInfo,
+    rust_src: SrcInfo,
+    profile: ProfileInfo,
+    memcpy: bool,
+    features: Vec<String>,
+    pub crates: Vec<CrateInfo>,
+}
+
+#[derive(Serialize)]
+struct TargetInfo {
+    triple: String,
+    spec: Option<String>,
+    /// The hash of the contents of `spec`
+    spec_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RustcInfo {
+    path: String,
+    version: String,
+    commit_hash: Option<String>,
+    commit_date: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SrcInfo {
+    path: String,
+    /// The hash of the source files of the sysroot crates
+    fingerprint: String,
+}
+
+#[derive(Serialize)]
+struct ProfileInfo {
+    name: &'static str,
+    /// The profile section of `Cargo.toml` that was appended to the sysroot manifests
+    settings: Option<String>,
+}
+
+/// A crate that was built for the sysroot, along with its dependencies
+#[derive(Serialize)]
+pub struct CrateInfo {
+    name: String,
+    /// The generated `Cargo.toml`
+    manifest: String,
+    /// The packages of the `Cargo.lock` that cargo resolved
+    dependencies: Vec<Dependency>,
+}
+
+#[derive(Serialize)]
+struct Dependency {
+    name: String,
+    version: String,
+    source: Option<String>,
+}
+
+impl SysrootInfo {
+    pub fn new(
+        cmode: &CompilationMode,
+        ctx: &BuildContext,
+        meta: &VersionMeta,
+        src: &Src,
+        hash: u64,
+        unwind: bool,
+    ) -> Result<SysrootInfo> {
+        let config = &ctx.crate_config;
+        let (spec, spec_hash) = match *cmode {
+            CompilationMode::Cross(Target::Custom { ref json, .. }) => {
+                let mut hasher = Fnv::new();
+                hasher.write(util::read(json)?.as_bytes());
+                (
+                    Some(json.display().to_string()),
+                    Some(format!("{:016x}", hasher.finish())),
+                )
+            }
+            _ => (None, None),
+        };
+
+        let mut dirs = vec!["libcore", "liballoc", "tools/rustc-std-workspace-core"];
+        if unwind {
+            dirs.extend(&["libunwind", "libpanic_unwind"]);
+        }
+
+        Ok(SysrootInfo {
+            xbuild_version: env!("CARGO_PKG_VERSION"),
+            built: gc::now(),
+            hash: hash.to_string(),
+            target: TargetInfo {
+                triple: cmode.triple().to_owned(),
+                spec,
+                spec_hash,
+            },
+            rustc: RustcInfo {
+                path: ctx.toolchain.resolved_rustc().display().to_string(),
+                version: meta.short_version_string.clone(),
+                commit_hash: meta.commit_hash.clone(),
+                commit_date: meta.commit_date.clone(),
+            },
+            rust_src: SrcInfo {
+                path: src.path().display().to_string(),
+                fingerprint: src_fingerprint(src.path(), &dirs)?,
+            },
+            profile: ProfileInfo {
+                name: config.sysroot_profile.name(),
+                settings: ctx.profile().map(|p| p.to_string()),
+            },
+            memcpy: config.memcpy,
+            features: config.sysroot_features.clone(),
+            crates: vec![],
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        let mut json = serde_json::to_string_pretty(self)
+            .chain_err(|| "couldn't serialize the sysroot info")?;
+        json.push('\n');
+        Ok(json)
+    }
+}
+
+impl CrateInfo {
+    /// Describes the crate `name` that was built from `manifest` in `dir`, whose `Cargo.lock`
+    /// lists the resolved dependencies
+    pub fn new(name: &str, manifest: &str, dir: &Path) -> Result<CrateInfo> {
+        let lock = util::parse(&dir.join("Cargo.lock"))?;
+        let packages = lock
+            .lookup("package")
+            .and_then(|p| p.as_slice())
+            .unwrap_or(&[]);
+        let field = |package: &::toml::Value, key: &str| {
+            package
+                .lookup(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_owned())
+        };
+
+        Ok(CrateInfo {
+            name: name.to_owned(),
+            manifest: manifest.to_owned(),
+            dependencies: packages
+                .iter()
+                .filter(|package| field(package, "name").as_deref() != Some(name))
+                .map(|package| Dependency {
+                    name: field(package, "name").unwrap_or_default(),
+                    version: field(package, "version").unwrap_or_default(),
+                    source: field(package, "source"),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The 64-bit FNV-1a hash
+///
+/// Unlike `DefaultHasher`, its values don't change between Rust versions and platforms, so the
+/// hashes in `sysroot-info.json` can be compared across toolchains and machines. The bytes
+/// are passed to `write` directly, since the `Hash` implementations aren't stable either.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hashes the paths and contents of the files in `dirs` of the Rust source in `src`, so that
+/// a patched source can be told apart from the one of the toolchain
+fn src_fingerprint(src: &Path, dirs: &[&str]) -> Result<String> {
+    let mut hasher = Fnv::new();
+    for dir in dirs {
+        let dir = src.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(&dir).sort_by(|a, b| a.cmp(b)) {
+            let entry = entry.chain_err(|| format!("couldn't walk {}", dir.display()))?;
+            if entry.file_type().is_file() {
+                let path = entry.path();
+                // The path with `/` separators and the length keep the hash independent of the
+                // platform and each file apart from the next
+                let name = path
+                    .strip_prefix(src)
+                    .unwrap_or(path)
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let contents =
+                    fs::read(path).chain_err(|| format!("couldn't read {}", path.display()))?;
+                hasher.write(name.as_bytes());
+                hasher.write(&[0]);
+                hasher.write(&(contents.len() as u64).to_le_bytes());
+                hasher.write(&contents);
+            }
+        }
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::hash::Hasher;
+
+    use tempdir::TempDir;
+
+    use super::{src_fingerprint, Fnv};
+
+    fn fnv(bytes: &[u8]) -> u64 {
+        let mut hasher = Fnv::new();
+        hasher.write(bytes);
+        hasher.finish()
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        assert_eq!(fnv(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv(b"foobar"), 0x8594_4171_f739_67e8);
+
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::create_dir_all(td.path().join("libcore/num")).unwrap();
+        fs::write(td.path().join("libcore/lib.rs"), "#![no_std]\n").unwrap();
+        fs::write(td.path().join("libcore/num/mod.rs"), "").unwrap();
+        assert_eq!(
+            src_fingerprint(td.path(), &["libcore", "liballoc"]).unwrap(),
+            "0de404170016c875"
+        );
+    }
+}
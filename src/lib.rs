@@ -2,10 +2,7 @@ extern crate cargo_metadata;
 #[macro_use]
 extern crate error_chain;
 extern crate fs2;
-#[cfg(any(
-    all(target_os = "linux", not(target_env = "musl")),
-    target_os = "macos"
-))]
+#[cfg(unix)]
 extern crate libc;
 extern crate rustc_version;
 extern crate serde_json;
@@ -19,20 +16,28 @@ use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
-use std::{env, io, process};
+use std::time::{Duration, Instant};
+use std::{env, io, process, thread};
 
 use rustc_version::Channel;
 
 use errors::*;
-use rustc::Target;
+use rustc::{Src, Sysroot, Target};
 
+pub use status::StatusEvent;
+pub use xargo::Home;
+
+mod cache;
 mod cargo;
 mod cli;
 mod config;
-mod errors;
+pub mod errors;
 mod extensions;
 mod flock;
+mod gc;
+mod info;
 mod rustc;
+mod status;
 mod sysroot;
 mod util;
 mod xargo;
@@ -79,10 +84,7 @@ impl CompilationMode {
     }
 
     fn is_native(&self) -> bool {
-        match *self {
-            CompilationMode::Native(_) => true,
-            _ => false,
-        }
+        matches!(*self, CompilationMode::Native(_))
     }
 }
 
@@ -92,11 +94,15 @@ pub fn main_common(command_name: &str) {
     }
 
     match run(command_name) {
+        // cargo already reported why it failed
+        Err(Error(ErrorKind::CargoFailed(status), _)) => exit_like(status),
         Err(e) => {
+            status::log_error(&e);
+
             let stderr = io::stderr();
             let mut stderr = stderr.lock();
 
-            writeln!(stderr, "error: {}", e).ok();
+            writeln!(stderr, "{} {}", util::level("error"), e).ok();
 
             for e in e.iter().skip(1) {
                 writeln!(stderr, "caused by: {}", e).ok();
@@ -107,29 +113,97 @@ pub fn main_common(command_name: &str) {
                     writeln!(stderr, "{:?}", backtrace).ok();
                 }
             } else {
-                writeln!(stderr, "note: run with `RUST_BACKTRACE=1` for a backtrace").ok();
+                writeln!(
+                    stderr,
+                    "{} run with `RUST_BACKTRACE=1` for a backtrace",
+                    util::level("note")
+                )
+                .ok();
             }
 
-            process::exit(1)
+            process::exit(e.exit_code())
         }
-        Ok(Some(status)) => {
-            if !status.success() {
-                process::exit(status.code().unwrap_or(1))
-            }
+        Ok(()) => {}
+    }
+}
+
+/// Exits with the exit code of `status`, or by the same signal that terminated the child
+#[cfg(unix)]
+fn exit_like(status: ExitStatus) -> ! {
+    use std::os::unix::process::ExitStatusExt;
+
+    if let Some(signal) = status.signal() {
+        // Restore the default action, so that the signal actually terminates this process
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
         }
-        Ok(None) => {}
+        process::exit(128 + signal)
     }
+    process::exit(status.code().unwrap_or(1))
 }
 
-fn run(command_name: &str) -> Result<Option<ExitStatus>> {
+#[cfg(not(unix))]
+fn exit_like(status: ExitStatus) -> ! {
+    process::exit(status.code().unwrap_or(1))
+}
+
+/// The status of a successful process, for modes like `--env-script` that don't run cargo
+#[cfg(unix)]
+fn exit_success() -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+
+    ExitStatus::from_raw(0)
+}
+
+#[cfg(windows)]
+fn exit_success() -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+
+    ExitStatus::from_raw(0)
+}
+
+fn run(command_name: &str) -> Result<()> {
     use cli::Command;
 
     let (command, args) = cli::args(command_name)?;
+    util::set_color(args.color())?;
+    if let Some(path) = args.log_json() {
+        status::log_json(path)?;
+    }
+    let cd = CurrentDirectory::get()?;
+    let toolchain = rustc::Toolchain::pinned(&cd.manifest_dir(&args), args.verbose())?;
     match command {
-        Command::Build => Ok(Some(build(args, command_name)?)),
+        Command::Build => {
+            let status = build_args(&toolchain, args, command_name, None, None, true)?.status;
+            if !status.success() {
+                Err(ErrorKind::CargoFailed(status))?
+            }
+            Ok(())
+        }
+        Command::GcSysroots => {
+            gc_sysroots(&toolchain, &args)?;
+            Ok(())
+        }
+        Command::PrintCfg => {
+            print_cfg(&toolchain, args)?;
+            Ok(())
+        }
+        Command::PrintTargetSpec => {
+            print_target_spec(&toolchain, args)?;
+            Ok(())
+        }
+        Command::PrintSysrootInfo => {
+            print_sysroot_info(&toolchain, args)?;
+            Ok(())
+        }
+        Command::PrintVersionMeta => {
+            print_version_meta(&toolchain, &args)?;
+            Ok(())
+        }
         Command::Help => {
             print!(include_str!("help.txt"), command_name = command_name);
-            Ok(None)
+            Ok(())
         }
         Command::Version => {
             writeln!(
@@ -138,91 +212,1225 @@ fn run(command_name: &str) -> Result<Option<ExitStatus>> {
                 include_str!(concat!(env!("OUT_DIR"), "/commit-info.txt"))
             )
             .unwrap();
-            Ok(None)
+            Ok(())
         }
     }
 }
 
-fn build(args: cli::Args, command_name: &str) -> Result<ExitStatus> {
-    let verbose = args.verbose();
-    let meta = rustc::version();
+/// Options for building a sysroot programmatically through `build_sysroot`
+#[derive(Clone)]
+pub struct SysrootOptions {
+    /// The target triple or the path to a target specification file
+    pub target: String,
+    /// The directory in which the sysroot is placed, relative to the current directory
+    pub sysroot_path: PathBuf,
+    /// The flags passed to `rustc` when compiling the sysroot crates
+    pub rustflags: Vec<String>,
+    /// The contents of the `[profile.release]` section used for the sysroot crates
+    pub profile: Option<String>,
+    /// Whether the `mem` feature of `compiler_builtins` is activated
+    pub memcpy: bool,
+    /// Whether the executed commands are printed to stderr
+    pub verbose: bool,
+}
+
+impl SysrootOptions {
+    /// Creates options for `target` with the same defaults as `cargo xbuild`
+    pub fn new(target: &str) -> SysrootOptions {
+        SysrootOptions {
+            target: target.to_owned(),
+            sysroot_path: PathBuf::from("target/sysroot"),
+            rustflags: Vec::new(),
+            profile: None,
+            memcpy: true,
+            verbose: false,
+        }
+    }
+}
+
+/// Builds (or reuses) the sysroot described by `options` and returns its location
+///
+/// The resulting sysroot can be passed to `rustc` through `--sysroot`, e.g. when invoking
+/// `cargo` manually:
+///
+/// ```no_run
+/// extern crate xargo_lib;
+///
+/// use std::process::Command;
+///
+/// let mut options = xargo_lib::SysrootOptions::new("x86_64-my_os.json");
+/// options.profile = Some("panic = \"abort\"".to_owned());
+/// let home = xargo_lib::build_sysroot(&options).unwrap();
+///
+/// Command::new("cargo")
+///     .args(["build", "--target", "x86_64-my_os.json"])
+///     .env("CARGO_ENCODED_RUSTFLAGS", format!("--sysroot\x1f{}", home.display()))
+///     .status()
+///     .unwrap();
+/// ```
+pub fn build_sysroot(options: &SysrootOptions) -> Result<Home> {
+    build_sysroot_prefixed(options, None)
+}
+
+/// Builds the sysroots for several targets in parallel
+///
+/// Each target has its own sysroot lock, so the builds don't block each other. The output of
+/// each sysroot build is prefixed with its target.
+pub fn build_sysroots(options: &[SysrootOptions]) -> Result<Vec<Home>> {
+    let builds = options
+        .iter()
+        .cloned()
+        .map(|options| {
+            let scope = status::Scope::current();
+            thread::spawn(move || {
+                let prefix = format!("[{}]", options.target);
+                scope.enter(|| build_sysroot_prefixed(&options, Some(&prefix)))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    builds
+        .into_iter()
+        .map(|build| {
+            build
+                .join()
+                .unwrap_or_else(|_| Err("the sysroot build panicked".into()))
+        })
+        .collect()
+}
+
+fn build_sysroot_prefixed(options: &SysrootOptions, prefix: Option<&str>) -> Result<Home> {
+    let verbose = options.verbose;
+    let toolchain = rustc::Toolchain::default();
+    let meta = rustc::version(&toolchain, verbose)?;
     let cd = CurrentDirectory::get()?;
-    let config = cargo::config()?;
 
-    let metadata =
-        cargo_metadata::metadata(args.manifest_path()).expect("cargo metadata invocation failed");
-    let root = Path::new(&metadata.workspace_root);
-    let crate_config = config::Config::from_metadata(&metadata)
-        .map_err(|_| "parsing package.metadata.cargo-xbuild section failed")?;
+    let sysroot = rustc::sysroot(&toolchain, verbose)?;
+    let crate_config = config::Config {
+        memcpy: options.memcpy,
+        sysroot_path: options.sysroot_path.clone(),
+        sysroot_cache: false,
+        sysroot_profile: config::SysrootProfile::Release,
+        sysroot_features: Vec::new(),
+        sysroot_pre_build: None,
+        sysroot_post_build: None,
+        allow_stable: allow_stable_env(),
+    };
+    let src = rust_src(&meta, &sysroot, &crate_config, false, false, verbose)?;
+    let home = xargo::home(cd.path(), &crate_config)?;
+    rustc::persist_queries(&toolchain, home.queries_file(), &meta);
+
+    let cmode = if options.target == meta.host {
+        CompilationMode::Native(meta.host.clone())
+    } else {
+        Target::new(&toolchain, &options.target, &cd, &[], verbose)?
+            .map(CompilationMode::Cross)
+            .ok_or_else(|| format!("unknown target `{}`", options.target))?
+    };
+    let ctx = BuildContext {
+        toolchain,
+        root: cd.path().to_owned(),
+        config: None,
+        toml: cargo::toml_from_release_profile(options.profile.as_deref())?,
+        crate_config,
+        settings: BuildSettings::default(),
+    };
+    let rustflags = cargo::Rustflags::new(options.rustflags.clone());
+    let job = Job {
+        cmode: &cmode,
+        ctx: &ctx,
+        meta: &meta,
+        rustflags: &rustflags,
+        verbose,
+    };
+
+    sysroot::update(&job, &home, &src, &sysroot, prefix)?;
+
+    Ok(home)
+}
+
+/// Fails if `rustflags` contain unstable `-Z` options that the toolchain of `meta` rejects
+///
+/// This only catches the problem before the sysroot is built, as `rustc` would report it
+/// anyway.
+fn check_unstable_options(
+    rustflags: &cargo::Rustflags,
+    meta: &rustc_version::VersionMeta,
+) -> Result<()> {
+    let options = rustflags.unstable_options();
+    if options.is_empty()
+        || meta.channel == Channel::Nightly
+        || meta.channel == Channel::Dev
+        || env::var_os("RUSTC_BOOTSTRAP").is_some_and(|v| v == "1")
+    {
+        return Ok(());
+    }
+
+    Err(ErrorKind::InvalidConfig(format!(
+        "the rustflags contain the unstable options `-Z {}`, which require a nightly toolchain \
+         (found {} {}); remove them or use `cargo +nightly`",
+        options.join("`, `-Z "),
+        format!("{:?}", meta.channel).to_lowercase(),
+        meta.semver
+    )))?
+}
+
+/// Fails if `rustflags` already contain a `--sysroot`
+///
+/// `cargo-xbuild` appends the `--sysroot` of the sysroot it builds, so `rustc` would silently
+/// ignore the one of the rustflags.
+fn check_user_sysroot(rustflags: &cargo::Rustflags, triple: &str) -> Result<()> {
+    if let Some(sysroot) = rustflags.sysroot() {
+        Err(ErrorKind::InvalidConfig(format!(
+            "the rustflags contain `--sysroot {}`, which conflicts with the sysroot that \
+             cargo-xbuild builds for `{}`, as `rustc` only uses one of them; remove it, and \
+             pass the libraries of that sysroot through `-L {}/lib/rustlib/{}/lib` instead",
+            sysroot, triple, sysroot, triple
+        )))?
+    }
+    Ok(())
+}
+
+/// Linker options that can only take effect once, as their short and long spelling
+///
+/// Only `-T` is also recognized with a joined value, e.g. `-Tlinker.ld`, since e.g.
+/// `-export-dynamic` isn't `-e xport-dynamic`.
+const SINGLE_LINKER_OPTIONS: &[(&str, &str)] = &[
+    ("-T", "--script"),
+    ("-e", "--entry"),
+    ("-m", ""),
+    ("", "--oformat"),
+    ("", "--image-base"),
+];
+
+/// Returns the options of `SINGLE_LINKER_OPTIONS` among the linker `args`, along with their
+/// values, e.g. `("--script", "linker.ld")` for `-Tlinker.ld`
+///
+/// Arguments passed through the compiler, like `-Wl,-Tlinker.ld`, are recognized as well.
+fn single_linker_options(args: &[String]) -> Vec<(&'static str, String)> {
+    let mut expanded = vec![];
+    for arg in args {
+        if let Some(args) = arg.strip_prefix("-Wl,") {
+            expanded.extend(args.split(',').map(|a| a.to_owned()));
+        } else {
+            expanded.push(arg.clone());
+        }
+    }
+    expanded.retain(|a| !a.is_empty());
+
+    let mut options = vec![];
+    let mut args = expanded.into_iter();
+    while let Some(arg) = args.next() {
+        for &(short, long) in SINGLE_LINKER_OPTIONS {
+            let name = if long.is_empty() { short } else { long };
+            let long_eq = format!("{}=", long);
+            let value = if arg == short || arg == long {
+                args.next()
+            } else if !long.is_empty() && arg.starts_with(&long_eq) {
+                Some(arg[long_eq.len()..].to_owned())
+            } else if short == "-T" && arg.starts_with(short) {
+                Some(arg[short.len()..].to_owned())
+            } else {
+                continue;
+            };
+            options.extend(value.map(|v| (name, v)));
+            break;
+        }
+    }
+    options
+}
+
+/// Warns if the `-C link-arg`s of `rustflags` set a linker option, e.g. the linker script, to
+/// another value than the `pre-link-args` of the target specification of `target`
+fn check_link_args(rustflags: &cargo::Rustflags, target: &Target) -> Result<()> {
+    for conflict in link_arg_conflicts(rustflags, target)? {
+        writeln!(io::stderr(), "{} {}", util::level("warning"), conflict).ok();
+    }
+    Ok(())
+}
+
+/// The warnings of `check_link_args`
+fn link_arg_conflicts(rustflags: &cargo::Rustflags, target: &Target) -> Result<Vec<String>> {
+    let json = match *target {
+        Target::Custom { ref json, .. } => json,
+        Target::Builtin { .. } => return Ok(vec![]),
+    };
+
+    let spec_options = single_linker_options(&target.pre_link_args()?);
+    let mut conflicts = vec![];
+    for (option, value) in single_linker_options(&rustflags.link_args()) {
+        if let Some((_, spec_value)) = spec_options
+            .iter()
+            .find(|&&(o, ref v)| o == option && *v != value)
+        {
+            conflicts.push(format!(
+                "the rustflags pass `{} {}` to the linker, but the `pre-link-args` of {} pass \
+                 `{} {}`",
+                option,
+                value,
+                json.display(),
+                option,
+                spec_value
+            ));
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Whether `XBUILD_ALLOW_STABLE=1` is set
+///
+/// A `RUSTC_BOOTSTRAP=1` in the environment counts as well: it already applies to both the
+/// sysroot and the crate, so there's no point in refusing the stable toolchain.
+fn allow_stable_env() -> bool {
+    env::var_os("XBUILD_ALLOW_STABLE").is_some_and(|v| v == "1")
+        || env::var_os("RUSTC_BOOTSTRAP").is_some_and(|v| v == "1")
+}
+
+/// Fails on a stable or beta toolchain, which can't build the sysroot due to unstable
+/// features, unless this is explicitly overridden through `RUSTC_BOOTSTRAP` (`allow_stable`)
+fn check_channel(meta: &rustc_version::VersionMeta, allow_stable: bool) -> Result<()> {
+    if (meta.channel == Channel::Stable || meta.channel == Channel::Beta) && !allow_stable {
+        bail!(
+            "cargo-xbuild requires a nightly toolchain (found {} {}); run `rustup override set \
+             nightly` or use `cargo +nightly`. Pass `--allow-stable` or set \
+             `XBUILD_ALLOW_STABLE=1` to build the sysroot with `RUSTC_BOOTSTRAP=1` anyway.",
+            format!("{:?}", meta.channel).to_lowercase(),
+            meta.semver
+        );
+    }
+    Ok(())
+}
+
+/// Returns the Rust source used to build the sysroot
+///
+/// A missing `rust-src` component is installed through rustup if `auto_install` is set, or if
+/// `interactive` is set and the user agrees to it.
+fn rust_src(
+    meta: &rustc_version::VersionMeta,
+    sysroot: &Sysroot,
+    config: &config::Config,
+    auto_install: bool,
+    interactive: bool,
+    verbose: bool,
+) -> Result<Src> {
+    check_channel(meta, config.allow_stable)?;
 
-    // We can't build sysroot with stable or beta due to unstable features
-    let sysroot = rustc::sysroot(verbose)?;
-    let src = match meta.channel {
+    Ok(match meta.channel {
         Channel::Dev => rustc::Src::from_env().ok_or(
             "The XARGO_RUST_SRC env variable must be set and point to the \
              Rust source directory when working with the 'dev' channel",
         )?,
-        Channel::Nightly => {
+        Channel::Nightly | Channel::Stable | Channel::Beta => {
             if let Some(src) = rustc::Src::from_env() {
                 src
             } else {
-                sysroot.src()?
+                match sysroot.src() {
+                    Err(Error(ErrorKind::MissingRustSrc(Some(ref toolchain)), _))
+                        if auto_install
+                            || interactive
+                                && util::confirm(&format!(
+                                    "The `rust-src` component of the `{}` toolchain is \
+                                     missing. Install it with rustup?",
+                                    toolchain
+                                )) =>
+                    {
+                        rustc::install_rust_src(toolchain, verbose)?;
+                        sysroot.src()?
+                    }
+                    src => src?,
+                }
             }
         }
-        Channel::Stable | Channel::Beta => {
-            bail!(
-                "The sysroot can't be built for the {:?} channel. \
-                 Switch to nightly.",
-                meta.channel
-            );
+    })
+}
+
+fn gc_sysroots(toolchain: &rustc::Toolchain, args: &cli::Args) -> Result<()> {
+    let ctx = BuildContext::new(toolchain, args, &CurrentDirectory::get()?)?;
+    let home = xargo::home(&ctx.root, &ctx.crate_config)?;
+
+    let removed = gc::collect(&home, &ctx.crate_config, &[])?;
+    let mut stdout = io::stdout();
+    for entry in &removed {
+        writeln!(
+            stdout,
+            "{:>12} {} ({})",
+            "Removed",
+            entry.name,
+            gc::human_size(entry.size)
+        )
+        .ok();
+    }
+    writeln!(
+        stdout,
+        "{:>12} {}",
+        "Reclaimed",
+        gc::human_size(removed.iter().map(|e| e.size).sum())
+    )
+    .ok();
+
+    Ok(())
+}
+
+/// Pretty-prints what the sysroot of the target was built from, see `sysroot-info.json`
+fn print_sysroot_info(toolchain: &rustc::Toolchain, mut args: cli::Args) -> Result<()> {
+    let verbose = args.verbose();
+    let meta = rustc::version(toolchain, verbose)?;
+    let cd = CurrentDirectory::get()?;
+
+    if args.target() == Some("host") {
+        args.replace_target(&meta.host);
+    }
+
+    let ctx = BuildContext::new(toolchain, &args, &cd)?;
+    let home = xargo::home(&ctx.root, &ctx.crate_config)?;
+
+    let triple = match compilation_mode(&args, &ctx, &meta, &cd, verbose)? {
+        Some(cmode) => cmode.triple().to_owned(),
+        None => args.target().unwrap_or(&meta.host).to_owned(),
+    };
+    let info = match home.sysroot_info(&triple)? {
+        Some(info) => info,
+        None => bail!(
+            "no sysroot with a `sysroot-info.json` has been built for `{}` in {}",
+            triple,
+            home.as_path().display()
+        ),
+    };
+    let info: serde_json::Value = serde_json::from_str(&info)
+        .chain_err(|| format!("couldn't parse the sysroot info of `{}`", triple))?;
+    let info = serde_json::to_string_pretty(&info)
+        .chain_err(|| format!("couldn't print the sysroot info of `{}`", triple))?;
+    writeln!(io::stdout(), "{}", info).ok();
+
+    Ok(())
+}
+
+/// Options for building a crate programmatically through `build`
+#[derive(Clone)]
+pub struct BuildOptions {
+    /// The cargo subcommand, e.g. `build`, `check`, or `test`
+    pub command: String,
+    /// The path to the `Cargo.toml` of the crate or workspace
+    pub manifest_path: Option<PathBuf>,
+    /// The target triple or the path to a target specification file
+    pub target: Option<String>,
+    /// Additional arguments passed to cargo, e.g. `--release`
+    pub args: Vec<String>,
+    /// Whether the executed commands are printed to stderr
+    pub verbose: bool,
+    /// Overrides the directory in which the sysroot is placed
+    pub sysroot_path: Option<PathBuf>,
+}
+
+impl BuildOptions {
+    /// Creates options for `cargo xbuild` in the current directory
+    pub fn new() -> BuildOptions {
+        BuildOptions {
+            command: "build".to_owned(),
+            manifest_path: None,
+            target: None,
+            args: Vec::new(),
+            verbose: false,
+            sysroot_path: None,
+        }
+    }
+}
+
+impl Default for BuildOptions {
+    fn default() -> BuildOptions {
+        BuildOptions::new()
+    }
+}
+
+/// The result of `build`
+#[derive(Debug)]
+pub struct BuildOutput {
+    /// The sysroot the crate was compiled against, or `None` if the sysroot of the toolchain
+    /// was used
+    pub sysroot: Option<PathBuf>,
+    /// The exit status of cargo
+    pub status: ExitStatus,
+    /// The files produced by cargo, as reported in its `compiler-artifact` messages
+    pub artifacts: Vec<PathBuf>,
+}
+
+/// Builds the crate described by `options` like `cargo x<command>`
+///
+/// Unlike the `cargo-xbuild` binary, this never exits the process and doesn't write to stdout.
+/// Any output of cargo that isn't one of its JSON messages is written to stderr. The artifacts
+/// are read from these messages, so a `--message-format` in `args` that isn't a JSON format is
+/// replaced with the JSON format that renders the same diagnostics.
+///
+/// ```no_run
+/// extern crate xargo_lib;
+///
+/// let mut options = xargo_lib::BuildOptions::new();
+/// options.target = Some("x86_64-my_os.json".to_owned());
+/// options.args.push("--release".to_owned());
+/// let output = xargo_lib::build(&options).unwrap();
+///
+/// assert!(output.status.success());
+/// for artifact in &output.artifacts {
+///     println!("{}", artifact.display());
+/// }
+/// ```
+pub fn build(options: &BuildOptions) -> Result<BuildOutput> {
+    let mut all = vec![];
+    if let Some(ref manifest_path) = options.manifest_path {
+        all.push("--manifest-path".to_owned());
+        all.push(manifest_path.display().to_string());
+    }
+    if let Some(ref target) = options.target {
+        all.push("--target".to_owned());
+        all.push(target.clone());
+    }
+    if options.verbose {
+        all.push("--verbose".to_owned());
+    }
+    all.extend(options.args.iter().cloned());
+
+    let mut args = cli::parse(all);
+    util::set_color(args.color())?;
+    args.request_json_messages();
+    let mut artifacts = vec![];
+    build_args(
+        &rustc::Toolchain::default(),
+        args,
+        &options.command,
+        options.sysroot_path.as_deref(),
+        Some(&mut artifacts),
+        false,
+    )
+    .map(|output| BuildOutput {
+        artifacts,
+        ..output
+    })
+}
+
+/// Like `build`, but passes the progress of the build to `handler` instead of writing it to
+/// stderr
+///
+/// `handler` is called e.g. when the sysroot build starts and finishes or when the build waits
+/// for the lock of a sysroot that is used by another process. It may borrow from the caller,
+/// and it is called on the threads of the build as well, one event at a time.
+pub fn build_with_status<F>(options: &BuildOptions, mut handler: F) -> Result<BuildOutput>
+where
+    F: FnMut(&StatusEvent) + Send,
+{
+    status::with_handler(&mut handler, || build(options))
+}
+
+fn build_args(
+    toolchain: &rustc::Toolchain,
+    mut args: cli::Args,
+    command_name: &str,
+    sysroot_path: Option<&Path>,
+    artifacts: Option<&mut Vec<PathBuf>>,
+    interactive: bool,
+) -> Result<BuildOutput> {
+    let verbose = args.verbose();
+    let meta = rustc::version(toolchain, verbose)?;
+    let cd = CurrentDirectory::get()?;
+    let env_script = args.env_script().map(util::Shell::from_name).transpose()?;
+
+    // `--target host` explicitly requests a sysroot built from source for the host
+    let explicit_host = args.target() == Some("host");
+    if explicit_host {
+        args.replace_target(&meta.host);
+    }
+    absolute_target_spec(&mut args, &cd)?;
+
+    let mut ctx = BuildContext::new(toolchain, &args, &cd)?;
+    if let Some(sysroot_path) = sysroot_path {
+        ctx.crate_config.sysroot_path = sysroot_path.to_owned();
+    }
+    ctx.crate_config.allow_stable = args.allow_stable() || allow_stable_env();
+    ctx.settings.locked_sysroot = args.locked_sysroot();
+    ctx.settings.sysroot_only = args.sysroot_only().to_vec();
+    let ctx = ctx;
+    let (root, config, crate_config) = (&*ctx.root, &ctx.config, &ctx.crate_config);
+    if verbose {
+        writeln!(io::stderr(), "+ {:?}", crate_config).ok();
+    }
+    let home = xargo::home(root, crate_config)?;
+
+    let cmode = compilation_mode(&args, &ctx, &meta, &cd, verbose)?;
+
+    // A target specification that was found by name is passed to cargo by its path, as cargo
+    // and `rustc` don't know about `target_path`
+    if let Some(CompilationMode::Cross(Target::Custom {
+        ref orig_triple, ..
+    })) = cmode
+    {
+        match args.target() {
+            Some(target) if target == orig_triple => {}
+            Some(_) => args.replace_target(orig_triple),
+            None if Path::new(orig_triple).is_absolute() => {
+                args.insert(&format!("--target={}", orig_triple))
+            }
+            None => {}
+        }
+    }
+
+    let spec = match cmode {
+        Some(CompilationMode::Cross(Target::Custom { ref json, .. })) => Some(json.clone()),
+        _ => None,
+    };
+    status::emit(StatusEvent::TargetResolved {
+        triple: cmode
+            .as_ref()
+            .map_or(&*meta.host, |c| c.triple())
+            .to_owned(),
+        spec,
+    });
+
+    if let Some(path) = args.emit_build_std_config() {
+        let cmode = cmode.unwrap_or_else(|| CompilationMode::Native(meta.host.clone()));
+        emit_build_std_config(path, &cmode, &ctx)?;
+        return Ok(BuildOutput {
+            sysroot: None,
+            status: exit_success(),
+            artifacts: vec![],
+        });
+    }
+
+    // Let cargo build the sysroot crates instead
+    if args.use_build_std() {
+        if env_script.is_some() {
+            Err(ErrorKind::InvalidConfig(
+                "`--env-script` can't be combined with `--use-build-std`, which doesn't build a \
+                 sysroot"
+                    .to_owned(),
+            ))?
+        }
+        let cmode = cmode.unwrap_or_else(|| CompilationMode::Native(meta.host.clone()));
+        // `-Zbuild-std` requires an explicit `--target`, even for the host
+        if args.target().is_none() {
+            args.insert(&format!("--target={}", cmode.orig_triple()));
+        }
+        for arg in sysroot::build_std_args(&cmode, &ctx, &meta)? {
+            args.insert(&arg);
+        }
+
+        return Ok(BuildOutput {
+            sysroot: None,
+            status: cargo::run(
+                toolchain,
+                &args,
+                command_name,
+                config.as_ref(),
+                artifacts,
+                verbose,
+            )?,
+            artifacts: vec![],
+        });
+    }
+
+    // The prebuilt sysroot of the toolchain works for the host, so there's no need to build
+    // one unless explicitly requested
+    let cmode = match cmode {
+        Some(ref cmode)
+            if cmode.is_native()
+                && !explicit_host
+                && env::var_os("XBUILD_FORCE_SYSROOT").is_none() =>
+        {
+            None
+        }
+        cmode => cmode,
+    };
+
+    if let Some(cmode) = cmode {
+        // Only persisted along with a sysroot, as builds for the host don't create one
+        rustc::persist_queries(toolchain, home.queries_file(), &meta);
+        let cfgs = ctx.cfgs(cmode.orig_triple(), verbose)?;
+        if let Some(ref config) = config {
+            for (expr, key) in config.ambiguous_target_keys(cmode.triple(), &cfgs) {
+                writeln!(
+                    io::stderr(),
+                    "{} .cargo/config: `{}` is set in both `target.{}` and the matching \
+                     `target.'{}'`; only `target.{}.{}` is used",
+                    util::level("warning"),
+                    key,
+                    cmode.triple(),
+                    expr,
+                    cmode.triple(),
+                    key
+                )
+                .ok();
+            }
+        }
+
+        let sysroot = rustc::sysroot(toolchain, verbose)?;
+        let src = rust_src(
+            &meta,
+            &sysroot,
+            crate_config,
+            args.auto_install_rust_src(),
+            interactive,
+            verbose,
+        )?;
+        let rustflags = ctx.rustflags(cmode.triple(), &cfgs, args.quiet())?;
+        let rustdocflags =
+            cargo::rustdocflags(config.as_ref(), cmode.triple(), &cfgs, args.quiet())?;
+        check_unstable_options(&rustflags, &meta)?;
+        check_user_sysroot(&rustflags, cmode.triple())?;
+        if let CompilationMode::Cross(ref target) = cmode {
+            if !args.quiet() {
+                check_link_args(&rustflags, target)?;
+            }
         }
+
+        let job = Job {
+            cmode: &cmode,
+            ctx: &ctx,
+            meta: &meta,
+            rustflags: &rustflags,
+            verbose,
+        };
+        let start = Instant::now();
+        let built = sysroot::update(&job, &home, &src, &sysroot, None)?;
+        let checked = start.elapsed();
+        for entry in gc::collect(&home, crate_config, &[cmode.triple(), &meta.host])? {
+            if verbose {
+                writeln!(io::stderr(), "{} {}", util::status("Removed"), entry.name).ok();
+            }
+        }
+        if let Some(shell) = env_script {
+            let sysroot = cd.path().join(home.as_path());
+            print_env_script(shell, &rustflags, Some(&sysroot), cmode.triple());
+            return Ok(BuildOutput {
+                sysroot: Some(home.as_path().to_owned()),
+                status: exit_success(),
+                artifacts: vec![],
+            });
+        }
+        let start = Instant::now();
+        let status = xargo::run(&args, &job, rustdocflags, &home, command_name, artifacts)?;
+        if args.timings() {
+            let elapsed = start.elapsed();
+            print_timings(cmode.triple(), built, checked, command_name, elapsed);
+        }
+        return Ok(BuildOutput {
+            sysroot: Some(home.as_path().to_owned()),
+            status,
+            artifacts: vec![],
+        });
+    }
+
+    // The host uses the sysroot of the toolchain, so only the rustflags are needed
+    if let Some(shell) = env_script {
+        let cfgs = ctx.cfgs(&meta.host, verbose)?;
+        let rustflags = ctx.rustflags(&meta.host, &cfgs, args.quiet())?;
+        print_env_script(shell, &rustflags, None, &meta.host);
+        return Ok(BuildOutput {
+            sysroot: None,
+            status: exit_success(),
+            artifacts: vec![],
+        });
+    }
+
+    Ok(BuildOutput {
+        sysroot: None,
+        status: cargo::run(
+            toolchain,
+            &args,
+            command_name,
+            config.as_ref(),
+            artifacts,
+            verbose,
+        )?,
+        artifacts: vec![],
+    })
+}
+
+/// Prints the `.cargo/config.toml` settings that make `cargo -Zbuild-std` build the same
+/// sysroot crates for the same target as cargo-xbuild, or writes them to `path`, see
+/// `--emit-build-std-config`
+///
+/// Settings that `-Zbuild-std` can't reproduce are an error.
+fn emit_build_std_config(
+    path: Option<&Path>,
+    cmode: &CompilationMode,
+    ctx: &BuildContext,
+) -> Result<()> {
+    let (root, config) = (&*ctx.root, &ctx.crate_config);
+    let mut unsupported = vec![];
+    if env::var_os("XARGO_RUST_SRC").is_some() {
+        unsupported.push(
+            "`XARGO_RUST_SRC` sets a custom Rust source, while `-Zbuild-std` always uses the \
+             `rust-src` component",
+        );
+    }
+    if config.sysroot_pre_build.is_some() {
+        unsupported.push("`sysroot_pre_build` runs a command on the Rust source, e.g. to patch it");
+    }
+    if config.sysroot_post_build.is_some() {
+        unsupported.push("`sysroot_post_build` runs a command on the finished sysroot");
+    }
+    if !unsupported.is_empty() {
+        Err(ErrorKind::InvalidConfig(format!(
+            "these settings have no `-Zbuild-std` equivalent:\n  - {}",
+            unsupported.join("\n  - ")
+        )))?
+    }
+
+    let (crates, features) = sysroot::build_std_crates(cmode, ctx)?;
+    let strings = |values: Vec<String>| {
+        toml::Value::Array(values.into_iter().map(toml::Value::String).collect())
+    };
+    let mut unstable = toml::Table::new();
+    let crates = crates.iter().map(|c| c.to_string()).collect();
+    unstable.insert("build-std".to_owned(), strings(crates));
+    if !features.is_empty() {
+        unstable.insert("build-std-features".to_owned(), strings(features));
+    }
+    // `build.target` is relative to the directory that contains `.cargo`
+    let target = match *cmode {
+        CompilationMode::Cross(Target::Custom { ref json, .. }) => json
+            .strip_prefix(root)
+            .unwrap_or(json)
+            .display()
+            .to_string(),
+        _ => cmode.orig_triple().to_owned(),
+    };
+    let mut build = toml::Table::new();
+    build.insert("target".to_owned(), toml::Value::String(target));
+    let mut table = toml::Table::new();
+    table.insert("unstable".to_owned(), toml::Value::Table(unstable));
+    table.insert("build".to_owned(), toml::Value::Table(build));
+
+    let mut contents = String::new();
+    if config.sysroot_profile != config::SysrootProfile::Release {
+        contents.push_str(
+            "# `-Zbuild-std` builds the sysroot crates with the profile of the crate, not with \
+             `sysroot_profile`\n",
+        );
+    }
+    contents.push_str(&toml::Value::Table(table).to_string());
+
+    match path {
+        Some(path) => {
+            util::write(path, &contents)?;
+            writeln!(io::stderr(), "{} {}", util::status("Wrote"), path.display()).ok();
+        }
+        None => {
+            write!(io::stdout(), "{}", contents).ok();
+        }
+    }
+    Ok(())
+}
+
+/// Prints the commands of `--env-script` for `shell`, which set `RUSTFLAGS` to `rustflags` and
+/// the `--sysroot` and set `XBUILD_SYSROOT` and `XBUILD_TARGET` like for build scripts
+///
+/// Only these commands are printed to stdout, so that the output can be evaluated.
+fn print_env_script(
+    shell: util::Shell,
+    rustflags: &cargo::Rustflags,
+    sysroot: Option<&Path>,
+    triple: &str,
+) {
+    let mut flags = rustflags.as_slice().to_vec();
+    if let Some(sysroot) = sysroot {
+        flags.push("--sysroot".to_owned());
+        flags.push(sysroot.display().to_string());
+    }
+    // `RUSTFLAGS` is split on whitespace, also by `$RUSTFLAGS` in a shell
+    if flags.iter().any(|f| f.contains(char::is_whitespace)) {
+        writeln!(
+            io::stderr(),
+            "{} the rustflags or the sysroot path contain whitespace, so `RUSTFLAGS` won't \
+             split them correctly",
+            util::level("warning")
+        )
+        .ok();
+    }
+
+    let mut stdout = io::stdout();
+    writeln!(stdout, "{}", shell.export("RUSTFLAGS", &flags.join(" "))).ok();
+    if let Some(sysroot) = sysroot {
+        let sysroot = sysroot.display().to_string();
+        writeln!(stdout, "{}", shell.export("XBUILD_SYSROOT", &sysroot)).ok();
+    }
+    writeln!(stdout, "{}", shell.export("XBUILD_TARGET", triple)).ok();
+}
+
+/// Prints the summary of `--xbuild-timings`, see `timings`
+fn print_timings(
+    triple: &str,
+    built: Option<Duration>,
+    checked: Duration,
+    command_name: &str,
+    cargo: Duration,
+) {
+    let timings = timings(triple, built, checked, command_name, cargo);
+    writeln!(io::stderr(), "{} {}", util::status("Timings"), timings).ok();
+}
+
+/// The summary of `--xbuild-timings`: the duration of the sysroot build, if any, the total
+/// time spent on the sysroot, including its check, and the duration of cargo
+fn timings(
+    triple: &str,
+    built: Option<Duration>,
+    checked: Duration,
+    command_name: &str,
+    cargo: Duration,
+) -> String {
+    let secs = |d: Duration| d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+    let sysroot = match built {
+        Some(built) => format!("built in {:.2}s", secs(built)),
+        None => "up to date".to_owned(),
+    };
+    format!(
+        "sysroot for {}: {} ({:.2}s in total), cargo {}: {:.2}s",
+        triple,
+        sysroot,
+        secs(checked),
+        command_name,
+        secs(cargo)
+    )
+}
+
+/// Replaces a `--target` that is a path to a target specification file with the canonical path
+///
+/// The path is relative to the current directory, but cargo runs in the directory of
+/// `--manifest-path`, so both must use the absolute path. Like for `build.target`, a missing
+/// file is reported right away.
+fn absolute_target_spec(args: &mut cli::Args, cd: &CurrentDirectory) -> Result<()> {
+    let path = match args.target() {
+        Some(target) if target.ends_with(".json") => cd.path().join(target),
+        _ => return Ok(()),
     };
+    let canonicalized = path
+        .canonicalize()
+        .chain_err(|| ErrorKind::TargetSpecNotFound(path.clone()))?;
+    let target = util::simplify_path(canonicalized)
+        .into_os_string()
+        .into_string()
+        .map_err(|err| format!("target path not valid utf8: {:?}", err))?;
+    args.replace_target(&target);
+    Ok(())
+}
+
+/// The directories of the `target_path` configuration key, relative to the workspace `root`
+fn target_path(root: &Path, settings: &BuildSettings) -> Vec<PathBuf> {
+    settings
+        .target_path
+        .iter()
+        .map(|dir| root.join(dir))
+        .collect()
+}
 
-    let cmode = if let Some(triple) = args.target() {
+/// Resolves the target of `args`, or of `build.target` in `.cargo/config`
+///
+/// Returns `None` for a triple that is neither a builtin target nor a target specification
+/// file, which is left to cargo to report.
+fn compilation_mode(
+    args: &cli::Args,
+    ctx: &BuildContext,
+    meta: &rustc_version::VersionMeta,
+    cd: &CurrentDirectory,
+    verbose: bool,
+) -> Result<Option<CompilationMode>> {
+    let toolchain = &ctx.toolchain;
+    let target_path = target_path(&ctx.root, &ctx.settings);
+    Ok(if let Some(triple) = args.target() {
         if triple == meta.host {
             Some(CompilationMode::Native(meta.host.clone()))
         } else {
-            Target::new(triple, &cd, verbose)?.map(CompilationMode::Cross)
+            let target = Target::new(toolchain, triple, cd, &target_path, verbose)?;
+            if target.is_none() && triple.ends_with(".json") {
+                Err(ErrorKind::TargetSpecNotFound(PathBuf::from(triple)))?;
+            }
+            target.map(CompilationMode::Cross)
         }
     } else {
-        if let Some(ref config) = config {
+        if let Some(ref config) = ctx.config {
             if let Some(triple) = config.target()? {
-                Target::new(&triple, &cd, verbose)?.map(CompilationMode::Cross)
+                if triple == meta.host {
+                    Some(CompilationMode::Native(meta.host.clone()))
+                } else {
+                    let target = Target::new(toolchain, &triple, cd, &target_path, verbose)?;
+                    if target.is_none()
+                        && env::var_os("XBUILD_SKIP_TARGET_VALIDATION").is_none()
+                    {
+                        let mut msg = format!(
+                            "{}: unknown build.target `{}`",
+                            config.source(&["build", "target"]),
+                            triple
+                        );
+                        if let Some(suggestion) =
+                            rustc::suggest_target(toolchain, &triple, verbose)?
+                        {
+                            msg.push_str(&format!("; did you mean `{}`?", suggestion));
+                        }
+                        Err(ErrorKind::InvalidConfig(msg))?;
+                    }
+                    target.map(CompilationMode::Cross)
+                }
             } else {
                 Some(CompilationMode::Native(meta.host.clone()))
             }
         } else {
             Some(CompilationMode::Native(meta.host.clone()))
         }
+    })
+}
+
+/// Resolves the target of `--print`: the path of a target specification file, or else the
+/// target triple
+fn print_target(
+    args: &mut cli::Args,
+    ctx: &BuildContext,
+    meta: &rustc_version::VersionMeta,
+    cd: &CurrentDirectory,
+) -> Result<String> {
+    if args.target() == Some("host") {
+        args.replace_target(&meta.host);
+    }
+    absolute_target_spec(args, cd)?;
+
+    Ok(
+        match compilation_mode(args, ctx, meta, cd, args.verbose())? {
+            Some(CompilationMode::Cross(Target::Custom { ref json, .. })) => {
+                json.display().to_string()
+            }
+            Some(cmode) => cmode.orig_triple().to_owned(),
+            None => args.target().unwrap_or(&meta.host).to_owned(),
+        },
+    )
+}
+
+/// Prints the target specification that is used for the target, i.e. the contents of the
+/// target specification file or the one of the builtin target
+fn print_target_spec(toolchain: &rustc::Toolchain, mut args: cli::Args) -> Result<()> {
+    let verbose = args.verbose();
+    let meta = rustc::version(toolchain, verbose)?;
+    let cd = CurrentDirectory::get()?;
+    let ctx = BuildContext::new(toolchain, &args, &cd)?;
+
+    let target = print_target(&mut args, &ctx, &meta, &cd)?;
+    let spec = if target.ends_with(".json") {
+        util::read(Path::new(&target))?
+    } else {
+        let bootstrap = args.allow_stable() || allow_stable_env();
+        rustc::target_spec_json(toolchain, &target, bootstrap, verbose)?
     };
 
-    if let Some(cmode) = cmode {
-        let home = xargo::home(root, &crate_config)?;
-        let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
-
-        sysroot::update(
-            &cmode,
-            &home,
-            &root,
-            &crate_config,
-            &rustflags,
-            &meta,
-            &src,
-            &sysroot,
-            verbose,
-        )?;
-        return xargo::run(
-            &args,
-            &cmode,
-            rustflags,
-            &home,
-            &meta,
-            command_name,
-            verbose,
-        );
+    let mut stdout = io::stdout();
+    write!(stdout, "{}", spec).ok();
+    if !spec.ends_with('\n') {
+        writeln!(stdout).ok();
+    }
+
+    Ok(())
+}
+
+/// The `rustc` version, as printed by `--print version-meta`
+#[derive(Serialize)]
+struct VersionInfo<'a> {
+    semver: String,
+    commit_hash: Option<&'a str>,
+    commit_date: Option<&'a str>,
+    channel: String,
+    host: &'a str,
+}
+
+/// Prints the version of `rustc` that cargo-xbuild detected, which is part of the sysroot
+/// hash, as `key: value` lines or as JSON with `--message-format=json`
+fn print_version_meta(toolchain: &rustc::Toolchain, args: &cli::Args) -> Result<()> {
+    let meta = rustc::version(toolchain, args.verbose())?;
+    write!(
+        io::stdout(),
+        "{}",
+        version_meta(&meta, args.message_format_json())?
+    )
+    .ok();
+    Ok(())
+}
+
+/// The output of `--print version-meta` for `meta`
+fn version_meta(meta: &rustc_version::VersionMeta, json: bool) -> Result<String> {
+    let info = VersionInfo {
+        semver: meta.semver.to_string(),
+        commit_hash: meta.commit_hash.as_deref(),
+        commit_date: meta.commit_date.as_deref(),
+        channel: format!("{:?}", meta.channel).to_lowercase(),
+        host: &meta.host,
+    };
+
+    if json {
+        let json = serde_json::to_string(&info).chain_err(|| "couldn't serialize the version")?;
+        return Ok(format!("{}\n", json));
+    }
+    let unknown = |value: Option<&str>| value.unwrap_or("unknown").to_owned();
+    Ok(format!(
+        "semver: {}\ncommit-hash: {}\ncommit-date: {}\nchannel: {}\nhost: {}\n",
+        info.semver,
+        unknown(info.commit_hash),
+        unknown(info.commit_date),
+        info.channel,
+        info.host
+    ))
+}
+
+/// Prints the cfgs of the target with the rustflags that are used for the sysroot and the
+/// crate, like `rustc --print cfg`
+fn print_cfg(toolchain: &rustc::Toolchain, mut args: cli::Args) -> Result<()> {
+    let verbose = args.verbose();
+    let meta = rustc::version(toolchain, verbose)?;
+    let cd = CurrentDirectory::get()?;
+    let ctx = BuildContext::new(toolchain, &args, &cd)?;
+
+    let target = print_target(&mut args, &ctx, &meta, &cd)?;
+    let triple = Path::new(&target)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(&target)
+        .to_owned();
+
+    let cfgs = ctx.cfgs(&target, verbose)?;
+    let rustflags = ctx.rustflags(&triple, &cfgs, args.quiet())?;
+
+    let mut stdout = io::stdout();
+    for cfg in rustc::cfg(toolchain, &target, rustflags.as_slice(), verbose)? {
+        writeln!(stdout, "{}", cfg).ok();
+    }
+
+    Ok(())
+}
+
+/// The configuration of a build: `.cargo/config`, the `[profile]` sections of `Cargo.toml`
+/// and the `cargo-xbuild` metadata tables
+///
+/// The files are only read and parsed once, here, and then passed around by reference. The
+/// rustflags and the sysroot profile are derived from it.
+pub struct BuildContext {
+    /// The `cargo` and `rustc` that are run
+    toolchain: rustc::Toolchain,
+    /// The workspace root, as reported by `cargo metadata`
+    root: PathBuf,
+    config: Option<cargo::Config>,
+    toml: cargo::Toml,
+    crate_config: config::Config,
+    settings: BuildSettings,
+}
+
+/// What one build compiles the sysroot and the crate for and with, shared by its steps
+pub struct Job<'a> {
+    cmode: &'a CompilationMode,
+    ctx: &'a BuildContext,
+    meta: &'a rustc_version::VersionMeta,
+    rustflags: &'a cargo::Rustflags,
+    verbose: bool,
+}
+
+/// The settings of a build that, unlike `config::Config`, don't affect the compiled sysroot
+#[derive(Debug, PartialEq)]
+struct BuildSettings {
+    /// Whether an outdated sysroot is an error instead of being rebuilt, see
+    /// `--locked-sysroot`
+    locked_sysroot: bool,
+    /// The sysroot crates that are rebuilt, while the others are kept, see `--sysroot-only`;
+    /// empty for all of them
+    sysroot_only: Vec<String>,
+    /// Directories in which target specifications are looked up by name, like in
+    /// `RUST_TARGET_PATH`, relative to the workspace root
+    target_path: Vec<PathBuf>,
+    /// Whether the build compiles crates for the host, i.e. build scripts or proc macros,
+    /// which need the host part of the sysroot
+    host_crates: bool,
+    /// Whether the crate is built with `--cfg xbuild` and the `XBUILD_SYSROOT_HASH` variable,
+    /// see `xargo::run`
+    expose_build_info: bool,
+}
+
+impl Default for BuildSettings {
+    fn default() -> BuildSettings {
+        BuildSettings {
+            locked_sysroot: false,
+            sysroot_only: vec![],
+            target_path: vec![],
+            host_crates: true,
+            expose_build_info: false,
+        }
+    }
+}
+
+impl BuildContext {
+    fn new(
+        toolchain: &rustc::Toolchain,
+        args: &cli::Args,
+        cd: &CurrentDirectory,
+    ) -> Result<BuildContext> {
+        let metadata =
+            cargo::metadata(toolchain, args, false).chain_err(|| "`cargo metadata` failed")?;
+        config::check_packages(toolchain, &metadata, args)?;
+        let root = PathBuf::from(&metadata.workspace_root);
+        let config = cargo::config(&cd.manifest_dir(args), Some(&root), args.config())?;
+
+        // `cargo metadata` already resolved the workspace root (including `package.workspace`
+        // pointers and globs in `workspace.members`), so the `[profile]` sections are found
+        // regardless of the current directory
+        let toml = cargo::toml(&root)?;
+        status::emit(StatusEvent::ConfigRead {
+            manifests: vec![root.join("Cargo.toml")],
+            cargo_config: config.as_ref().map(|c| c.sources()).unwrap_or_default(),
+        });
+
+        if !args.quiet() {
+            let selected =
+                config::selected_packages(&metadata, &toml, args.packages(), args.manifest_path())?;
+            for package in selected {
+                let manifest = Path::new(&package.manifest_path);
+                if manifest != root.join("Cargo.toml") && cargo::sets_profile(manifest)? {
+                    writeln!(
+                        io::stderr(),
+                        "{} the [profile] sections of {} are ignored for the sysroot, like \
+                         cargo ignores them; set them in {} instead",
+                        util::level("warning"),
+                        manifest.display(),
+                        root.join("Cargo.toml").display()
+                    )
+                    .ok();
+                }
+            }
+        }
+
+        let (crate_config, mut settings) =
+            config::Config::from_metadata(&metadata, &toml, args.packages(), args.manifest_path())?;
+        settings.host_crates = config::host_crates(toolchain, &metadata, args);
+
+        Ok(BuildContext {
+            toolchain: toolchain.clone(),
+            root,
+            config,
+            toml,
+            crate_config,
+            settings,
+        })
+    }
+
+    /// The cfgs of `target` without any rustflags
+    ///
+    /// These are only needed for the `target.'cfg(..)'` sections of `.cargo/config`, so
+    /// `rustc` isn't run without one.
+    fn cfgs(&self, target: &str, verbose: bool) -> Result<Vec<String>> {
+        match self.config {
+            Some(_) => rustc::cfg(&self.toolchain, target, &[], verbose),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// The rustflags for `triple`, from the environment or `.cargo/config`
+    fn rustflags(&self, triple: &str, cfgs: &[String], quiet: bool) -> Result<cargo::Rustflags> {
+        cargo::rustflags(self.config.as_ref(), triple, cfgs, quiet)
     }
 
-    cargo::run(&args, verbose)
+    /// The `[profile]` section of the workspace root that applies to the sysroot
+    fn profile<'a>(&'a self) -> Option<cargo::Profile<'a>> {
+        self.toml.profile(self.crate_config.sysroot_profile)
+    }
 }
 
 pub struct CurrentDirectory {
@@ -239,4 +1447,206 @@ impl CurrentDirectory {
     fn path(&self) -> &Path {
         &self.path
     }
+
+    /// The directory of `--manifest-path`, or the current directory
+    ///
+    /// This is where `.cargo/config` is searched, so that it's found when building a crate in
+    /// another directory.
+    fn manifest_dir(&self, args: &cli::Args) -> PathBuf {
+        match args.manifest_path().and_then(Path::parent) {
+            Some(dir) => self.path.join(dir),
+            None => self.path.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use rustc_version;
+    use serde_json;
+    use tempdir::TempDir;
+
+    use errors::{Error, ErrorKind};
+    use rustc::Toolchain;
+    use {cargo, cli};
+
+    use super::{
+        build_sysroots, check_channel, check_unstable_options, link_arg_conflicts, timings,
+        version_meta, CurrentDirectory, Target,
+    };
+
+    #[test]
+    fn build_sysroots_without_targets() {
+        assert!(build_sysroots(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compile_error_keeps_exit_code_of_cargo() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::write(
+            td.path().join("Cargo.toml"),
+            "[package]\nname = \"broken\"\nversion = \"0.0.0\"\n[workspace]\n",
+        )
+        .unwrap();
+        fs::create_dir(td.path().join("src")).unwrap();
+        fs::write(td.path().join("src/lib.rs"), "fn f() -> u8 { \"no\" }\n").unwrap();
+
+        let args = cli::parse(vec![
+            "--manifest-path".to_owned(),
+            td.path().join("Cargo.toml").display().to_string(),
+            "--quiet".to_owned(),
+            "--offline".to_owned(),
+        ]);
+        let status = cargo::run(&Toolchain::default(), &args, "check", None, None, false).unwrap();
+        assert_eq!(status.code(), Some(101));
+        assert_eq!(Error::from(ErrorKind::CargoFailed(status)).exit_code(), 101);
+    }
+
+    #[test]
+    fn manifest_path_locates_config_outside_current_directory() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let krate = td.path().join("crate");
+        fs::create_dir_all(krate.join(".cargo")).unwrap();
+        fs::write(
+            krate.join(".cargo/config"),
+            "[build]\ntarget = \"thumbv7m-none-eabi\"\n",
+        )
+        .unwrap();
+        fs::write(krate.join("Cargo.toml"), "").unwrap();
+
+        let cd = CurrentDirectory::get().unwrap();
+        for path in &[krate.join("Cargo.toml"), krate.clone()] {
+            let args = cli::parse(vec![
+                "--manifest-path".to_owned(),
+                path.display().to_string(),
+            ]);
+            // Passed on to cargo, which only accepts the path to the manifest
+            let manifest = krate.join("Cargo.toml").display().to_string();
+            assert_eq!(args.all(), ["--manifest-path", &*manifest]);
+
+            let dir = cd.manifest_dir(&args);
+            assert_eq!(dir, krate);
+            let config = cargo::config(&dir, None, args.config()).unwrap().unwrap();
+            assert_eq!(
+                config.target().unwrap().as_deref(),
+                Some("thumbv7m-none-eabi")
+            );
+        }
+    }
+
+    fn meta(release: &str) -> rustc_version::VersionMeta {
+        rustc_version::version_meta_for(&format!(
+            "rustc {0}\nbinary: rustc\ncommit-hash: unknown\ncommit-date: unknown\n\
+             host: x86_64-unknown-linux-gnu\nrelease: {0}\n",
+            release
+        ))
+    }
+
+    #[test]
+    fn nightly_needs_no_bootstrap() {
+        assert!(check_channel(&meta("1.80.0-nightly"), false).is_ok());
+    }
+
+    #[test]
+    fn stable_requires_opt_in() {
+        let err = check_channel(&meta("1.78.0"), false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("requires a nightly toolchain (found stable 1.78.0)"));
+        assert!(check_channel(&meta("1.79.0-beta.2"), false).is_err());
+
+        assert!(check_channel(&meta("1.78.0"), true).is_ok());
+    }
+
+    #[test]
+    fn unstable_options_require_nightly() {
+        let rustflags = cargo::Rustflags::new(vec![
+            "-Z".to_owned(),
+            "unstable-options".to_owned(),
+            "-Cpanic=abort".to_owned(),
+        ]);
+        let err = check_unstable_options(&rustflags, &meta("1.78.0")).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`-Z unstable-options`, which require a nightly toolchain (found stable"));
+
+        assert!(check_unstable_options(&rustflags, &meta("1.80.0-nightly")).is_ok());
+        let stable = cargo::Rustflags::new(vec!["-Cpanic=abort".to_owned()]);
+        assert!(check_unstable_options(&stable, &meta("1.78.0")).is_ok());
+    }
+
+    #[test]
+    fn timings_report_the_sysroot_and_cargo() {
+        let built = Duration::from_millis(1234);
+        assert_eq!(
+            timings(
+                "thumbv7m-none-eabi",
+                Some(built),
+                built + Duration::from_millis(10),
+                "build",
+                Duration::from_millis(500)
+            ),
+            "sysroot for thumbv7m-none-eabi: built in 1.23s (1.24s in total), cargo build: 0.50s"
+        );
+        assert_eq!(
+            timings(
+                "thumbv7m-none-eabi",
+                None,
+                Duration::from_millis(20),
+                "check",
+                Duration::from_secs(2)
+            ),
+            "sysroot for thumbv7m-none-eabi: up to date (0.02s in total), cargo check: 2.00s"
+        );
+    }
+
+    #[test]
+    fn link_args_contradicting_the_target_spec_are_reported() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let json = td.path().join("custom.json");
+        fs::write(
+            &json,
+            r#"{"pre-link-args": {"ld.lld": ["--script=spec.ld", "-e", "start"]}}"#,
+        )
+        .unwrap();
+        let target = Target::Custom {
+            json: json.clone(),
+            triple: "custom".to_owned(),
+            orig_triple: json.display().to_string(),
+        };
+        let flags =
+            |flags: &[&str]| cargo::Rustflags::new(flags.iter().map(|f| (*f).to_owned()).collect());
+
+        let conflicts =
+            link_arg_conflicts(&flags(&["-C", "link-arg=-Tother.ld"]), &target).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("`--script other.ld`"));
+        assert!(conflicts[0].contains("`--script spec.ld`"));
+
+        // Agreeing and unrelated linker options are fine
+        let same = flags(&["-Clink-arg=-Tspec.ld", "-Clink-args=-e start --gc-sections"]);
+        assert!(link_arg_conflicts(&same, &target).unwrap().is_empty());
+    }
+
+    #[test]
+    fn version_meta_includes_the_host_and_the_commit_hash() {
+        let meta = rustc_version::version_meta_for(
+            "rustc 1.80.0-nightly (0123abcd 2024-05-01)\nbinary: rustc\n\
+             commit-hash: 0123abcd\ncommit-date: 2024-05-01\n\
+             host: x86_64-unknown-linux-gnu\nrelease: 1.80.0-nightly\n",
+        );
+
+        let lines = version_meta(&meta, false).unwrap();
+        assert!(lines.contains("commit-hash: 0123abcd\n"));
+        assert!(lines.contains("host: x86_64-unknown-linux-gnu\n"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&version_meta(&meta, true).unwrap()).unwrap();
+        assert_eq!(json["commit_hash"], "0123abcd");
+        assert_eq!(json["host"], "x86_64-unknown-linux-gnu");
+        assert_eq!(json["channel"], "nightly");
+    }
 }
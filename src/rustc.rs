@@ -1,11 +1,15 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::env;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-
-pub use rustc_version::version_meta as version;
+use std::process::{Command, Stdio};
 
+use rustc_version::{self, VersionMeta};
 use serde_json;
 use serde_json::Value;
 use walkdir::WalkDir;
@@ -15,27 +19,539 @@ use extensions::CommandExt;
 use CurrentDirectory;
 use {rustc, util};
 
-fn command() -> Command {
-    env::var_os("RUSTC")
-        .map(Command::new)
-        .unwrap_or_else(|| Command::new("rustc"))
+/// The `cargo` and `rustc` that cargo-xbuild runs
+///
+/// These are the ones of the environment, unless a `rust-toolchain` file pins another
+/// toolchain, see `Toolchain::pinned`. The toolchain is passed to everything that runs them,
+/// instead of changing the environment of the process, which the threads of
+/// `build_sysroots` share.
+#[derive(Clone, Debug, Default)]
+pub struct Toolchain {
+    /// The pinned channel
+    channel: Option<String>,
+    /// The `cargo` to run instead of `CARGO` or the one in `PATH`
+    cargo: Option<PathBuf>,
+}
+
+impl Toolchain {
+    /// The toolchain of the environment, but with another `cargo`, whose `rustc` is used if
+    /// it has one
+    pub fn with_cargo(cargo: PathBuf) -> Toolchain {
+        Toolchain {
+            channel: None,
+            cargo: Some(cargo),
+        }
+    }
+
+    /// The toolchain that is pinned for `dir`, see `pinned_toolchain`, if cargo-xbuild wasn't
+    /// invoked through rustup
+    ///
+    /// The rustup proxies already select the pinned toolchain, and set `RUSTUP_TOOLCHAIN` for
+    /// their children. Otherwise, e.g. when `cargo-xbuild` is run directly with the `cargo` of
+    /// another toolchain in `PATH`, the `cargo` and `rustc` of the pinned toolchain are used,
+    /// and `RUSTUP_TOOLCHAIN` is set for the commands that run them. An explicit `RUSTC` always
+    /// wins.
+    pub fn pinned(dir: &Path, verbose: bool) -> Result<Toolchain> {
+        if env::var_os("RUSTUP_TOOLCHAIN").is_some() || env::var_os("RUSTC").is_some() {
+            return Ok(Toolchain::default());
+        }
+        let (file, channel) = match pinned_toolchain(dir)? {
+            Some(pin) => pin,
+            None => return Ok(Toolchain::default()),
+        };
+
+        let mut cmd = Command::new("rustup");
+        cmd.args(["which", "--toolchain", &channel, "cargo"]);
+        if verbose {
+            writeln!(io::stderr(), "+ {:?}", cmd).ok();
+        }
+        let out = match cmd.output() {
+            Ok(out) => out,
+            Err(_) => {
+                writeln!(
+                    io::stderr(),
+                    "{} {} pins the toolchain `{}`, but rustup isn't available to select it",
+                    util::level("warning"),
+                    file.display(),
+                    channel
+                )
+                .ok();
+                return Ok(Toolchain::default());
+            }
+        };
+        if !out.status.success() {
+            bail!(
+                "{} pins the toolchain `{}`, which isn't installed: {}",
+                file.display(),
+                channel,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+
+        let cargo = PathBuf::from(String::from_utf8_lossy(&out.stdout).trim());
+        Ok(Toolchain {
+            channel: Some(channel),
+            ..Toolchain::with_cargo(cargo)
+        })
+    }
+
+    /// A command that runs `cargo`: the pinned one, or else `CARGO` or the `cargo` in `PATH`
+    pub fn cargo(&self) -> Command {
+        let mut cmd = match self.cargo {
+            Some(ref cargo) => Command::new(cargo),
+            None => Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into())),
+        };
+        self.apply(&mut cmd);
+        cmd
+    }
+
+    /// The `rustc` that cargo uses: `RUSTC`, or else the `rustc` in `PATH`, which is usually
+    /// the rustup shim that runs the active toolchain
+    ///
+    /// Like cargo, this uses the `rustc` next to `cargo` when invoked through rustup (e.g. by
+    /// `cargo +nightly`) or when a toolchain is pinned, so that it always matches the
+    /// toolchain of `cargo`.
+    pub fn rustc(&self) -> PathBuf {
+        if let Some(rustc) = env::var_os("RUSTC") {
+            return PathBuf::from(rustc);
+        }
+
+        let cargo = match self.cargo {
+            Some(ref cargo) => Some(cargo.clone().into_os_string()),
+            None if env::var_os("RUSTUP_TOOLCHAIN").is_some() => env::var_os("CARGO"),
+            None => None,
+        };
+        if let Some(cargo) = cargo {
+            let rustc =
+                Path::new(&cargo).with_file_name(format!("rustc{}", env::consts::EXE_SUFFIX));
+            if rustc.is_file() {
+                return rustc;
+            }
+        }
+
+        PathBuf::from("rustc")
+    }
+
+    /// The canonicalized `rustc` binary that `rustc` refers to, looked up in `PATH` if it's
+    /// just a file name
+    ///
+    /// Unlike `rustc`, this doesn't depend on how `RUSTC` spells the path, e.g. through a
+    /// symlink, so it's what the sysroot hash includes.
+    pub fn resolved_rustc(&self) -> PathBuf {
+        resolve(self.rustc(), env::var_os("PATH"))
+    }
+
+    /// Makes the rustup proxies that `cmd` runs, e.g. the `rustc` of cargo, select the pinned
+    /// toolchain
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(ref channel) = self.channel {
+            cmd.env("RUSTUP_TOOLCHAIN", channel);
+        }
+    }
+
+    /// The name of the rustup toolchain with the `sysroot`, if `rustc` belongs to one
+    fn name(&self, sysroot: &Path) -> Option<String> {
+        if let Some(ref channel) = self.channel {
+            return Some(channel.clone());
+        }
+        if let Some(toolchain) = env::var_os("RUSTUP_TOOLCHAIN") {
+            return Some(toolchain.to_string_lossy().into_owned());
+        }
+
+        if sysroot.parent().and_then(|p| p.file_name()) == Some(OsStr::new("toolchains")) {
+            sysroot
+                .file_name()
+                .map(|t| t.to_string_lossy().into_owned())
+        } else {
+            None
+        }
+    }
+}
+
+fn resolve(rustc: PathBuf, paths: Option<OsString>) -> PathBuf {
+    let found = if rustc.components().count() == 1 {
+        let exe = format!("{}{}", rustc.display(), env::consts::EXE_SUFFIX);
+        paths.and_then(|paths| {
+            env::split_paths(&paths)
+                .flat_map(|dir| vec![dir.join(&rustc), dir.join(&exe)])
+                .find(|candidate| candidate.is_file())
+        })
+    } else {
+        Some(rustc.clone())
+    };
+    found
+        .and_then(|found| found.canonicalize().ok())
+        .unwrap_or(rustc)
+}
+
+/// The toolchain channel that a `rust-toolchain` or `rust-toolchain.toml` file in `dir` or
+/// one of its parents pins, along with the file
+///
+/// Like rustup, the closest file wins, and `rust-toolchain` may contain just the channel.
+pub fn pinned_toolchain(dir: &Path) -> Result<Option<(PathBuf, String)>> {
+    let mut file = None::<PathBuf>;
+    for name in &["rust-toolchain", "rust-toolchain.toml"] {
+        if let Some(found) = util::search(dir, name) {
+            // Paths are compared by components, so a directory sorts after its ancestors
+            if file.as_ref().is_none_or(|f| f.parent().unwrap() < found) {
+                file = Some(found.join(name));
+            }
+        }
+    }
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let contents = util::read(&file)?;
+    let contents = contents.trim();
+    let channel = if !contents.contains('\n') && !contents.contains('=') {
+        Some(contents.to_owned())
+    } else {
+        util::parse_str(contents, &file.display().to_string())?
+            .lookup("toolchain.channel")
+            .and_then(|c| c.as_str())
+            .map(|c| c.to_owned())
+    };
+    Ok(channel.filter(|c| !c.is_empty()).map(|c| (file, c)))
+}
+
+/// The `SOURCE_DATE_EPOCH` for the sysroot build and the crate, if `XBUILD_SOURCE_DATE_EPOCH=1`
+/// asks for the commit date of `rustc` and the variable isn't set already
+///
+/// Tools that embed timestamps, e.g. build scripts, use this instead of the current time, so
+/// that builds with the same toolchain are reproducible.
+pub fn source_date_epoch(meta: &VersionMeta) -> Option<String> {
+    if env::var_os("XBUILD_SOURCE_DATE_EPOCH").is_none_or(|v| v != "1")
+        || env::var_os("SOURCE_DATE_EPOCH").is_some()
+    {
+        return None;
+    }
+    let date = meta.commit_date.as_ref()?;
+    let mut parts = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    Some((util::days_from_civil(year, month, day) * 24 * 60 * 60).to_string())
+}
+
+fn command(toolchain: &Toolchain) -> Command {
+    let mut cmd = Command::new(toolchain.rustc());
+    toolchain.apply(&mut cmd);
+    cmd
+}
+
+/// `rustc -vV`
+pub fn version(toolchain: &Toolchain, verbose: bool) -> Result<VersionMeta> {
+    command(toolchain)
+        .arg("-vV")
+        .run_and_get_stdout(verbose)
+        .map(|out| rustc_version::version_meta_for(&out))
+}
+
+/// The outputs of `rustc --print target-list` and `rustc --print cfg` for one toolchain
+#[derive(Default, Deserialize, Serialize)]
+struct Queries {
+    rustc: PathBuf,
+    version: Option<String>,
+    commit_hash: Option<String>,
+    targets: Option<Vec<String>>,
+    cfgs: BTreeMap<String, Vec<String>>,
+}
+
+struct QueryCache {
+    queries: Queries,
+    file: Option<PathBuf>,
+}
+
+impl QueryCache {
+    fn save(&self) {
+        // The cache only saves `rustc` invocations, so failing to write it isn't an error
+        if let Some(ref file) = self.file {
+            if let Ok(json) = serde_json::to_string(&self.queries) {
+                file.parent().map(fs::create_dir_all);
+                util::write_atomic(file, &json).ok();
+            }
+        }
+    }
+}
+
+thread_local! {
+    static QUERIES: RefCell<Option<QueryCache>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with the query cache of the `rustc` of `toolchain`
+fn queries<F, R>(toolchain: &Toolchain, f: F) -> R
+where
+    F: FnOnce(&mut QueryCache) -> R,
+{
+    QUERIES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let rustc = toolchain.rustc();
+        if cache.as_ref().is_none_or(|c| c.queries.rustc != rustc) {
+            *cache = Some(QueryCache {
+                queries: Queries {
+                    rustc,
+                    ..Queries::default()
+                },
+                file: None,
+            });
+        }
+        f(cache.as_mut().unwrap())
+    })
+}
+
+/// Persists the outputs of `targets` and `cfg` in `file`, so that later invocations with the
+/// same toolchain don't have to query `rustc` again
+///
+/// The outputs stored in `file` are discarded if they were produced by a different `rustc`
+/// than `meta` describes.
+pub fn persist_queries(toolchain: &Toolchain, file: PathBuf, meta: &VersionMeta) {
+    queries(toolchain, |cache| {
+        cache.queries.version = Some(meta.short_version_string.clone());
+        cache.queries.commit_hash = meta.commit_hash.clone();
+
+        let stored = util::read(&file)
+            .ok()
+            .and_then(|json| serde_json::from_str::<Queries>(&json).ok())
+            .filter(|stored| {
+                stored.rustc == cache.queries.rustc
+                    && stored.version == cache.queries.version
+                    && stored.commit_hash == cache.queries.commit_hash
+            });
+        let dirty = match stored {
+            Some(stored) => {
+                let dirty = cache.queries.targets.is_some() && stored.targets.is_none()
+                    || cache
+                        .queries
+                        .cfgs
+                        .keys()
+                        .any(|target| !stored.cfgs.contains_key(target));
+                if cache.queries.targets.is_none() {
+                    cache.queries.targets = stored.targets;
+                }
+                for (target, cfgs) in stored.cfgs {
+                    cache.queries.cfgs.entry(target).or_insert(cfgs);
+                }
+                dirty
+            }
+            None => true,
+        };
+
+        cache.file = Some(file);
+        if dirty {
+            cache.save();
+        }
+    })
 }
 
 /// `rustc --print target-list`
-pub fn targets(verbose: bool) -> Result<Vec<String>> {
-    command()
-        .args(&["--print", "target-list"])
+pub fn targets(toolchain: &Toolchain, verbose: bool) -> Result<Vec<String>> {
+    if let Some(targets) = queries(toolchain, |cache| cache.queries.targets.clone()) {
+        return Ok(targets);
+    }
+
+    let targets = command(toolchain)
+        .args(["--print", "target-list"])
+        .run_and_get_stdout(verbose)
+        .map(|t| t.lines().map(|l| l.to_owned()).collect::<Vec<_>>())?;
+    queries(toolchain, |cache| {
+        cache.queries.targets = Some(targets.clone());
+        cache.save();
+    });
+    Ok(targets)
+}
+
+/// Returns the known target that is closest to the unknown `triple`, if any is close enough
+pub fn suggest_target(
+    toolchain: &Toolchain,
+    triple: &str,
+    verbose: bool,
+) -> Result<Option<String>> {
+    Ok(closest_target(triple, targets(toolchain, verbose)?))
+}
+
+fn closest_target(triple: &str, targets: Vec<String>) -> Option<String> {
+    let max_distance = ::std::cmp::max(3, triple.len() / 3);
+
+    targets
+        .into_iter()
+        .map(|t| (util::edit_distance(triple, &t), t))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, t)| t)
+}
+
+/// `rustc --print cfg --target <target> <flags>`
+///
+/// The output is cached if there are no `flags`. The cfgs of a target specification file are
+/// cached along with the hash of its contents, so that they're queried again after it changed.
+pub fn cfg(
+    toolchain: &Toolchain,
+    target: &str,
+    flags: &[String],
+    verbose: bool,
+) -> Result<Vec<String>> {
+    let key = match fs::read(target) {
+        Ok(ref spec) if target.ends_with(".json") => {
+            let mut hasher = DefaultHasher::new();
+            spec.hash(&mut hasher);
+            format!("{}#{:016x}", target, hasher.finish())
+        }
+        _ => target.to_owned(),
+    };
+    if flags.is_empty() {
+        if let Some(cfgs) = queries(toolchain, |cache| cache.queries.cfgs.get(&key).cloned()) {
+            return Ok(cfgs);
+        }
+    }
+
+    let cfgs = command(toolchain)
+        .args(["--print", "cfg", "--target", target])
+        .args(flags)
         .run_and_get_stdout(verbose)
-        .map(|t| t.lines().map(|l| l.to_owned()).collect())
+        .map(|t| t.lines().map(|l| l.to_owned()).collect::<Vec<_>>())?;
+    if flags.is_empty() {
+        queries(toolchain, |cache| {
+            cache.queries.cfgs.insert(key, cfgs.clone());
+            cache.save();
+        });
+    }
+    Ok(cfgs)
+}
+
+/// `rustc -Z unstable-options --print target-spec-json --target <triple>`
+///
+/// The option is unstable, so it's only available on nightly, or with `RUSTC_BOOTSTRAP=1` if
+/// `bootstrap` is set. The stderr of `rustc` is shown, e.g. for an unknown triple.
+pub fn target_spec_json(
+    toolchain: &Toolchain,
+    triple: &str,
+    bootstrap: bool,
+    verbose: bool,
+) -> Result<String> {
+    let mut cmd = command(toolchain);
+    cmd.args(["-Z", "unstable-options", "--print", "target-spec-json"])
+        .args(["--target", triple])
+        .stderr(Stdio::inherit());
+    if bootstrap {
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+    }
+    cmd.run_and_get_stdout(verbose)
+}
+
+/// Evaluates a `cfg(..)` expression, as used in `target.'cfg(..)'` sections of
+/// `.cargo/config`, against the output of `rustc --print cfg`
+///
+/// Returns `None` if `expr` isn't a valid `cfg(..)` expression.
+pub fn cfg_matches(expr: &str, cfgs: &[String]) -> Option<bool> {
+    let expr = expr.trim();
+    if !expr.starts_with("cfg(") || !expr.ends_with(')') {
+        return None;
+    }
+
+    let mut parser = CfgParser {
+        rest: &expr[4..expr.len() - 1],
+        cfgs,
+    };
+    let result = parser.predicate()?;
+    if parser.rest.trim().is_empty() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+struct CfgParser<'a> {
+    rest: &'a str,
+    cfgs: &'a [String],
+}
+
+impl<'a> CfgParser<'a> {
+    fn eat(&mut self, token: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        if self.rest.starts_with(token) {
+            self.rest = &self.rest[token.len()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        self.rest = self.rest.trim_start();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return None;
+        }
+        let ident = &self.rest[..end];
+        self.rest = &self.rest[end..];
+        Some(ident)
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        if !self.eat("\"") {
+            return None;
+        }
+        let end = self.rest.find('"')?;
+        let string = &self.rest[..end];
+        self.rest = &self.rest[end + 1..];
+        Some(string)
+    }
+
+    /// `name`, `name = "value"`, `all(..)`, `any(..)` or `not(..)`
+    fn predicate(&mut self) -> Option<bool> {
+        let ident = self.ident()?;
+        if self.eat("(") {
+            let mut results = vec![];
+            while !self.eat(")") {
+                results.push(self.predicate()?);
+                if !self.eat(",") {
+                    if !self.eat(")") {
+                        return None;
+                    }
+                    break;
+                }
+            }
+            match ident {
+                "all" => Some(results.iter().all(|&r| r)),
+                "any" => Some(results.iter().any(|&r| r)),
+                "not" if results.len() == 1 => Some(!results[0]),
+                _ => None,
+            }
+        } else if self.eat("=") {
+            let value = self.string()?;
+            let cfg = format!("{}=\"{}\"", ident, value);
+            Some(self.cfgs.contains(&cfg))
+        } else {
+            Some(self.cfgs.iter().any(|c| c == ident))
+        }
+    }
+}
+
+/// `rustup component add rust-src --toolchain <toolchain>`
+pub fn install_rust_src(toolchain: &str, verbose: bool) -> Result<()> {
+    writeln!(
+        io::stderr(),
+        "{} rust-src for toolchain {}",
+        util::status("Installing"),
+        toolchain
+    )
+    .ok();
+    Command::new("rustup")
+        .args(["component", "add", "rust-src", "--toolchain", toolchain])
+        .run(verbose)
 }
 
 /// `rustc --print sysroot`
-pub fn sysroot(verbose: bool) -> Result<Sysroot> {
-    command()
-        .args(&["--print", "sysroot"])
+pub fn sysroot(toolchain: &Toolchain, verbose: bool) -> Result<Sysroot> {
+    command(toolchain)
+        .args(["--print", "sysroot"])
         .run_and_get_stdout(verbose)
         .map(|l| Sysroot {
             path: PathBuf::from(l.trim()),
+            toolchain: toolchain.name(Path::new(l.trim())),
         })
 }
 /// Path to Rust source
@@ -44,10 +560,12 @@ pub struct Src {
 }
 
 impl Src {
+    pub fn new(path: PathBuf) -> Self {
+        Src { path }
+    }
+
     pub fn from_env() -> Option<Self> {
-        env::var_os("XARGO_RUST_SRC").map(|s| Src {
-            path: PathBuf::from(s),
-        })
+        env::var_os("XARGO_RUST_SRC").map(|s| Src::new(PathBuf::from(s)))
     }
 
     pub fn path(&self) -> &Path {
@@ -58,6 +576,8 @@ impl Src {
 /// Path to `rustc`'s sysroot
 pub struct Sysroot {
     path: PathBuf,
+    /// The name of the rustup toolchain, if `rustc` belongs to one
+    toolchain: Option<String>,
 }
 
 impl Sysroot {
@@ -97,8 +617,7 @@ impl Sysroot {
             }
         }
 
-        Err("`rust-src` component not found. Run `rustup component add \
-             rust-src`.")?
+        Err(ErrorKind::MissingRustSrc(self.toolchain.clone()))?
     }
 }
 
@@ -115,11 +634,26 @@ pub enum Target {
 }
 
 impl Target {
-    pub fn new(triple: &str, cd: &CurrentDirectory, verbose: bool) -> Result<Option<Target>> {
+    /// Resolves `triple`, which is a builtin target, a path to a target specification file, or
+    /// the name of one
+    ///
+    /// A name that isn't a builtin target is looked up as `<name>.json`, see `find_spec`. A
+    /// specification found by name is treated like its canonical path was passed, so that
+    /// cargo and the sysroot build find it without the search path.
+    pub fn new(
+        toolchain: &Toolchain,
+        triple: &str,
+        cd: &CurrentDirectory,
+        target_path: &[PathBuf],
+        verbose: bool,
+    ) -> Result<Option<Target>> {
         let triple = triple.to_owned();
 
-        if rustc::targets(verbose)?.iter().any(|t| t == &triple) {
-            Ok(Some(Target::Builtin { triple: triple }))
+        if rustc::targets(toolchain, verbose)?
+            .iter()
+            .any(|t| t == &triple)
+        {
+            Ok(Some(Target::Builtin { triple }))
         } else {
             let json = PathBuf::from(&triple);
             if json.exists() {
@@ -136,32 +670,26 @@ impl Target {
                     orig_triple: triple,
                 }));
             }
-            let mut json = cd.path().join(&triple);
-            json.set_extension("json");
 
-            if json.exists() {
-                return Ok(Some(Target::Custom {
-                    json: json,
-                    triple: triple.clone(),
-                    orig_triple: triple,
-                }));
-            } else {
-                if let Some(p) = env::var_os("RUST_TARGET_PATH") {
-                    let mut json = PathBuf::from(p);
-                    json.push(&triple);
-                    json.set_extension("json");
-
-                    if json.exists() {
-                        return Ok(Some(Target::Custom {
-                            json: json,
-                            triple: triple.clone(),
-                            orig_triple: triple,
-                        }));
-                    }
+            let rust_target_path = env::var_os("RUST_TARGET_PATH");
+            match find_spec(&triple, cd.path(), rust_target_path.as_ref(), target_path) {
+                Some(json) => {
+                    let json = util::simplify_path(
+                        json.canonicalize()
+                            .chain_err(|| ErrorKind::TargetSpecNotFound(json.clone()))?,
+                    );
+                    let orig_triple = json
+                        .to_str()
+                        .ok_or_else(|| format!("target path not valid utf8: {:?}", json))?
+                        .to_owned();
+                    Ok(Some(Target::Custom {
+                        json,
+                        triple,
+                        orig_triple,
+                    }))
                 }
+                None => Ok(None),
             }
-
-            Ok(None)
         }
     }
 
@@ -183,6 +711,25 @@ impl Target {
         }
     }
 
+    /// Returns the `pre-link-args` of a custom target specification, for all linker flavors
+    pub fn pre_link_args(&self) -> Result<Vec<String>> {
+        if let Target::Custom { ref json, .. } = *self {
+            let spec = serde_json::from_str::<Value>(&util::read(json)?)
+                .chain_err(|| format!("{} is not valid JSON", json.display()))?;
+            Ok(spec
+                .get("pre-link-args")
+                .and_then(|a| a.as_object())
+                .into_iter()
+                .flat_map(|flavors| flavors.values())
+                .filter_map(|args| args.as_array())
+                .flat_map(|args| args.iter().filter_map(|a| a.as_str()))
+                .map(|a| a.to_owned())
+                .collect())
+        } else {
+            Ok(vec![])
+        }
+    }
+
     pub fn hash<H>(&self, hasher: &mut H) -> Result<()>
     where
         H: Hasher,
@@ -199,3 +746,234 @@ impl Target {
         Ok(())
     }
 }
+
+/// Looks up the target specification `<triple>.json` in the current directory `cd`, then in
+/// the directories of `RUST_TARGET_PATH` (`rust_target_path`), like `rustc`, and then in
+/// `target_path` (see the `target_path` configuration key)
+fn find_spec(
+    triple: &str,
+    cd: &Path,
+    rust_target_path: Option<&OsString>,
+    target_path: &[PathBuf],
+) -> Option<PathBuf> {
+    let mut dirs = vec![cd.to_owned()];
+    dirs.extend(rust_target_path.into_iter().flat_map(env::split_paths));
+    dirs.extend(target_path.iter().cloned());
+    dirs.iter()
+        .map(|dir| cd.join(dir).join(format!("{}.json", triple)))
+        .find(|json| json.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn targets() -> Vec<String> {
+        [
+            "x86_64-unknown-linux-gnu",
+            "x86_64-unknown-none",
+            "thumbv7em-none-eabihf",
+        ]
+        .iter()
+        .map(|t| t.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn closest_target_suggests_typos() {
+        assert_eq!(
+            closest_target("x86_64-unknown-nonw", targets()),
+            Some("x86_64-unknown-none".to_owned())
+        );
+        assert_eq!(
+            closest_target("thumbv7em-none-eabi-hf", targets()),
+            Some("thumbv7em-none-eabihf".to_owned())
+        );
+    }
+
+    #[test]
+    fn closest_target_ignores_unrelated_names() {
+        assert_eq!(closest_target("my-kernel", targets()), None);
+    }
+
+    fn cfgs() -> Vec<String> {
+        [
+            "panic=\"abort\"",
+            "target_arch=\"arm\"",
+            "target_os=\"none\"",
+            "target_has_atomic=\"32\"",
+            "debug_assertions",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn cfg_matches_evaluates_predicates() {
+        let cfgs = cfgs();
+        assert_eq!(cfg_matches("cfg(target_os = \"none\")", &cfgs), Some(true));
+        assert_eq!(
+            cfg_matches("cfg(target_os = \"linux\")", &cfgs),
+            Some(false)
+        );
+        assert_eq!(cfg_matches("cfg(debug_assertions)", &cfgs), Some(true));
+        assert_eq!(cfg_matches("cfg(unix)", &cfgs), Some(false));
+        assert_eq!(
+            cfg_matches(
+                "cfg(all(target_arch = \"arm\", not(unix), any(windows, target_os = \"none\")))",
+                &cfgs
+            ),
+            Some(true)
+        );
+        assert_eq!(cfg_matches("cfg(all())", &cfgs), Some(true));
+        assert_eq!(cfg_matches("cfg(any())", &cfgs), Some(false));
+    }
+
+    #[test]
+    fn cfg_matches_rejects_invalid_expressions() {
+        let cfgs = cfgs();
+        assert_eq!(cfg_matches("thumbv7m-none-eabi", &cfgs), None);
+        assert_eq!(cfg_matches("cfg(target_os = none)", &cfgs), None);
+        assert_eq!(cfg_matches("cfg(not(unix, windows))", &cfgs), None);
+        assert_eq!(cfg_matches("cfg(unix windows)", &cfgs), None);
+    }
+
+    #[test]
+    fn cfg_includes_target_features_of_flags() {
+        let toolchain = Toolchain::default();
+        let host = version(&toolchain, false).unwrap().host;
+        let flags = vec!["-C".to_owned(), "target-feature=+crt-static".to_owned()];
+        let cfgs = cfg(&toolchain, &host, &flags, false).unwrap();
+        assert!(cfgs.contains(&"target_feature=\"crt-static\"".to_owned()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_finds_the_binary_behind_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        use tempdir::TempDir;
+
+        let td = TempDir::new("xbuild-test").unwrap();
+        let bin = td.path().join("bin");
+        let link = td.path().join("link");
+        fs::create_dir(&bin).unwrap();
+        fs::create_dir(&link).unwrap();
+        fs::write(bin.join("rustc"), "").unwrap();
+        symlink(bin.join("rustc"), link.join("rustc")).unwrap();
+
+        let rustc = bin.join("rustc").canonicalize().unwrap();
+        let paths = Some(env::join_paths(&[td.path().join("empty"), link.clone()]).unwrap());
+        assert_eq!(resolve(PathBuf::from("rustc"), paths), rustc);
+        assert_eq!(resolve(link.join("rustc"), None), rustc);
+        // Left as it is if it can't be found
+        assert_eq!(
+            resolve(PathBuf::from("rustc"), None),
+            PathBuf::from("rustc")
+        );
+    }
+
+    #[test]
+    fn cached_queries_do_not_spawn_rustc() {
+        // The cache is per thread, so this sees neither other tests nor a cache file. Answers
+        // that `rustc` would never give show that it isn't spawned again.
+        let toolchain = Toolchain::default();
+        queries(&toolchain, |cache| {
+            cache.queries.targets = Some(vec!["cached-target".to_owned()]);
+            cache
+                .queries
+                .cfgs
+                .insert("cached-target".to_owned(), vec!["cached".to_owned()]);
+        });
+        assert_eq!(
+            super::targets(&toolchain, false).unwrap(),
+            ["cached-target"]
+        );
+        assert_eq!(
+            cfg(&toolchain, "cached-target", &[], false).unwrap(),
+            ["cached"]
+        );
+
+        let host = version(&toolchain, false).unwrap().host;
+        let first = cfg(&toolchain, &host, &[], false).unwrap();
+        queries(&toolchain, |cache| {
+            cache
+                .queries
+                .cfgs
+                .get_mut(&host)
+                .unwrap()
+                .push("cached".to_owned())
+        });
+        let second = cfg(&toolchain, &host, &[], false).unwrap();
+        assert_eq!(second[..first.len()], first[..]);
+        assert_eq!(second.last().unwrap(), "cached");
+    }
+
+    #[test]
+    fn current_directory_wins_over_the_search_paths() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let dir = |name: &str| {
+            let dir = td.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("custom.json"), "{}").unwrap();
+            dir
+        };
+        let (cd, env_dir, config_dir) = (dir("cd"), dir("env"), dir("config"));
+        let rust_target_path = Some(env::join_paths([&env_dir]).unwrap());
+        let target_path = [PathBuf::from("../config")];
+        let find = || find_spec("custom", &cd, rust_target_path.as_ref(), &target_path);
+
+        assert_eq!(find(), Some(cd.join("custom.json")));
+        fs::remove_file(cd.join("custom.json")).unwrap();
+        assert_eq!(find(), Some(env_dir.join("custom.json")));
+        fs::remove_file(env_dir.join("custom.json")).unwrap();
+        assert_eq!(find(), Some(cd.join("../config/custom.json")));
+        fs::remove_file(config_dir.join("custom.json")).unwrap();
+        assert_eq!(find(), None);
+    }
+
+    #[test]
+    fn pinned_channel_is_used_for_the_spawned_commands() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        fs::write(td.path().join("rust-toolchain"), "nightly-2024-01-01\n").unwrap();
+        let bin = td.path().join("toolchains/nightly-2024-01-01/bin");
+        fs::create_dir_all(&bin).unwrap();
+        let cargo = bin.join(format!("cargo{}", env::consts::EXE_SUFFIX));
+        let rustc = bin.join(format!("rustc{}", env::consts::EXE_SUFFIX));
+        fs::write(&cargo, "").unwrap();
+        fs::write(&rustc, "").unwrap();
+
+        // `Toolchain::pinned` asks rustup for the `cargo` of the channel
+        let (_, channel) = pinned_toolchain(&td.path().join("src")).unwrap().unwrap();
+        let toolchain = Toolchain {
+            channel: Some(channel),
+            ..Toolchain::with_cargo(cargo.clone())
+        };
+        let pin = Some(Some(OsStr::new("nightly-2024-01-01")));
+        fn rustup_toolchain(cmd: &Command) -> Option<Option<&OsStr>> {
+            cmd.get_envs()
+                .find(|&(var, _)| var == "RUSTUP_TOOLCHAIN")
+                .map(|(_, value)| value)
+        }
+
+        let cmd = toolchain.cargo();
+        assert_eq!(cmd.get_program(), cargo.as_os_str());
+        assert_eq!(rustup_toolchain(&cmd), pin);
+        let cmd = command(&toolchain);
+        assert_eq!(rustup_toolchain(&cmd), pin);
+        // An explicit `RUSTC` wins over the pinned toolchain
+        if env::var_os("RUSTC").is_none() {
+            assert_eq!(cmd.get_program(), rustc.as_os_str());
+        }
+        assert_eq!(
+            toolchain.name(Path::new("/sysroot")),
+            Some("nightly-2024-01-01".to_owned())
+        );
+
+        let cmd = Toolchain::default().cargo();
+        assert_eq!(rustup_toolchain(&cmd), None);
+    }
+}
@@ -0,0 +1,336 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+use errors::*;
+use util;
+
+/// A progress update of a build, see `build_with_status`
+#[derive(Debug)]
+pub enum StatusEvent {
+    /// The `Cargo.toml` files in `manifests` and the `.cargo/config` files and `--config`
+    /// overrides in `cargo_config`, from the highest to the lowest precedence, were read
+    ConfigRead {
+        manifests: Vec<PathBuf>,
+        cargo_config: Vec<String>,
+    },
+    /// The build is for `triple`, which is specified by the file `spec` for a custom target
+    TargetResolved {
+        triple: String,
+        spec: Option<PathBuf>,
+    },
+    /// The sysroot for `triple` is checked for changes
+    SysrootCheck { triple: String },
+    /// The sysroot for `triple` has the hash `hash`, which is computed from `inputs`, one
+    /// `name: value` line per input
+    SysrootHash {
+        triple: String,
+        hash: u64,
+        inputs: String,
+    },
+    /// The sysroot for `triple` is up to date and reused
+    SysrootFresh { triple: String },
+    /// The sysroot for `triple` is about to be built
+    SysrootBuildStart { triple: String },
+    /// The sysroot for `triple` was built, or taken from the sysroot cache if `cached` is set
+    SysrootBuildFinish {
+        triple: String,
+        duration: Duration,
+        cached: bool,
+    },
+    /// The sysroot cache had the sysroot for `triple` if `hit` is set
+    SysrootCache { triple: String, hit: bool },
+    /// Another process, with the process ID `pid` if known, holds the lock on `what`, so the
+    /// build waits for it
+    ///
+    /// This is repeated periodically while waiting.
+    LockWait { what: String, pid: Option<u32> },
+    /// The lock on `what` was acquired after waiting `waited` for another process
+    LockAcquired { what: String, waited: Duration },
+    /// `cargo` is invoked for the crate itself
+    CargoInvocation { command: String },
+    /// `cargo` exited with `status`
+    CargoFinish { status: ExitStatus },
+}
+
+/// The handler of `with_handler`, whose lifetime is erased so that it can be shared with the
+/// threads of the build, see `Scope`
+///
+/// It is only called through the `Mutex`, and `with_handler` takes it out before the handler
+/// goes out of scope.
+type SharedHandler = Arc<Mutex<Option<&'static mut Handler<'static>>>>;
+
+type Handler<'a> = dyn FnMut(&StatusEvent) + Send + 'a;
+
+thread_local! {
+    static HANDLER: RefCell<Option<SharedHandler>> = RefCell::new(None);
+}
+
+/// The `--log-json` log, which is shared by all threads, so that it includes the events of
+/// the threads of `build_sysroots`
+static LOG: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Passes `event` to the handler of the current thread, or renders it to stderr like the
+/// `cargo-xbuild` binary if there is none
+///
+/// The event is also written to the `--log-json` log, if any.
+pub fn emit(event: StatusEvent) {
+    log(&event);
+
+    let handled = HANDLER.with(|handler| match *handler.borrow() {
+        Some(ref handler) => match *handler.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(ref mut handler) => {
+                handler(&event);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    });
+
+    if !handled {
+        render(&event);
+    }
+}
+
+fn render(event: &StatusEvent) {
+    if let StatusEvent::LockWait { ref what, pid } = *event {
+        let holder = pid.map(|pid| format!(" (held by pid {})", pid));
+        writeln!(
+            io::stderr(),
+            "{} waiting for file lock on {}{}",
+            util::status("Blocking"),
+            what,
+            holder.unwrap_or_default()
+        )
+        .ok();
+    }
+}
+
+/// Runs `f` with `handler` receiving the events emitted on the current thread and on the
+/// threads that enter the `Scope` of this one
+pub fn with_handler<H, F, R>(handler: &mut H, f: F) -> R
+where
+    H: FnMut(&StatusEvent) + Send,
+    F: FnOnce() -> R,
+{
+    /// Takes the handler out of its `Mutex` and restores the previous one, even on a panic
+    struct Reset(SharedHandler, Option<SharedHandler>);
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+            HANDLER.with(|h| *h.borrow_mut() = self.1.take());
+        }
+    }
+
+    let handler: &mut Handler = handler;
+    // Sound, as `Reset` takes the handler out before it goes out of scope
+    let handler = unsafe { mem::transmute::<&mut Handler, &'static mut Handler<'static>>(handler) };
+    let shared = Arc::new(Mutex::new(Some(handler)));
+    let previous = HANDLER.with(|h| h.borrow_mut().replace(shared.clone()));
+    let _reset = Reset(shared, previous);
+    f()
+}
+
+/// The handler of a thread, which threads that it spawns for the build enter, so that their
+/// events reach it too
+pub struct Scope(Option<SharedHandler>);
+
+impl Scope {
+    /// The scope of the current thread
+    pub fn current() -> Scope {
+        Scope(HANDLER.with(|h| h.borrow().clone()))
+    }
+
+    /// Runs `f` with the handler of this scope
+    pub fn enter<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let previous = HANDLER.with(|h| mem::replace(&mut *h.borrow_mut(), self.0));
+        let result = f();
+        HANDLER.with(|h| *h.borrow_mut() = previous);
+        result
+    }
+}
+
+/// Writes the events emitted on any thread to `path`, or to stdout for `-`, as one JSON object
+/// per line, see `--log-json`
+pub fn log_json(path: &str) -> Result<()> {
+    let log: Box<dyn Write + Send> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path).chain_err(|| format!("couldn't create {}", path))?)
+    };
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(log);
+    Ok(())
+}
+
+/// Records in the `--log-json` log, if any, that cargo-xbuild failed with `error`
+pub fn log_error(error: &Error) {
+    let mut fields = Map::new();
+    fields.insert("message".to_owned(), Value::from(error.to_string()));
+    fields.insert(
+        "causes".to_owned(),
+        error.iter().skip(1).map(|e| e.to_string()).collect(),
+    );
+    fields.insert("exit_code".to_owned(), Value::from(error.exit_code()));
+    write_log("error", fields);
+}
+
+/// The field names of the log are part of its format, so they must not be changed
+fn log(event: &StatusEvent) {
+    let mut fields = Map::new();
+    let mut field = |name: &str, value: Value| {
+        fields.insert(name.to_owned(), value);
+    };
+    let name = match *event {
+        StatusEvent::ConfigRead {
+            ref manifests,
+            ref cargo_config,
+        } => {
+            field("manifests", manifests.iter().map(|m| path(m)).collect());
+            field("cargo_config", Value::from(cargo_config.clone()));
+            "config-read"
+        }
+        StatusEvent::TargetResolved {
+            ref triple,
+            ref spec,
+        } => {
+            field("triple", Value::from(&**triple));
+            field("spec", spec.as_deref().map_or(Value::Null, path));
+            "target-resolved"
+        }
+        StatusEvent::SysrootCheck { ref triple } => {
+            field("triple", Value::from(&**triple));
+            "sysroot-check"
+        }
+        StatusEvent::SysrootHash {
+            ref triple,
+            hash,
+            ref inputs,
+        } => {
+            field("triple", Value::from(&**triple));
+            // As a string, since JSON numbers beyond 2^53 lose precision in many parsers
+            field("hash", Value::from(hash.to_string()));
+            field("inputs", inputs.lines().collect());
+            "sysroot-hash"
+        }
+        StatusEvent::SysrootFresh { ref triple } => {
+            field("triple", Value::from(&**triple));
+            "sysroot-fresh"
+        }
+        StatusEvent::SysrootBuildStart { ref triple } => {
+            field("triple", Value::from(&**triple));
+            "sysroot-build-start"
+        }
+        StatusEvent::SysrootBuildFinish {
+            ref triple,
+            duration,
+            cached,
+        } => {
+            field("triple", Value::from(&**triple));
+            field("duration_secs", secs(duration));
+            field("cached", Value::from(cached));
+            "sysroot-build-finish"
+        }
+        StatusEvent::SysrootCache { ref triple, hit } => {
+            field("triple", Value::from(&**triple));
+            field("hit", Value::from(hit));
+            "sysroot-cache"
+        }
+        StatusEvent::LockWait { ref what, pid } => {
+            field("what", Value::from(&**what));
+            field("pid", pid.map_or(Value::Null, Value::from));
+            "lock-wait"
+        }
+        StatusEvent::LockAcquired { ref what, waited } => {
+            field("what", Value::from(&**what));
+            field("waited_secs", secs(waited));
+            "lock-acquired"
+        }
+        StatusEvent::CargoInvocation { ref command } => {
+            field("command", Value::from(&**command));
+            "cargo-invocation"
+        }
+        StatusEvent::CargoFinish { status } => {
+            field("success", Value::from(status.success()));
+            field("exit_code", status.code().map_or(Value::Null, Value::from));
+            "cargo-finish"
+        }
+    };
+    write_log(name, fields);
+}
+
+/// Writes an `event` line with `fields` and flushes it, so that the log is complete even if
+/// the process exits right afterwards
+///
+/// The line is written while the lock is held, so that the lines of several threads don't
+/// interleave.
+fn write_log(event: &str, fields: Map<String, Value>) {
+    if let Some(ref mut log) = *LOG.lock().unwrap_or_else(|e| e.into_inner()) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = Map::new();
+        line.insert("event".to_owned(), Value::from(event));
+        line.insert("time".to_owned(), secs(time));
+        line.extend(fields);
+        let line = format!("{}\n", Value::Object(line));
+        log.write_all(line.as_bytes())
+            .and_then(|_| log.flush())
+            .ok();
+    }
+}
+
+fn path(path: &Path) -> Value {
+    Value::from(path.display().to_string())
+}
+
+fn secs(duration: Duration) -> Value {
+    Value::from(duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{emit, with_handler, Scope, StatusEvent};
+
+    fn check(triple: &str) -> StatusEvent {
+        StatusEvent::SysrootCheck {
+            triple: triple.to_owned(),
+        }
+    }
+
+    #[test]
+    fn handler_receives_events_of_entered_threads() {
+        let mut triples = vec![];
+        with_handler(
+            &mut |event: &StatusEvent| {
+                if let StatusEvent::SysrootCheck { ref triple } = *event {
+                    triples.push(triple.clone());
+                }
+            },
+            || {
+                emit(check("main"));
+                let scope = Scope::current();
+                thread::spawn(move || scope.enter(|| emit(check("worker"))))
+                    .join()
+                    .unwrap();
+                // Threads that don't enter the scope render their events themselves
+                thread::spawn(|| emit(check("other"))).join().unwrap();
+            },
+        );
+        emit(check("after"));
+        assert_eq!(triples, ["main", "worker"]);
+    }
+}
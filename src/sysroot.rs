@@ -1,71 +1,306 @@
 use std::collections::hash_map::DefaultHasher;
-use std::env;
 use std::hash::{Hash, Hasher};
-use std::io;
 use std::io::Write;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+use std::{env, fs, io};
 
-use rustc_version::VersionMeta;
+use rustc_version::{Channel, VersionMeta};
 use tempdir::TempDir;
 use toml::{Table, Value};
 
+use cache::Cache;
 use cargo;
 use cargo::Rustflags;
-use config::Config;
+use config::SysrootProfile;
 use errors::*;
 use extensions::CommandExt;
-use rustc::{Src, Sysroot, Target};
+use flock::FileLock;
+use gc;
+use info::{self, CrateInfo, SysrootInfo};
+use rustc::{self, Src, Sysroot, Target};
+use status::{self, StatusEvent};
 use util;
-use xargo::Home;
-use CompilationMode;
+use xargo::{self, Home};
+use {BuildContext, CompilationMode, Job};
 
-#[cfg(feature = "dev")]
-fn profile() -> &'static str {
-    "debug"
+/// Builds the sysroot for `cmode` while `rustlib`, its exclusive lock, is held
+///
+/// With `--sysroot-only`, the artifacts of the crates that it doesn't select are taken from
+/// the current sysroot, if it has any, instead of the new build.
+fn build(
+    job: &Job,
+    rustlib: &FileLock,
+    home: &Home,
+    src: &Src,
+    hash: u64,
+    prefix: Option<&str>,
+) -> Result<bool> {
+    let Job {
+        cmode,
+        ctx,
+        meta,
+        verbose,
+        ..
+    } = *job;
+    let config = &ctx.crate_config;
+    let lib = rustlib.parent().join("lib");
+    let kept = if ctx.settings.sysroot_only.is_empty() {
+        None
+    } else {
+        keep_unselected(&lib, &ctx.settings.sysroot_only)?
+    };
+
+    // This also removes temporary directories left behind by interrupted builds
+    rustlib
+        .remove_siblings(xargo::is_sysroot_entry)
+        .chain_err(|| format!("couldn't clear {}", rustlib.path().display()))?;
+
+    // Build into a temporary directory and only rename it to `lib` once it is complete, so
+    // that an interrupted build never leaves a partially populated sysroot behind
+    let dst = rustlib
+        .parent()
+        .join(format!("{}{}", xargo::TMP_LIB_PREFIX, process::id()));
+    util::mkdir(&dst)?;
+
+    if let Some(ref hook) = config.sysroot_pre_build {
+        run_hook("pre-build", hook, cmode, home, src, verbose)?;
+    }
+
+    // A partially rebuilt sysroot must neither come from nor end up in the cache
+    let cache = match kept {
+        Some(_) => None,
+        None => Cache::from_env(config)?,
+    };
+    let cached = match cache {
+        Some(ref cache) => {
+            let hit = cache.fetch(cmode.triple(), hash, &dst)?;
+            status::emit(StatusEvent::SysrootCache {
+                triple: cmode.triple().to_owned(),
+                hit,
+            });
+            hit
+        }
+        None => false,
+    };
+
+    let unwind = panic_unwind(cmode, ctx)?;
+    // The info is placed in `dst` until it's complete, so that the sysroot cache keeps it
+    let info_file = dst.join(info::FILE);
+    if !cached {
+        let mut info = SysrootInfo::new(cmode, ctx, meta, src, hash, unwind)?;
+        // `panic_unwind` depends on `alloc`, so this builds both
+        let krate = if unwind { &LIBPANIC_UNWIND } else { &LIBALLOC };
+        let krate = build_lib(krate, job, src, &dst, hash, prefix)?;
+        info.crates.push(krate);
+        util::write(&info_file, &info.to_json()?)?;
+
+        if let Some(ref cache) = cache {
+            cache.publish(cmode.triple(), hash, &dst)?;
+        }
+    }
+
+    if let Some(ref kept) = kept {
+        restore_unselected(kept.path(), &dst)?;
+    }
+
+    // Entries of the sysroot cache from before `sysroot-info.json` lack the built crates
+    if cached && !info_file.exists() {
+        let info = SysrootInfo::new(cmode, ctx, meta, src, hash, unwind)?;
+        util::write(&info_file, &info.to_json()?)?;
+    }
+
+    install(rustlib.parent(), &dst, hash, || {
+        match config.sysroot_post_build {
+            Some(ref hook) => run_hook("post-build", hook, cmode, home, src, verbose),
+            None => Ok(()),
+        }
+    })?;
+
+    Ok(cached)
 }
 
-#[cfg(not(feature = "dev"))]
-fn profile() -> &'static str {
-    "release"
+/// Moves the built sysroot in `dst` to `lib` in `dir`, runs the `post_build` hook and marks
+/// the sysroot as complete
+fn install<F>(dir: &Path, dst: &Path, hash: u64, post_build: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let lib = dir.join("lib");
+    // `dst` is a sibling of `lib`, so this doesn't cross file systems and is atomic
+    fs::rename(dst, &lib)
+        .chain_err(|| format!("couldn't move {} to {}", dst.display(), lib.display()))?;
+    let info_file = lib.join(info::FILE);
+    fs::rename(&info_file, dir.join(info::FILE))
+        .chain_err(|| format!("couldn't move {}", info_file.display()))?;
+
+    post_build()?;
+
+    // Record the produced `.rlib`s so that later runs can verify that none of them went missing
+    let mut manifest = String::new();
+    for file in rlibs(&lib)? {
+        manifest.push_str(&file);
+        manifest.push('\n');
+    }
+    util::write_atomic(&dir.join(".manifest"), &manifest)?;
+
+    // Only write the hash file once `lib` is in place and the hook succeeded; a sysroot
+    // without it is rebuilt
+    util::write_atomic(&dir.join(".hash"), &hash.to_string())
 }
 
-fn build(
+/// Runs the `sysroot_pre_build` or `sysroot_post_build` `hook`, depending on `kind`, through the
+/// shell in the project directory, e.g. to patch the Rust source in `src` or to add libraries
+/// to the sysroot in `home`, whose lock is held
+fn run_hook(
+    kind: &str,
+    hook: &str,
     cmode: &CompilationMode,
-    ctoml: &cargo::Toml,
     home: &Home,
-    config: &Config,
     src: &Src,
-    hash: u64,
     verbose: bool,
 ) -> Result<()> {
-    let rustlib = home.lock_rw(cmode.triple())?;
-    rustlib
-        .remove_siblings()
-        .chain_err(|| format!("couldn't clear {}", rustlib.path().display()))?;
-    let dst = rustlib.parent().join("lib");
-    util::mkdir(&dst)?;
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(hook);
+
+    let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+    cmd.current_dir(home.project());
+    cmd.env("XBUILD_SYSROOT", cd.join(home.as_path()));
+    cmd.env("XBUILD_TARGET", cmode.triple());
+    cmd.env("XBUILD_RUST_SRC", src.path());
+
+    // The output goes to stderr, so that stdout only carries e.g. the `--env-script` commands
+    cmd.run_prefixed(&format!("[sysroot_{}]", kind.replace('-', "_")), verbose)
+        .chain_err(|| ErrorKind::SysrootHookFailed(kind.to_owned(), hook.to_owned()))
+}
+
+/// The name of the crate of an artifact like `libcore-0123abcd.rlib`
+fn artifact_crate(file: &str) -> Option<&str> {
+    if !file.starts_with("lib") {
+        return None;
+    }
+    file.rfind('-').map(|i| &file[3..i])
+}
 
-    build_liballoc(cmode, &ctoml, src, &dst, config, verbose)?;
+/// Copies the artifacts of the crates that aren't in `only` from `lib` into a temporary
+/// directory, or returns `None` if there is no `lib` yet
+fn keep_unselected(lib: &Path, only: &[String]) -> Result<Option<TempDir>> {
+    if !lib.is_dir() {
+        return Ok(None);
+    }
 
-    // Create hash file
-    util::write(&rustlib.parent().join(".hash"), &hash.to_string())?;
+    let td = TempDir::new("xargo-kept").chain_err(|| "couldn't create a temporary directory")?;
+    for entry in lib
+        .read_dir()
+        .chain_err(|| format!("couldn't read {}", lib.display()))?
+    {
+        let entry = entry.chain_err(|| format!("couldn't read {}", lib.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        match artifact_crate(&name) {
+            Some(krate) if !only.iter().any(|c| c == krate) => {
+                let (src, dst) = (entry.path(), td.path().join(&name));
+                fs::copy(&src, &dst).chain_err(|| {
+                    format!("couldn't copy {} to {}", src.display(), dst.display())
+                })?;
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(td))
+}
 
+/// Replaces the artifacts in `dst` of each crate that has artifacts in `kept` with those
+fn restore_unselected(kept: &Path, dst: &Path) -> Result<()> {
+    let names = |dir: &Path| -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in dir
+            .read_dir()
+            .chain_err(|| format!("couldn't read {}", dir.display()))?
+        {
+            let entry = entry.chain_err(|| format!("couldn't read {}", dir.display()))?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    };
+
+    let kept_names = names(kept)?;
+    for name in names(dst)? {
+        if kept_names
+            .iter()
+            .any(|k| artifact_crate(k) == artifact_crate(&name))
+        {
+            let file = dst.join(&name);
+            fs::remove_file(&file).chain_err(|| format!("couldn't remove {}", file.display()))?;
+        }
+    }
+    for name in kept_names {
+        let (src, file) = (kept.join(&name), dst.join(&name));
+        fs::copy(&src, &file)
+            .chain_err(|| format!("couldn't copy {} to {}", src.display(), file.display()))?;
+    }
     Ok(())
 }
 
+/// The rustflags of the sysroot build, i.e. the `user` rustflags plus the ones of xbuild
+///
+/// The paths of the Rust source in `src` and of the crates.io dependencies are remapped like
+/// in the sysroot of the toolchain, e.g. to `/rustc/<commit hash>/library`, so that the
+/// compiled crates don't depend on where they were built. `XBUILD_SYSROOT_INCREMENTAL` enables
+/// incremental compilation, which speeds up rebuilds of a patched Rust source.
+fn sysroot_rustflags(user: &Rustflags, src: &Path, meta: &VersionMeta) -> Result<Vec<String>> {
+    let mut flags = user.as_slice().to_vec();
+    let commit = meta.commit_hash.as_ref().map_or("unknown", |h| &**h);
+    let dir = src.file_name().unwrap_or_default().to_string_lossy();
+    flags.push(format!(
+        "--remap-path-prefix={}=/rustc/{}/{}",
+        src.display(),
+        commit,
+        dir
+    ));
+    if let Some(home) = cargo::cargo_home() {
+        flags.push(format!(
+            "--remap-path-prefix={}=/cargo/registry/src",
+            home.join("registry").join("src").display()
+        ));
+    }
+    if let Some(dir) = env::var_os("XBUILD_SYSROOT_INCREMENTAL") {
+        let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+        flags.push(format!("-Cincremental={}", cd.join(dir).display()));
+    }
+    Ok(flags)
+}
+
 fn build_crate(
     crate_name: &str,
     mut stoml: String,
-    cmode: &CompilationMode,
-    ctoml: &cargo::Toml,
+    job: &Job,
+    src: &Src,
     dst: &Path,
-    verbose: bool,
-) -> Result<()> {
+    hash: u64,
+    prefix: Option<&str>,
+) -> Result<CrateInfo> {
+    let Job {
+        cmode,
+        ctx,
+        meta,
+        rustflags,
+        verbose,
+    } = *job;
+    let config = &ctx.crate_config;
+    let cargo_config = ctx.config.as_ref();
     let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
     let td = td.path();
 
-    if let Some(profile) = ctoml.profile() {
+    if let Some(profile) = ctx.profile() {
         stoml.push_str(&profile.to_string())
     }
 
@@ -73,11 +308,54 @@ fn build_crate(
     util::mkdir(&td.join("src"))?;
     util::write(&td.join("src/lib.rs"), "")?;
 
-    let cargo = std::env::var("CARGO").unwrap_or("cargo".to_string());
-    let mut cmd = Command::new(cargo);
-    cmd.env_remove("CARGO_TARGET_DIR");
+    // `XBUILD_BUILD_DIR` keeps the intermediate artifacts of the sysroot build around, e.g.
+    // for caching them on CI; by default they are thrown away with the temporary directory
+    let target_dir = match env::var_os("XBUILD_BUILD_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => td.join("target"),
+    };
+    let out_dir = target_dir
+        .join(cmode.triple())
+        .join(config.sysroot_profile.dir());
+    clear_stale_deps(&out_dir, hash)?;
+
+    let mut cmd = ctx.toolchain.cargo();
+    cmd.env("CARGO_TARGET_DIR", &target_dir);
     cmd.env_remove("RUSTFLAGS");
+    cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    cmd.env_remove("CARGO_BUILD_RUSTFLAGS");
+    cmd.env_remove(cargo::target_env_var(cmode.triple(), "rustflags"));
     cmd.env("__CARGO_DEFAULT_LIB_METADATA", "XARGO");
+    // The artifacts are expected in `target_dir` and the rustflags, which already include the
+    // ones of `.cargo/config`, are set below
+    cargo::apply_env(
+        cargo_config,
+        &mut cmd,
+        &["CARGO_TARGET_DIR", "RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"],
+    )?;
+    cargo::apply_target_tools(cargo_config, &mut cmd, cmode.triple())?;
+    // `RUSTC_WRAPPER` and `RUSTC_WORKSPACE_WRAPPER` are inherited, so that e.g. `sccache`
+    // caches the sysroot build too. They don't change the compiled crates, so they aren't
+    // part of the hash.
+    cargo::apply_rustc_wrappers(cargo_config, &mut cmd)?;
+    // Only the sysroot crates need unstable features, the crate itself is built without this
+    if config.allow_stable {
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+    }
+    // The resolved rustflags replace the ones of `.cargo/config`, which cargo would otherwise
+    // ignore in favor of the variables. Like for the crate, flags containing whitespace are
+    // rejected if the cargo only understands `RUSTFLAGS`.
+    let flags = sysroot_rustflags(rustflags, src.path(), meta)?;
+    let cargo_version = cargo::version(&ctx.toolchain, verbose)?;
+    xargo::set_flags(&mut cmd, "rustflags", &flags, &cargo_version, verbose)?;
+    if let Some(epoch) = rustc::source_date_epoch(meta) {
+        cmd.env("SOURCE_DATE_EPOCH", epoch);
+    }
+    if env::var_os("XBUILD_DISABLE_RUSTC_WRAPPER").is_some_and(|v| v == "1") {
+        // An empty value also overrides `build.rustc-wrapper` of `.cargo/config`
+        cmd.env("RUSTC_WRAPPER", "");
+        cmd.env("RUSTC_WORKSPACE_WRAPPER", "");
+    }
 
     // As of rust-lang/cargo#4788 Cargo invokes rustc with a changed "current directory" so
     // we can't assume that such directory will be the same as the directory from which
@@ -87,27 +365,31 @@ fn build_crate(
     // file stored in the current directory we'll set `RUST_TARGET_PATH`  to the current
     // directory.
     if env::var_os("RUST_TARGET_PATH").is_none() {
-        if let CompilationMode::Cross(ref target) = *cmode {
-            if let Target::Custom { ref json, .. } = *target {
-                cmd.env("RUST_TARGET_PATH", json.parent().unwrap());
-            }
+        if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+            cmd.env("RUST_TARGET_PATH", json.parent().unwrap());
         }
     }
 
     cmd.arg("rustc");
     cmd.arg("-p").arg(crate_name);
+    // The output is prefixed and thus piped when building several sysroots in parallel, so
+    // cargo can't detect the terminal itself
+    util::apply_color(&mut cmd);
+    if let Some(choice) = util::color_choice() {
+        cmd.arg("--color").arg(choice);
+    }
 
-    match () {
-        #[cfg(feature = "dev")]
-        () => {}
-        #[cfg(not(feature = "dev"))]
-        () => {
-            cmd.arg("--release");
-        }
+    if config.sysroot_profile == SysrootProfile::Release {
+        cmd.arg("--release");
+    }
+    // These are the features of the sysroot crates, which are separate from the features of
+    // the app in `args.all()`
+    if !config.sysroot_features.is_empty() {
+        cmd.arg("--features").arg(config.sysroot_features.join(" "));
     }
     cmd.arg("--manifest-path");
     cmd.arg(td.join("Cargo.toml"));
-    cmd.args(&["--target", cmode.orig_triple()]);
+    cmd.args(["--target", cmode.orig_triple()]);
 
     if verbose {
         cmd.arg("-v");
@@ -117,40 +399,99 @@ fn build_crate(
     cmd.arg("-Z");
     cmd.arg("force-unstable-if-unmarked");
 
-    cmd.run(verbose)?;
+    match prefix {
+        Some(prefix) => cmd.run_prefixed(prefix, verbose),
+        None => cmd.run(verbose),
+    }
+    .chain_err(|| ErrorKind::SysrootBuildFailed(crate_name.to_owned()))?;
 
     // Copy artifacts to Xargo sysroot
-    util::cp_r(
-        &td.join("target")
-            .join(cmode.triple())
-            .join(profile())
-            .join("deps"),
-        dst,
-    )?;
+    util::cp_r(&out_dir.join("deps"), dst)?;
 
-    Ok(())
+    CrateInfo::new(crate_name, &stoml, td)
 }
 
-fn build_liballoc(
-    cmode: &CompilationMode,
-    ctoml: &cargo::Toml,
+/// Removes the `deps` directory in `dir`, the output directory of the sysroot build, if its
+/// artifacts were built for another sysroot `hash`
+///
+/// cargo keeps the artifacts of earlier builds with other inputs, e.g. a second `libcore-*.rlib`,
+/// which would end up in the sysroot as well. This only matters for `XBUILD_BUILD_DIR`.
+fn clear_stale_deps(dir: &Path, hash: u64) -> Result<()> {
+    let file = dir.join(".xbuild-hash");
+    let deps = dir.join("deps");
+    let fresh = file.exists() && util::read(&file)? == hash.to_string();
+    if !fresh && deps.exists() {
+        fs::remove_dir_all(&deps).chain_err(|| format!("couldn't remove {}", deps.display()))?;
+    }
+    fs::create_dir_all(dir).chain_err(|| format!("couldn't create directory {}", dir.display()))?;
+    util::write(&file, &hash.to_string())
+}
+
+/// A sysroot crate that is built along with its dependencies
+struct SysrootCrate {
+    name: &'static str,
+    /// The directory of the crate in the Rust source
+    dir: &'static str,
+    /// The other sysroot crates it depends on, with their directories
+    deps: &'static [(&'static str, &'static str)],
+    /// Additional dependencies from crates.io, in TOML
+    extra: &'static str,
+}
+
+const LIBALLOC: SysrootCrate = SysrootCrate {
+    name: "alloc",
+    dir: "liballoc",
+    deps: &[("core", "libcore")],
+    extra: "",
+};
+
+const LIBPANIC_UNWIND: SysrootCrate = SysrootCrate {
+    name: "panic_unwind",
+    dir: "libpanic_unwind",
+    deps: &[
+        ("core", "libcore"),
+        ("alloc", "liballoc"),
+        ("unwind", "libunwind"),
+    ],
+    extra: r#"
+[dependencies.libc]
+version = "0.2"
+default-features = false
+features = ['rustc-dep-of-std']
+"#,
+};
+
+fn build_lib(
+    krate: &SysrootCrate,
+    job: &Job,
     src: &Src,
     dst: &Path,
-    config: &Config,
-    verbose: bool,
-) -> Result<()> {
-    const TOML: &'static str = r#"
+    hash: u64,
+    prefix: Option<&str>,
+) -> Result<CrateInfo> {
+    let config = &job.ctx.crate_config;
+    let path = src.path().join(krate.dir).join("lib.rs");
+    if !path.is_file() {
+        bail!(
+            "the `{}` crate wasn't found in the Rust source at {}",
+            krate.name,
+            src.path().display()
+        );
+    }
+
+    let mut stoml = format!(
+        r#"
 [package]
 authors = ["The Rust Project Developers"]
-name = "alloc"
+name = "{}"
 version = "0.0.0"
 edition = "2018"
-
+{}
 [dependencies.compiler_builtins]
 version = "0.1.0"
-"#;
-
-    let mut stoml = TOML.to_owned();
+"#,
+        krate.name, krate.extra
+    );
 
     if config.memcpy {
         stoml.push_str("features = ['mem', 'core']\n");
@@ -158,11 +499,10 @@ version = "0.1.0"
         stoml.push_str("features = ['rustc-std-workspace-core']\n");
     }
 
-    stoml.push_str("[dependencies.core]\n");
-    stoml.push_str(&format!(
-        "path = '{}'\n",
-        src.path().join("libcore").display()
-    ));
+    for &(name, dir) in krate.deps {
+        stoml.push_str(&format!("[dependencies.{}]\n", name));
+        stoml.push_str(&format!("path = '{}'\n", src.path().join(dir).display()));
+    }
 
     stoml.push_str("[patch.crates-io.rustc-std-workspace-core]\n");
     stoml.push_str(&format!(
@@ -170,21 +510,99 @@ version = "0.1.0"
         src.path().join("tools/rustc-std-workspace-core").display()
     ));
 
-    let path = src.path().join("liballoc/lib.rs").display().to_string();
     let mut map = Table::new();
     let mut lib = Table::new();
-    lib.insert("name".to_owned(), Value::String("alloc".to_owned()));
-    lib.insert("path".to_owned(), Value::String(path));
+    lib.insert("name".to_owned(), Value::String(krate.name.to_owned()));
+    lib.insert("path".to_owned(), Value::String(path.display().to_string()));
     map.insert("lib".to_owned(), Value::Table(lib));
     stoml.push_str(&Value::Table(map).to_string());
 
-    build_crate("alloc", stoml, cmode, ctoml, dst, verbose)
+    build_crate(krate.name, stoml, job, src, dst, hash, prefix)
 }
 
-fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
-    // FIXME this should be `lock_ro`
-    let lock = home.lock_rw(cmode.triple())?;
-    let hfile = lock.parent().join(".hash");
+/// Returns `true` if the sysroot is built for the `unwind` panic strategy, which needs
+/// `panic_unwind`
+///
+/// The `panic` key of the profile takes precedence over the default strategy of the target,
+/// which `rustc --print cfg` reports. Unwinding is an error for targets that don't support it,
+/// e.g. bare metal targets.
+fn panic_unwind(cmode: &CompilationMode, ctx: &BuildContext) -> Result<bool> {
+    let cfgs = rustc::cfg(&ctx.toolchain, cmode.orig_triple(), &[], false)?;
+    let default = cfgs.iter().any(|c| c == "panic=\"unwind\"");
+    match ctx.profile().and_then(|p| p.panic()) {
+        Some("unwind") if !default && !supports_unwinding(&cfgs) => {
+            Err(ErrorKind::InvalidConfig(format!(
+                "`panic = \"unwind\"` is set in [profile.{}], but the target `{}` doesn't \
+                 support unwinding",
+                ctx.crate_config.sysroot_profile.name(),
+                cmode.triple()
+            )))?
+        }
+        Some(panic) => Ok(panic == "unwind"),
+        None => Ok(default),
+    }
+}
+
+/// Whether `panic_unwind` implements unwinding for the target with the given `cfgs`, instead of
+/// aborting
+fn supports_unwinding(cfgs: &[String]) -> bool {
+    cfgs.iter()
+        .any(|c| c == "target_family=\"unix\"" || c == "target_family=\"windows\"")
+}
+
+/// The newest commit date of a `rustc` whose cargo and `rust-src` lack the
+/// `compiler-builtins-mem` feature of `-Zbuild-std-features`
+const BUILD_STD_MAX_UNSUPPORTED_COMMIT_DATE: &str = "2020-09-28";
+
+/// The first nightly that supports `--use-build-std`
+const BUILD_STD_MIN_NIGHTLY: &str = "nightly-2020-09-30";
+
+/// Returns the `-Zbuild-std` flags that make cargo build the sysroot crates itself, as an
+/// alternative to building a sysroot
+pub fn build_std_args(
+    cmode: &CompilationMode,
+    ctx: &BuildContext,
+    meta: &VersionMeta,
+) -> Result<Vec<String>> {
+    match (&meta.channel, &meta.commit_date) {
+        (&Channel::Nightly, &Some(ref date)) | (&Channel::Dev, &Some(ref date))
+            if &**date > BUILD_STD_MAX_UNSUPPORTED_COMMIT_DATE => {}
+        _ => bail!(
+            "`--use-build-std` requires {} or newer, but the toolchain is `{}`",
+            BUILD_STD_MIN_NIGHTLY,
+            meta.short_version_string
+        ),
+    }
+
+    let (crates, features) = build_std_crates(cmode, ctx)?;
+    let mut args = vec![format!("-Zbuild-std={}", crates.join(","))];
+    if !features.is_empty() {
+        args.push(format!("-Zbuild-std-features={}", features.join(",")));
+    }
+    Ok(args)
+}
+
+/// Returns the crates and the features for `-Zbuild-std` that correspond to the sysroot that
+/// cargo-xbuild builds
+pub fn build_std_crates(
+    cmode: &CompilationMode,
+    ctx: &BuildContext,
+) -> Result<(Vec<&'static str>, Vec<String>)> {
+    let config = &ctx.crate_config;
+    let mut crates = SYSROOT_CRATES.to_vec();
+    if panic_unwind(cmode, ctx)? {
+        crates.push("panic_unwind");
+    }
+    let mut features = config.sysroot_features.clone();
+    if config.memcpy {
+        features.push("compiler-builtins-mem".to_owned());
+    }
+    Ok((crates, features))
+}
+
+/// Reads the hash of the sysroot in `dir`, whose lock must be held
+pub fn old_hash(dir: &Path) -> Result<Option<u64>> {
+    let hfile = dir.join(".hash");
 
     if hfile.exists() {
         Ok(util::read(&hfile)?.parse().ok())
@@ -193,20 +611,71 @@ fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
     }
 }
 
+/// The crates whose `.rlib`s must be present in a complete sysroot
+pub const SYSROOT_CRATES: &[&str] = &["core", "compiler_builtins", "alloc"];
+
+/// The additional crates of a sysroot with `panic = "unwind"`
+const UNWIND_CRATES: &[&str] = &["panic_unwind", "unwind"];
+
+/// Returns the names of the `.rlib`s in `lib`
+fn rlibs(lib: &Path) -> Result<Vec<String>> {
+    let mut files = vec![];
+    for entry in lib
+        .read_dir()
+        .chain_err(|| format!("couldn't read {}", lib.display()))?
+    {
+        let entry = entry.chain_err(|| format!("couldn't read {}", lib.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".rlib") {
+            files.push(name);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Checks that the `.rlib`s of all `crates` and all `.rlib`s listed in the `.manifest` written
+/// by the build exist in the sysroot in `dir`, whose lock must be held
+fn artifacts_present(dir: &Path, crates: &[&str]) -> Result<bool> {
+    let lib = dir.join("lib");
+    if !lib.is_dir() {
+        return Ok(false);
+    }
+
+    let files = rlibs(&lib)?;
+
+    // Sysroots built by older versions don't have a manifest
+    let manifest = dir.join(".manifest");
+    if manifest.is_file() {
+        let manifest = util::read(&manifest)?;
+        if !manifest.lines().all(|f| files.iter().any(|g| g == f)) {
+            return Ok(false);
+        }
+    }
+
+    Ok(crates.iter().all(|krate| {
+        let prefix = format!("lib{}-", krate);
+        files.iter().any(|f| f.starts_with(&prefix))
+    }))
+}
+
 /// Computes the hash of the would-be target sysroot
 ///
 /// This information is used to compute the hash
 ///
-/// - RUSTFLAGS / build.rustflags / target.*.rustflags
+/// - CARGO_ENCODED_RUSTFLAGS / RUSTFLAGS / build.rustflags / target.*.rustflags
 /// - The target specification file, is any
-/// - `[profile.release]` in `Cargo.toml`
+/// - `[profile.release]` (or `[profile.dev]`) in `Cargo.toml`
 /// - `rustc` commit hash
+/// - The `rustc` binary that is used, e.g. the one `RUSTC` points to
+/// - The Rust source that is built, e.g. the one `XARGO_RUST_SRC` points to
+/// - Whether `panic_unwind` is part of the sysroot
 fn hash(
     cmode: &CompilationMode,
     rustflags: &Rustflags,
-    ctoml: &cargo::Toml,
+    ctx: &BuildContext,
     meta: &VersionMeta,
-    config: &Config,
+    src: &Src,
 ) -> Result<u64> {
     let mut hasher = DefaultHasher::new();
 
@@ -214,7 +683,7 @@ fn hash(
 
     cmode.hash(&mut hasher)?;
 
-    if let Some(profile) = ctoml.profile() {
+    if let Some(profile) = ctx.profile() {
         profile.hash(&mut hasher);
     }
 
@@ -222,93 +691,709 @@ fn hash(
         hash.hash(&mut hasher);
     }
 
-    config.hash(&mut hasher);
+    // Locally built compilers often report the same commit hash as the toolchain they are
+    // based on
+    ctx.toolchain.resolved_rustc().hash(&mut hasher);
+
+    // The hash is the key of the sysroot cache too, so a patched source must not share the
+    // entry of the toolchain's
+    src.path().hash(&mut hasher);
+
+    ctx.crate_config.hash(&mut hasher);
+
+    panic_unwind(cmode, ctx)?.hash(&mut hasher);
 
     Ok(hasher.finish())
 }
 
-pub fn update(
+/// Describes all inputs of the sysroot build, including the ones that `hash` leaves out
+/// because they don't change the compiled sysroot crates, e.g. the linker
+fn fingerprint(
     cmode: &CompilationMode,
-    home: &Home,
-    root: &Path,
-    config: &Config,
     rustflags: &Rustflags,
+    ctx: &BuildContext,
     meta: &VersionMeta,
     src: &Src,
+    hash: u64,
+) -> Result<String> {
+    let mut lines = vec![
+        format!("hash: {}", hash),
+        format!("target: {}", cmode.orig_triple()),
+        format!("rustflags: {}", rustflags.to_sorted_string()),
+        format!(
+            "rustc: {} ({})",
+            ctx.toolchain.resolved_rustc().display(),
+            meta.short_version_string
+        ),
+        format!("rust-src: {}", src.path().display()),
+        format!("config: {:?}", ctx.crate_config),
+    ];
+    if let Some(profile) = ctx.profile() {
+        let profile = profile.to_string();
+        lines.push(format!("profile: {}", profile.trim().replace('\n', "; ")));
+    }
+    if let Some(ref cargo_config) = ctx.config {
+        for key in &["linker", "ar"] {
+            if let Some(tool) = cargo_config.target_path(cmode.triple(), key)? {
+                lines.push(format!("{}: {}", key, tool));
+            }
+        }
+    }
+    for var in &["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"] {
+        if let Some(value) = env::var_os(var) {
+            lines.push(format!("{}: {}", var, value.to_string_lossy()));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Notes that the sysroot in `dir` is rebuilt because it was built from another target
+/// specification file with the same name
+///
+/// `rustc` finds the sysroot of a custom target by the file stem of its specification, so
+/// specifications with the same stem share one directory in the sysroot.
+fn check_target_spec(dir: &Path, cmode: &CompilationMode) -> Result<()> {
+    let json = match *cmode {
+        CompilationMode::Cross(Target::Custom { ref json, .. }) => json,
+        _ => return Ok(()),
+    };
+    let file = dir.join(".target-spec");
+    if !file.exists() {
+        return Ok(());
+    }
+
+    let recorded = util::read(&file)?;
+    if recorded != json.display().to_string() {
+        writeln!(
+            io::stderr(),
+            "{} the sysroot for `{}` was built from the target specification {}; rebuilding it \
+             for {}",
+            util::level("note"),
+            cmode.triple(),
+            recorded,
+            json.display()
+        )
+        .ok();
+    }
+    Ok(())
+}
+
+/// Lists the lines of the `recorded` fingerprint that the current `fingerprint` lacks, prefixed
+/// with `-`, and vice versa, prefixed with `+`
+fn fingerprint_diff(recorded: &str, fingerprint: &str) -> String {
+    let mut diff = String::new();
+    for line in recorded
+        .lines()
+        .filter(|l| !fingerprint.lines().any(|m| m == *l))
+    {
+        diff.push_str(&format!("    - {}\n", line));
+    }
+    for line in fingerprint
+        .lines()
+        .filter(|l| !recorded.lines().any(|m| m == *l))
+    {
+        diff.push_str(&format!("    + {}\n", line));
+    }
+    diff
+}
+
+/// The error for a sysroot in `dir` that needs a rebuild while `--locked-sysroot` is passed or
+/// the sysroot is `read_only`, which lists the changes to the recorded fingerprint
+fn locked_sysroot_error(
+    dir: &Path,
+    cmode: &CompilationMode,
+    fingerprint: &str,
+    read_only: bool,
+) -> Result<Error> {
+    let file = dir.join(".fingerprint");
+    let changes = if file.exists() {
+        let recorded = util::read(&file)?;
+        let recorded = recorded.split_once('\n').map(|x| x.1).unwrap_or("");
+        match fingerprint_diff(recorded, fingerprint) {
+            ref diff if diff.is_empty() => ", as its artifacts are incomplete".to_owned(),
+            diff => format!(":\n{}", diff.trim_end()),
+        }
+    } else {
+        ", as it wasn't built yet".to_owned()
+    };
+    let msg = if read_only {
+        format!(
+            "the sysroot for `{}` in {} is read-only, but needs to be rebuilt",
+            cmode.triple(),
+            dir.display()
+        )
+    } else {
+        format!(
+            "`--locked-sysroot` was passed, but the sysroot for `{}` needs to be rebuilt",
+            cmode.triple()
+        )
+    };
+    Ok(ErrorKind::SysrootOutdated(format!("{}{}", msg, changes)).into())
+}
+
+/// Returns the project that last built the sysroot in `dir` with another `fingerprint` than
+/// the one of `project`, along with the differences
+fn shared_with(dir: &Path, project: &Path, fingerprint: &str) -> Result<Option<(String, String)>> {
+    let file = dir.join(".fingerprint");
+    if !file.exists() {
+        return Ok(None);
+    }
+
+    let recorded = util::read(&file)?;
+    let mut recorded = recorded.splitn(2, '\n');
+    let other = recorded.next().unwrap_or("");
+    let other = other.trim_start_matches("project: ");
+    let recorded = recorded.next().unwrap_or("");
+    if other == project.display().to_string() || recorded == fingerprint {
+        return Ok(None);
+    }
+
+    Ok(Some((
+        other.to_owned(),
+        fingerprint_diff(recorded, fingerprint),
+    )))
+}
+
+/// Warns if the sysroot in `dir` was last built for another project than the one of `home`,
+/// with a different `fingerprint`, see `XBUILD_CHECK_SHARED_SYSROOT`
+fn check_shared(dir: &Path, home: &Home, fingerprint: &str) -> Result<()> {
+    let (project, diff) = match shared_with(dir, home.project(), fingerprint)? {
+        Some(shared) => shared,
+        None => return Ok(()),
+    };
+
+    let mut stderr = io::stderr();
+    writeln!(
+        stderr,
+        "{} the sysroot in {} is shared with the project in {}, which builds it with a \
+         different configuration:",
+        util::level("warning"),
+        home.display(),
+        project
+    )
+    .ok();
+    write!(stderr, "{}", diff).ok();
+    writeln!(
+        stderr,
+        "{} give each project its own sysroot through distinct `XBUILD_SYSROOT_PATH`s or \
+         `sysroot_path`s",
+        util::level("help")
+    )
+    .ok();
+    Ok(())
+}
+
+/// Checks the sysroot for `cmode` and rebuilds it if necessary
+///
+/// Returns how long the rebuild took, or `None` if the sysroot was up to date.
+pub fn update(
+    job: &Job,
+    home: &Home,
+    src: &Src,
     sysroot: &Sysroot,
-    verbose: bool,
-) -> Result<()> {
-    let ctoml = cargo::toml(root)?;
+    prefix: Option<&str>,
+) -> Result<Option<Duration>> {
+    let Job {
+        cmode,
+        ctx,
+        meta,
+        rustflags,
+        ..
+    } = *job;
+    let settings = &ctx.settings;
     let mut stderr = io::stderr();
 
-    let hash = hash(cmode, rustflags, &ctoml, meta, config)?;
+    status::emit(StatusEvent::SysrootCheck {
+        triple: cmode.triple().to_owned(),
+    });
+    let hash = hash(cmode, rustflags, ctx, meta, src)?;
+    let fingerprint = fingerprint(cmode, rustflags, ctx, meta, src, hash)?;
+    status::emit(StatusEvent::SysrootHash {
+        triple: cmode.triple().to_owned(),
+        hash,
+        inputs: fingerprint.clone(),
+    });
+    let check_shared_sysroot = env::var_os("XBUILD_CHECK_SHARED_SYSROOT").is_some_and(|v| v == "1");
 
-    if old_hash(cmode, home)? != Some(hash) {
-        build(cmode, &ctoml, home, config, src, hash, verbose)?;
+    let mut crates = SYSROOT_CRATES.to_vec();
+    if panic_unwind(cmode, ctx)? {
+        crates.extend(UNWIND_CRATES);
+    }
+    if let Some(krate) = settings
+        .sysroot_only
+        .iter()
+        .find(|c| !crates.contains(&&***c))
+    {
+        Err(ErrorKind::InvalidConfig(format!(
+            "`--sysroot-only` got `{}`, which isn't one of the sysroot crates `{}`",
+            krate,
+            crates.join("`, `")
+        )))?
     }
 
+    // Checking the sysroot only needs a shared lock, so that builds against an up to date
+    // sysroot don't wait for each other
+    let rebuild = {
+        let lock = home.lock_ro(cmode.triple())?;
+        if check_shared_sysroot {
+            check_shared(lock.parent(), home, &fingerprint)?;
+        }
+        let rebuild = if old_hash(lock.parent())? != Some(hash) {
+            check_target_spec(lock.parent(), cmode)?;
+            true
+        } else if !settings.sysroot_only.is_empty() {
+            true
+        } else if !artifacts_present(lock.parent(), &crates)? {
+            writeln!(
+                stderr,
+                "{} sysroot marker found but artifacts missing; rebuilding",
+                util::level("note")
+            )
+            .ok();
+            true
+        } else {
+            false
+        };
+        // A prebuilt sysroot may be read-only, which is fine as long as it's up to date
+        if rebuild && (settings.locked_sysroot || !util::is_writable(lock.parent())) {
+            let read_only = !settings.locked_sysroot;
+            return Err(locked_sysroot_error(
+                lock.parent(),
+                cmode,
+                &fingerprint,
+                read_only,
+            )?);
+        }
+        rebuild
+    };
+
+    // Another process may have built the sysroot while this one waited for the exclusive lock
+    let rustlib = if rebuild {
+        let lock = home.lock_rw(cmode.triple())?;
+        if old_hash(lock.parent())? != Some(hash)
+            || !artifacts_present(lock.parent(), &crates)?
+            || !settings.sysroot_only.is_empty()
+        {
+            Some(lock)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    let duration = if let Some(rustlib) = rustlib {
+        status::emit(StatusEvent::SysrootBuildStart {
+            triple: cmode.triple().to_owned(),
+        });
+        let start = Instant::now();
+        let cached = build(job, &rustlib, home, src, hash, prefix)?;
+        let duration = start.elapsed();
+        status::emit(StatusEvent::SysrootBuildFinish {
+            triple: cmode.triple().to_owned(),
+            duration,
+            cached,
+        });
+        util::write_atomic(
+            &rustlib.parent().join(".fingerprint"),
+            &format!("project: {}\n{}", home.project().display(), fingerprint),
+        )?;
+        if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+            util::write_atomic(
+                &rustlib.parent().join(".target-spec"),
+                &json.display().to_string(),
+            )?;
+        }
+        Some(duration)
+    } else {
+        status::emit(StatusEvent::SysrootFresh {
+            triple: cmode.triple().to_owned(),
+        });
+        None
+    };
+    home.touch(cmode.triple())?;
+
     // copy host artifacts into the sysroot, if necessary
-    if cmode.is_native() {
-        return Ok(());
+    if cmode.is_native() || !settings.host_crates {
+        return Ok(duration);
     }
 
-    let lock = home.lock_rw(&meta.host)?;
-    let hfile = lock.parent().join(".hash");
+    update_host(home, meta, sysroot)?;
+    home.touch(&meta.host)?;
 
-    let hash = meta.commit_hash.as_ref().map(|s| &**s).unwrap_or("");
-    if hfile.exists() {
-        if util::read(&hfile)? == hash {
+    Ok(duration)
+}
+
+/// Links or copies the libraries of the host from the toolchain into the sysroot, if they
+/// aren't up to date
+fn update_host(home: &Home, meta: &VersionMeta, sysroot: &Sysroot) -> Result<()> {
+    let mut stderr = io::stderr();
+
+    // The short version string contains the commit hash and date, and the sysroot path
+    // distinguishes toolchains, so any change of the host `rustc` triggers a new copy
+    let hash = format!("{}\n{}", meta.short_version_string, sysroot.path().display());
+    let host_fresh = |dir: &Path| -> Result<bool> {
+        let hfile = dir.join(".hash");
+        Ok(hfile.exists() && util::read(&hfile)? == hash && !util::has_broken_links(dir))
+    };
+    {
+        let lock = home.lock_ro(&meta.host)?;
+        if host_fresh(lock.parent())? {
             return Ok(());
         }
+        if !util::is_writable(lock.parent()) {
+            Err(ErrorKind::SysrootOutdated(format!(
+                "the read-only host sysroot in {} doesn't match the current toolchain",
+                lock.parent().display()
+            )))?
+        }
     }
 
-    lock.remove_siblings()
+    let lock = home.lock_rw(&meta.host)?;
+    let hfile = lock.parent().join(".hash");
+    if host_fresh(lock.parent())? {
+        return Ok(());
+    }
+    if hfile.exists() && util::read(&hfile)? == hash {
+        writeln!(
+            stderr,
+            "{} the host sysroot contains broken links; repairing it",
+            util::level("note")
+        )
+        .ok();
+    }
+
+    let mode = util::CopyMode::from_env()?;
+    // Only the copies of the entries of the toolchain are replaced
+    let src = sysroot.path().join("lib").join("rustlib").join(&meta.host);
+    lock.remove_siblings(|name| name == ".hash" || src.join(name).exists())
         .chain_err(|| format!("couldn't clear {}", lock.path().display()))?;
-    let dst = lock.parent().join("lib");
-    util::mkdir(&dst)?;
-    match util::cp_r(
-        &sysroot
-            .path()
-            .join("lib")
-            .join("rustlib")
-            .join(&meta.host)
-            .join("lib"),
-        &dst,
-    ) {
+
+    // Link or copy the whole `lib/rustlib/$HOST` tree (including `bin` and `lib/self-contained`) so
+    // that build scripts and proc macros find everything they need
+    match util::link_r(&src, lock.parent(), mode) {
         Ok(()) => {}
         Err(e) => {
             writeln!(
                 stderr,
-                "Unable to copy the directory 'lib' from sysroot: {}",
+                "Unable to copy the host directory from sysroot: {}",
                 e
             )
             .ok();
         }
     };
 
-    let bin_dst = lock.parent().join("bin");
-    util::mkdir(&bin_dst)?;
-    match util::cp_r(
-        &sysroot
-            .path()
-            .join("lib")
-            .join("rustlib")
-            .join(&meta.host)
-            .join("bin"),
-        &bin_dst,
-    ) {
-        Ok(()) => {}
-        Err(e) => {
-            writeln!(
-                stderr,
-                "Unable to copy the directory 'bin' from sysroot: {}",
-                e
-            )
-            .ok();
-        }
+    util::write_atomic(&hfile, &hash)?;
+    // Record the use before the lock is released, so that a concurrent `--gc-sysroots`
+    // doesn't take the fresh copy for an unused one
+    gc::touch(&lock.parent().join(".last-used"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::env;
+    use std::fs;
+    use std::hash::Hasher;
+    use std::path::Path;
+
+    use rustc_version;
+    use tempdir::TempDir;
+
+    use super::{
+        artifacts_present, build, clear_stale_deps, install, keep_unselected, locked_sysroot_error,
+        old_hash, restore_unselected, shared_with, supports_unwinding, sysroot_rustflags,
     };
+    use cargo::{self, Rustflags};
+    use config;
+    use errors::Result;
+    use rustc::{self, Src, Toolchain};
+    use xargo;
+    use {BuildContext, BuildSettings, CompilationMode, Job};
 
-    util::write(&hfile, hash)?;
+    fn cfgs(cfgs: &[&str]) -> Vec<String> {
+        cfgs.iter().map(|c| (*c).to_owned()).collect()
+    }
 
-    Ok(())
+    #[test]
+    fn unwinding_needs_an_operating_system() {
+        assert!(supports_unwinding(&cfgs(&[
+            "panic=\"abort\"",
+            "target_family=\"unix\"",
+            "target_os=\"linux\"",
+        ])));
+        assert!(!supports_unwinding(&cfgs(&[
+            "panic=\"abort\"",
+            "target_os=\"none\"",
+        ])));
+    }
+
+    #[test]
+    fn stale_deps_are_cleared() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let deps = td.path().join("deps");
+        clear_stale_deps(td.path(), 1).unwrap();
+        fs::create_dir(&deps).unwrap();
+        fs::write(deps.join("libcore-0123.rlib"), "").unwrap();
+
+        clear_stale_deps(td.path(), 1).unwrap();
+        assert!(deps.join("libcore-0123.rlib").exists());
+
+        clear_stale_deps(td.path(), 2).unwrap();
+        assert!(!deps.exists());
+    }
+
+    #[test]
+    fn artifacts_present_checks_the_manifest() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let lib = td.path().join("lib");
+        assert!(!artifacts_present(td.path(), &["core"]).unwrap());
+
+        fs::create_dir(&lib).unwrap();
+        for file in &["libcore-0123.rlib", "libextra-4567.rlib"] {
+            fs::write(lib.join(file), "").unwrap();
+        }
+        fs::write(
+            td.path().join(".manifest"),
+            "libcore-0123.rlib\nlibextra-4567.rlib\n",
+        )
+        .unwrap();
+        assert!(artifacts_present(td.path(), &["core"]).unwrap());
+        assert!(!artifacts_present(td.path(), &["core", "alloc"]).unwrap());
+
+        // A file of the manifest went missing
+        fs::remove_file(lib.join("libextra-4567.rlib")).unwrap();
+        assert!(!artifacts_present(td.path(), &["core"]).unwrap());
+    }
+
+    #[test]
+    fn sysroot_shared_with_other_configuration_is_detected() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let (a, b) = (Path::new("/projects/a"), Path::new("/projects/b"));
+        assert_eq!(shared_with(td.path(), b, "target: x").unwrap(), None);
+
+        fs::write(
+            td.path().join(".fingerprint"),
+            "project: /projects/a\ntarget: x\nrustflags: -Clink-arg=-Ta.ld\n",
+        )
+        .unwrap();
+        let (project, diff) =
+            shared_with(td.path(), b, "target: x\nrustflags: -Clink-arg=-Tb.ld\n")
+                .unwrap()
+                .unwrap();
+        assert_eq!(project, "/projects/a");
+        assert_eq!(
+            diff,
+            "    - rustflags: -Clink-arg=-Ta.ld\n    + rustflags: -Clink-arg=-Tb.ld\n"
+        );
+
+        // The same configuration, or the project that built it
+        let same = "target: x\nrustflags: -Clink-arg=-Ta.ld\n";
+        assert_eq!(shared_with(td.path(), b, same).unwrap(), None);
+        assert_eq!(shared_with(td.path(), a, "target: y\n").unwrap(), None);
+    }
+
+    #[test]
+    fn sysroot_only_keeps_the_other_crates() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let (lib, dst) = (td.path().join("lib"), td.path().join("dst"));
+        assert!(keep_unselected(&lib, &["core".to_owned()])
+            .unwrap()
+            .is_none());
+
+        fs::create_dir(&lib).unwrap();
+        fs::write(lib.join("libcore-old.rlib"), "old core").unwrap();
+        fs::write(lib.join("liballoc-old.rlib"), "old alloc").unwrap();
+        let kept = keep_unselected(&lib, &["core".to_owned()])
+            .unwrap()
+            .unwrap();
+
+        // The new build compiles all crates
+        fs::create_dir(&dst).unwrap();
+        fs::write(dst.join("libcore-new.rlib"), "new core").unwrap();
+        fs::write(dst.join("liballoc-new.rlib"), "new alloc").unwrap();
+        restore_unselected(kept.path(), &dst).unwrap();
+
+        let mut files = fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["liballoc-old.rlib", "libcore-new.rlib"]);
+        assert_eq!(
+            fs::read_to_string(dst.join("liballoc-old.rlib")).unwrap(),
+            "old alloc"
+        );
+    }
+
+    #[test]
+    fn incremental_flag_reaches_the_build_but_not_the_hash() {
+        let meta = rustc_version::version_meta_for(
+            "rustc 1.80.0-nightly\nbinary: rustc\ncommit-hash: abc\ncommit-date: unknown\n\
+             host: x86_64-unknown-linux-gnu\nrelease: 1.80.0-nightly\n",
+        );
+        let hash = |flags: &[&str]| {
+            let mut hasher = DefaultHasher::new();
+            Rustflags::new(flags.iter().map(|f| (*f).to_owned()).collect()).hash(&mut hasher);
+            hasher.finish()
+        };
+        let user = ["-Ctarget-cpu=cortex-m4"];
+        assert_eq!(
+            hash(&user),
+            hash(&["-Ctarget-cpu=cortex-m4", "-Cincremental=/a"])
+        );
+        assert_eq!(
+            hash(&user),
+            hash(&["-C", "incremental=/b", "-Ctarget-cpu=cortex-m4"])
+        );
+
+        env::set_var("XBUILD_SYSROOT_INCREMENTAL", "/tmp/xbuild-incremental");
+        let flags = sysroot_rustflags(
+            &Rustflags::new(vec![user[0].to_owned()]),
+            Path::new("/src/library"),
+            &meta,
+        );
+        env::remove_var("XBUILD_SYSROOT_INCREMENTAL");
+        let flags = flags.unwrap();
+        assert_eq!(flags[0], "-Ctarget-cpu=cortex-m4");
+        assert!(flags.contains(&"--remap-path-prefix=/src/library=/rustc/abc/library".to_owned()));
+        assert_eq!(
+            flags.last().unwrap(),
+            "-Cincremental=/tmp/xbuild-incremental"
+        );
+    }
+
+    #[test]
+    fn shuffled_rustflags_give_the_same_sysroot() {
+        let meta = rustc_version::version_meta_for(
+            "rustc 1.80.0-nightly\nbinary: rustc\ncommit-hash: abc\ncommit-date: unknown\n\
+             host: x86_64-unknown-linux-gnu\nrelease: 1.80.0-nightly\n",
+        );
+        let runs = [
+            "-Copt-level=3 --cfg foo -Ctarget-cpu=cortex-m4 -Zshare-generics=y --cfg bar",
+            "--cfg bar -Z share-generics=y -C target-cpu=cortex-m4 --cfg foo -C opt-level=3",
+        ];
+        let sysroots = runs
+            .iter()
+            .map(|run| {
+                let flags = Rustflags::new(run.split_whitespace().map(|f| f.to_owned()).collect());
+                let mut hasher = DefaultHasher::new();
+                flags.hash(&mut hasher);
+                let remap = sysroot_rustflags(&flags, Path::new("/src/library"), &meta)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|f| f.starts_with("--remap-path-prefix="))
+                    .collect::<Vec<_>>();
+                (hasher.finish(), flags.to_sorted_string(), remap)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sysroots[0], sysroots[1]);
+        assert_eq!(
+            sysroots[0].1,
+            "--cfg bar --cfg foo -Copt-level=3 -Ctarget-cpu=cortex-m4 -Zshare-generics=y"
+        );
+        assert!(sysroots[0]
+            .2
+            .contains(&"--remap-path-prefix=/src/library=/rustc/abc/library".to_owned()));
+    }
+
+    #[test]
+    fn failing_post_build_hook_leaves_no_hash() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let install_with = |hook: &dyn Fn() -> Result<()>| {
+            let _ = fs::remove_dir_all(td.path().join("lib"));
+            let dst = td.path().join(".tmp-lib-1");
+            fs::create_dir(&dst).unwrap();
+            fs::write(dst.join("libcore-0123abcd.rlib"), "").unwrap();
+            fs::write(dst.join("sysroot-info.json"), "{}").unwrap();
+            install(td.path(), &dst, 42, hook)
+        };
+
+        assert!(install_with(&|| bail!("the hook failed")).is_err());
+        assert_eq!(old_hash(td.path()).unwrap(), None);
+        assert!(!td.path().join(".manifest").exists());
+
+        install_with(&|| Ok(())).unwrap();
+        assert_eq!(old_hash(td.path()).unwrap(), Some(42));
+        assert_eq!(
+            fs::read_to_string(td.path().join(".manifest")).unwrap(),
+            "libcore-0123abcd.rlib\n"
+        );
+    }
+
+    #[test]
+    fn changed_rustflags_fail_a_locked_sysroot() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let cmode = CompilationMode::Native("x86_64-unknown-linux-gnu".to_owned());
+        fs::write(
+            td.path().join(".fingerprint"),
+            "project: /project\ntarget: x86_64-unknown-linux-gnu\nrustflags: -Copt-level=2\n",
+        )
+        .unwrap();
+
+        let fingerprint = "target: x86_64-unknown-linux-gnu\nrustflags: -Copt-level=3\n";
+        let err = locked_sysroot_error(td.path(), &cmode, fingerprint, false).unwrap();
+        assert_eq!(err.exit_code(), 106);
+        assert_eq!(
+            err.to_string(),
+            "`--locked-sysroot` was passed, but the sysroot for `x86_64-unknown-linux-gnu` needs \
+             to be rebuilt:\n    - rustflags: -Copt-level=2\n    + rustflags: -Copt-level=3"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pre_build_hook_runs_before_cargo() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = TempDir::new("xbuild-test").unwrap();
+        let log = td.path().join("log");
+        let cargo = td.path().join("cargo");
+        fs::write(
+            &cargo,
+            format!("#!/bin/sh\necho cargo >> '{}'\nexit 1\n", log.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&cargo, fs::Permissions::from_mode(0o755)).unwrap();
+        let src = td.path().join("src");
+        for dir in &["liballoc", "libpanic_unwind"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+            fs::write(src.join(dir).join("lib.rs"), "").unwrap();
+        }
+
+        let ctx = BuildContext {
+            toolchain: Toolchain::with_cargo(cargo),
+            root: td.path().to_owned(),
+            config: None,
+            toml: cargo::toml_from_release_profile(None).unwrap(),
+            crate_config: config::Config {
+                memcpy: true,
+                sysroot_path: td.path().join("sysroot"),
+                sysroot_cache: false,
+                sysroot_profile: config::SysrootProfile::Release,
+                sysroot_features: vec![],
+                sysroot_pre_build: Some(format!("echo pre-build >> '{}'", log.display())),
+                sysroot_post_build: None,
+                allow_stable: false,
+            },
+            settings: BuildSettings::default(),
+        };
+        let meta = rustc::version(&Toolchain::default(), false).unwrap();
+        let cmode = CompilationMode::Native(meta.host.clone());
+        let home = xargo::home(td.path(), &ctx.crate_config).unwrap();
+        let rustlib = home.lock_rw(&meta.host).unwrap();
+
+        let job = Job {
+            cmode: &cmode,
+            ctx: &ctx,
+            meta: &meta,
+            rustflags: &Rustflags::new(vec![]),
+            verbose: false,
+        };
+
+        let result = build(&job, &rustlib, &home, &Src::new(src), 42, None);
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "pre-build\ncargo\n");
+        assert_eq!(old_hash(rustlib.parent()).unwrap(), None);
+    }
 }
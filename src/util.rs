@@ -1,14 +1,114 @@
-use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
+use tempdir::TempDir;
 use toml::{Parser, Value};
 use walkdir::WalkDir;
 
 use errors::*;
 
+/// How `link_r` places files into the destination directory
+#[derive(Clone, Copy, PartialEq)]
+pub enum CopyMode {
+    /// Try a hard link, then a symlink, then a copy
+    Auto,
+    /// Try a hard link, then a copy
+    LinkOrCopy,
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+impl CopyMode {
+    /// Reads the mode from the `XBUILD_SYSROOT_COPY` environment variable
+    pub fn from_env() -> Result<CopyMode> {
+        match env::var("XBUILD_SYSROOT_COPY") {
+            Err(_) => Ok(CopyMode::Auto),
+            Ok(ref mode) if mode == "copy" => Ok(CopyMode::Copy),
+            Ok(ref mode) if mode == "hardlink" => Ok(CopyMode::Hardlink),
+            Ok(ref mode) if mode == "symlink" => Ok(CopyMode::Symlink),
+            Ok(mode) => Err(format!(
+                "XBUILD_SYSROOT_COPY must be one of `copy`, `hardlink`, or `symlink` (found `{}`)",
+                mode
+            ))?,
+        }
+    }
+
+    fn place(self, src: &Path, dst: &Path) -> io::Result<()> {
+        match self {
+            CopyMode::Auto => fs::hard_link(src, dst)
+                .or_else(|_| symlink(src, dst))
+                .or_else(|_| fs::copy(src, dst).map(|_| ())),
+            CopyMode::LinkOrCopy => {
+                fs::hard_link(src, dst).or_else(|_| fs::copy(src, dst).map(|_| ()))
+            }
+            CopyMode::Copy => fs::copy(src, dst).map(|_| ()),
+            CopyMode::Hardlink => fs::hard_link(src, dst),
+            CopyMode::Symlink => symlink(src, dst),
+        }
+    }
+}
+
+/// The shells that `--env-script` prints commands for
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Shell {
+    Posix,
+    Fish,
+    PowerShell,
+}
+
+impl Shell {
+    /// Parses the value of `--env-script`
+    pub fn from_name(name: &str) -> Result<Shell> {
+        match name {
+            "posix" => Ok(Shell::Posix),
+            "fish" => Ok(Shell::Fish),
+            "powershell" => Ok(Shell::PowerShell),
+            _ => Err(ErrorKind::InvalidConfig(format!(
+                "`--env-script` must be one of `posix`, `fish`, or `powershell` (found `{}`)",
+                name
+            )))?,
+        }
+    }
+
+    /// A command that sets the environment variable `name` to `value`, which is quoted so that
+    /// it's taken literally
+    pub fn export(self, name: &str, value: &str) -> String {
+        match self {
+            Shell::Posix => format!("export {}='{}'", name, value.replace('\'', "'\\''")),
+            Shell::Fish => format!(
+                "set -gx {} '{}'",
+                name,
+                value.replace('\\', "\\\\").replace('\'', "\\'")
+            ),
+            Shell::PowerShell => format!("$env:{} = '{}'", name, value.replace('\'', "''")),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(src, dst)
+}
+
+// Creating symlinks requires special privileges on Windows, so this often fails and
+// `CopyMode::Auto` falls back to copying
+#[cfg(windows)]
+fn symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    ::std::os::windows::fs::symlink_file(src, dst)
+}
+
 pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
+    link_r(src, dst, CopyMode::Copy)
+}
+
+/// Recursively places the files of `src` into `dst` according to `mode`
+pub fn link_r(src: &Path, dst: &Path, mode: CopyMode) -> Result<()> {
     for e in WalkDir::new(src) {
         // This is only an error when there's some sort of intermittent IO error
         // during iteration.
@@ -41,7 +141,7 @@ pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
                 .chain_err(|| format!("Could not create directory `{}`", dst_file.display()))?;
         } else {
             // else copy the file
-            fs::copy(&src_file, &dst_file).chain_err(|| {
+            mode.place(src_file, &dst_file).chain_err(|| {
                 format!(
                     "copying files from `{}` to `{}` failed",
                     src_file.display(),
@@ -54,15 +154,134 @@ pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Strips the `\\?\` prefix from extended-length Windows paths, as returned by
+/// `canonicalize`, where that's safe
+///
+/// Paths longer than `MAX_PATH` keep the prefix since they only work in extended-length form.
+/// `\\?\UNC\server\share` becomes `\\server\share`. This does nothing on other platforms.
+#[cfg(windows)]
+pub fn simplify_path(path: PathBuf) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    const MAX_PATH: usize = 260;
+
+    let simplified = {
+        let mut components = path.components();
+        let prefix = match components.next() {
+            Some(Component::Prefix(prefix)) => Some(prefix.kind()),
+            _ => None,
+        };
+        let rest = components.as_path();
+        match prefix {
+            Some(Prefix::VerbatimDisk(disk)) => {
+                Some(PathBuf::from(format!("{}:\\", disk as char)).join(rest))
+            }
+            Some(Prefix::VerbatimUNC(server, share)) => {
+                let mut unc = OsString::from(r"\\");
+                unc.push(server);
+                unc.push(r"\");
+                unc.push(share);
+                Some(PathBuf::from(unc).join(rest))
+            }
+            _ => None,
+        }
+    };
+
+    match simplified {
+        Some(ref simplified) if simplified.as_os_str().len() < MAX_PATH => simplified.clone(),
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+pub fn simplify_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// Returns `true` if `dir` contains a symlink whose target doesn't exist anymore
+///
+/// This happens when the sysroot was populated through symlinks and the toolchain was
+/// removed afterwards.
+pub fn has_broken_links(dir: &Path) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .any(|e| e.path_is_symbolic_link() && fs::metadata(e.path()).is_err())
+}
+
+/// Returns the total size of the files in `dir`
+pub fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Whether files can be created in `dir`, which e.g. a prebuilt sysroot on a read-only file
+/// system doesn't allow
+pub fn is_writable(dir: &Path) -> bool {
+    TempDir::new_in(dir, ".write-test").is_ok()
+}
+
+/// Levenshtein distance between `a` and `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == b[j] {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+
+    row[b.len()]
+}
+
 pub fn mkdir(path: &Path) -> Result<()> {
     fs::create_dir(path).chain_err(|| format!("couldn't create directory {}", path.display()))
 }
 
 /// Parses `path` as TOML
 pub fn parse(path: &Path) -> Result<Value> {
-    Ok(Value::Table(Parser::new(&read(path)?).parse().ok_or_else(
-        || format!("{} is not valid TOML", path.display()),
-    )?))
+    parse_str(&read(path)?, &path.display().to_string())
+}
+
+/// Parses `contents` as TOML, using `origin` to describe the source in error messages
+///
+/// The error points at the line and column of the first syntax error.
+pub fn parse_str(contents: &str, origin: &str) -> Result<Value> {
+    let mut parser = Parser::new(contents);
+    match parser.parse() {
+        Some(table) => Ok(Value::Table(table)),
+        None => {
+            let msg = match parser.errors.first() {
+                Some(error) => {
+                    let (line, col) = parser.to_linecol(error.lo);
+                    format!(
+                        "{} is not valid TOML: {} at line {}, column {}",
+                        origin,
+                        error.desc,
+                        line + 1,
+                        col + 1
+                    )
+                }
+                None => format!("{} is not valid TOML", origin),
+            };
+            Err(ErrorKind::InvalidConfig(msg))?
+        }
+    }
 }
 
 pub fn read(path: &Path) -> Result<String> {
@@ -92,6 +311,11 @@ pub fn search<'p>(mut path: &'p Path, file: &str) -> Option<&'p Path> {
     }
 }
 
+/// Like `search`, but returns all directories that contain `file`, closest first
+pub fn search_all<'p>(path: &'p Path, file: &str) -> Vec<&'p Path> {
+    path.ancestors().filter(|p| p.join(file).exists()).collect()
+}
+
 pub fn write(path: &Path, contents: &str) -> Result<()> {
     let p = path.display();
     File::create(path)
@@ -99,3 +323,281 @@ pub fn write(path: &Path, contents: &str) -> Result<()> {
         .write_all(contents.as_bytes())
         .chain_err(|| format!("couldn't write to {}", p))
 }
+
+/// Like `write`, but replaces `path` in one step so that it's never observed half-written
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let mut name = path.file_name().map(|n| n.to_owned()).unwrap_or_default();
+    name.push(".partial");
+    let tmp = path.with_file_name(name);
+
+    let mut file = File::create(&tmp).chain_err(|| format!("couldn't open {}", tmp.display()))?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .chain_err(|| format!("couldn't write to {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .chain_err(|| format!("couldn't move {} to {}", tmp.display(), path.display()))
+}
+
+/// Whether the diagnostics of cargo-xbuild are colored, see `set_color`: `COLOR_NEVER`,
+/// `COLOR_ALWAYS`, or 0 if `set_color` wasn't called
+static COLOR: AtomicUsize = AtomicUsize::new(0);
+const COLOR_NEVER: usize = 1;
+const COLOR_ALWAYS: usize = 2;
+
+/// Colors the diagnostics of cargo-xbuild according to the `--color` `choice`: `always`,
+/// `never` or `auto` (the default), which colors them if stderr is a terminal
+///
+/// Like in cargo, the `CARGO_TERM_COLOR` variable is used if `--color` isn't passed.
+pub fn set_color(choice: Option<&str>) -> Result<()> {
+    let env_choice = env::var("CARGO_TERM_COLOR").ok();
+    let env_choice = env_choice.as_deref();
+    let color = resolve_color(choice, env_choice, stderr_is_terminal)?;
+    let color = if color { COLOR_ALWAYS } else { COLOR_NEVER };
+    COLOR.store(color, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether the `--color` `choice`, or else the `CARGO_TERM_COLOR` `env_choice`, enables
+/// colors, where `auto` asks `terminal`
+fn resolve_color<F>(choice: Option<&str>, env_choice: Option<&str>, terminal: F) -> Result<bool>
+where
+    F: FnOnce() -> bool,
+{
+    let (origin, choice) = match choice {
+        Some(choice) => ("argument for --color", Some(choice)),
+        None => ("CARGO_TERM_COLOR", env_choice),
+    };
+    match choice {
+        Some("always") => Ok(true),
+        Some("never") => Ok(false),
+        Some("auto") | None => Ok(terminal()),
+        Some(choice) => bail!(
+            "{} must be auto, always, or never, but found `{}`",
+            origin,
+            choice
+        ),
+    }
+}
+
+/// The resolved `--color` choice, `always` or `never`, for passing it to child `cargo`s, see
+/// `apply_color`
+pub fn color_choice() -> Option<&'static str> {
+    match COLOR.load(Ordering::Relaxed) {
+        COLOR_ALWAYS => Some("always"),
+        COLOR_NEVER => Some("never"),
+        _ => None,
+    }
+}
+
+/// Passes the resolved color choice to the child `cargo` of `cmd` through `CARGO_TERM_COLOR`
+pub fn apply_color(cmd: &mut Command) {
+    if let Some(choice) = color_choice() {
+        cmd.env("CARGO_TERM_COLOR", choice);
+    }
+}
+
+/// Renders `text` in the bold ANSI `color` if diagnostics are colored
+fn paint(text: &str, color: u8) -> String {
+    paint_if(COLOR.load(Ordering::Relaxed) == COLOR_ALWAYS, text, color)
+}
+
+fn paint_if(colored: bool, text: &str, color: u8) -> String {
+    if colored {
+        format!("\x1b[1;{}m{}\x1b[0m", color, text)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// The prefix of a diagnostic of `level`, e.g. `warning:`, colored like cargo does
+pub fn level(level: &str) -> String {
+    let color = match level {
+        "error" => 31,
+        "warning" => 33,
+        _ => 36,
+    };
+    paint(&format!("{}:", level), color)
+}
+
+/// A status like `Installing`, right-aligned and colored like the ones of cargo
+pub fn status(status: &str) -> String {
+    paint(&format!("{:>12}", status), 32)
+}
+
+/// Asks the yes/no `question` on stderr, if both stdin and stderr are terminals
+pub fn confirm(question: &str) -> bool {
+    if !is_terminal() {
+        return false;
+    }
+
+    write!(io::stderr(), "{} [y/N] ", question).ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
+    matches!(answer.trim(), "y" | "Y" | "yes" | "Yes")
+}
+
+#[cfg(unix)]
+fn is_terminal() -> bool {
+    unsafe { ::libc::isatty(0) == 1 && ::libc::isatty(2) == 1 }
+}
+
+#[cfg(not(unix))]
+fn is_terminal() -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn stderr_is_terminal() -> bool {
+    unsafe { ::libc::isatty(2) == 1 }
+}
+
+#[cfg(not(unix))]
+fn stderr_is_terminal() -> bool {
+    false
+}
+
+/// The number of days between the Unix epoch and the given date of the Gregorian calendar,
+/// which must not be before the year 1
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn invalid_toml_is_a_config_error() {
+        match *parse_str("[build\ntarget = 1", ".cargo/config")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidConfig(ref msg) => assert!(msg.starts_with(".cargo/config")),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_file_and_line() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let path = td.path().join("config.toml");
+        fs::write(
+            &path,
+            "[build]\ntarget = \"thumbv7m-none-eabi\"\njobs = = 1\n[env]\n",
+        )
+        .unwrap();
+
+        let err = parse(&path).unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with(&format!("{} is not valid TOML: ", path.display())),
+            "{}",
+            msg
+        );
+        assert!(msg.contains(" at line 3, column "), "{}", msg);
+    }
+
+    #[test]
+    fn search_all_finds_every_ancestor() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let deep = td.path().join("a/b/c");
+        fs::create_dir_all(&deep).unwrap();
+        let needle = "xbuild-test-needle";
+        assert!(search_all(&deep, needle).is_empty());
+
+        fs::write(td.path().join("a").join(needle), "").unwrap();
+        assert_eq!(search_all(&deep, needle), [&*td.path().join("a")]);
+
+        fs::write(deep.join(needle), "").unwrap();
+        fs::write(td.path().join(needle), "").unwrap();
+        assert_eq!(
+            search_all(&deep, needle),
+            [&*deep, &*td.path().join("a"), td.path()]
+        );
+        assert_eq!(search(&deep, needle), Some(&*deep));
+    }
+
+    #[test]
+    fn color_never_strips_ansi_codes() {
+        let colored = resolve_color(Some("never"), None, || true).unwrap();
+        assert_eq!(paint_if(colored, "warning:", 33), "warning:");
+
+        let colored = resolve_color(Some("always"), None, || false).unwrap();
+        assert_eq!(
+            paint_if(colored, "warning:", 33),
+            "\x1b[1;33mwarning:\x1b[0m"
+        );
+
+        let err = resolve_color(Some("sometimes"), None, || true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "argument for --color must be auto, always, or never, but found `sometimes`"
+        );
+    }
+
+    #[test]
+    fn color_flag_wins_over_cargo_term_color() {
+        assert!(!resolve_color(Some("never"), Some("always"), || true).unwrap());
+        assert!(resolve_color(Some("auto"), Some("never"), || true).unwrap());
+        assert!(resolve_color(None, Some("always"), || false).unwrap());
+        assert!(!resolve_color(None, Some("auto"), || false).unwrap());
+        assert!(resolve_color(None, None, || true).unwrap());
+
+        let err = resolve_color(None, Some("yes"), || true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "CARGO_TERM_COLOR must be auto, always, or never, but found `yes`"
+        );
+
+        // Never colored diagnostics leave the output of the other tests alone
+        COLOR.store(COLOR_NEVER, Ordering::Relaxed);
+        let mut cmd = Command::new("cargo");
+        apply_color(&mut cmd);
+        let envs = cmd.get_envs().collect::<Vec<_>>();
+        assert_eq!(
+            envs,
+            [("CARGO_TERM_COLOR".as_ref(), Some("never".as_ref()))]
+        );
+    }
+
+    #[test]
+    fn edit_distance_counts_edits() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(
+            edit_distance("x86_64-unknown-none", "x86_64-unknown-none"),
+            0
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn simplify_path_strips_verbatim_prefixes() {
+        let simplify = |path: &str| simplify_path(PathBuf::from(path));
+        assert_eq!(
+            simplify(r"\\?\C:\project\x86_64-os.json"),
+            PathBuf::from(r"C:\project\x86_64-os.json")
+        );
+        assert_eq!(
+            simplify(r"\\?\UNC\server\share\x86_64-os.json"),
+            PathBuf::from(r"\\server\share\x86_64-os.json")
+        );
+        let long = format!(r"\\?\C:\{}\x86_64-os.json", "a".repeat(300));
+        assert_eq!(simplify(&long), PathBuf::from(&long));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn simplify_path_keeps_other_paths() {
+        let path = PathBuf::from("/project/x86_64-os.json");
+        assert_eq!(simplify_path(path.clone()), path);
+    }
+}
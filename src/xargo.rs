@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::io::{self, Write};
 use std::mem;
 use std::path::Path;
@@ -5,54 +6,340 @@ use std::path::{Display, PathBuf};
 use std::process::{Command, ExitStatus};
 use std::env;
 
-use rustc_version::VersionMeta;
 
-use cargo::Rustflags;
+use cargo::{self, Rustflags};
 use cli::Args;
 use config::Config;
 use errors::*;
 use extensions::CommandExt;
-use flock::{FileLock, Filesystem};
-use CompilationMode;
+use flock::{self, FileLock, Filesystem};
+use gc::{self, Entry, Policy};
+use info;
+use status::{self, StatusEvent};
+use rustc::{self, Target};
+use sysroot;
+use util;
+use {CompilationMode, Job};
 
 pub fn run(
     args: &Args,
-    cmode: &CompilationMode,
-    rustflags: Rustflags,
+    job: &Job,
+    mut rustdocflags: Rustflags,
     home: &Home,
-    meta: &VersionMeta,
     command_name: &str,
-    verbose: bool,
+    artifacts: Option<&mut Vec<PathBuf>>,
 ) -> Result<ExitStatus> {
-    let cargo = std::env::var("CARGO").unwrap_or("cargo".to_string());
-    let mut cmd = Command::new(cargo);
+    let Job {
+        cmode,
+        ctx,
+        meta,
+        verbose,
+        ..
+    } = *job;
+    let mut rustflags = job.rustflags.clone();
+    let cargo_config = ctx.config.as_ref();
+    let expose_build_info = ctx.settings.expose_build_info;
+    let mut cmd = ctx.toolchain.cargo();
     cmd.arg(command_name);
+    // `--verify-sysroot` reads the `rustc` invocations from the verbose output
+    if args.verify_sysroot() {
+        if args.quiet() {
+            Err(ErrorKind::InvalidConfig(
+                "`--verify-sysroot` can't be combined with `--quiet`".to_owned(),
+            ))?
+        }
+        if !args.verbose() {
+            cmd.arg("--verbose");
+        }
+    }
     cmd.args(args.all());
 
-    let flags = rustflags.for_xargo(home)?;
-    if verbose {
-        writeln!(io::stderr(), "+ RUSTFLAGS={:?}", flags).ok();
+    // Only the crate is built with this, as the flags were already hashed for the sysroot
+    if expose_build_info {
+        rustflags.push(&["--cfg", "xbuild"]);
+        rustdocflags.push(&["--cfg", "xbuild"]);
     }
-    cmd.env("RUSTFLAGS", flags);
+    let cargo_version = cargo::version(&ctx.toolchain, verbose)?;
+    apply_flags(
+        &mut cmd,
+        "rustflags",
+        &rustflags,
+        home,
+        &cargo_version,
+        verbose,
+    )?;
+    apply_flags(
+        &mut cmd,
+        "rustdocflags",
+        &rustdocflags,
+        home,
+        &cargo_version,
+        verbose,
+    )?;
+    // The per-target variables are already part of the flags
+    cmd.env_remove(cargo::target_env_var(cmode.triple(), "rustflags"));
+    cmd.env_remove(cargo::target_env_var(cmode.triple(), "rustdocflags"));
+    // The flags from the `[env]` table are already part of `rustflags` and `rustdocflags`
+    cargo::apply_env(
+        cargo_config,
+        &mut cmd,
+        &[
+            "RUSTFLAGS",
+            "CARGO_ENCODED_RUSTFLAGS",
+            "RUSTDOCFLAGS",
+            "CARGO_ENCODED_RUSTDOCFLAGS",
+        ],
+    )?;
 
-    let locks = (home.lock_ro(&meta.host), home.lock_ro(cmode.triple()));
+    // Pass the runner explicitly so that `cargo xrun` and `cargo xtest` use it even when
+    // the child `cargo` doesn't see the same `.cargo/config`
+    if let Some(config) = cargo_config {
+        if let Some(runner) = config.runner(cmode.triple())? {
+            let var = cargo::target_env_var(cmode.triple(), "runner");
+            if env::var_os(&var).is_none() {
+                cmd.env(var, runner);
+            }
+        }
+    }
+
+    cargo::apply_target_tools(cargo_config, &mut cmd, cmode.triple())?;
+    util::apply_color(&mut cmd);
+
+    let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+    apply_build_script_env(&mut cmd, cmode, home, &cd);
+    if let Some(epoch) = rustc::source_date_epoch(meta) {
+        cmd.env("SOURCE_DATE_EPOCH", epoch);
+    }
 
-    let status = cmd.run_and_get_status(verbose)?;
+    // Only build scripts and proc macros need the host sysroot
+    let host = if ctx.settings.host_crates {
+        Some(&*meta.host)
+    } else {
+        None
+    };
+    let locks = lock_sysroots(home, cmode.triple(), host);
+    if let Ok(ref lock) = locks.1 {
+        home.check_triple(cmode.triple())?;
+        // rustc records the variables that `env!` and `option_env!` read, so a changed hash
+        // only rebuilds the crates that embed it
+        if expose_build_info {
+            if let Some(hash) = sysroot::old_hash(lock.parent())? {
+                cmd.env("XBUILD_SYSROOT_HASH", format!("{:016x}", hash));
+            }
+        }
+    }
+
+    status::emit(StatusEvent::CargoInvocation {
+        command: format!("{:?}", cmd),
+    });
+
+    let status = match artifacts {
+        Some(artifacts) => {
+            let (status, files) = cmd.run_and_get_artifacts(verbose)?;
+            artifacts.extend(files);
+            status
+        }
+        None if args.verify_sysroot() => {
+            let (status, invocations) = cmd.run_and_get_invocations(args.verbose(), verbose)?;
+            if status.success() {
+                verify_sysroot(&invocations, home)?;
+            }
+            status
+        }
+        None => cmd.run_and_get_status(verbose)?,
+    };
 
     mem::drop(locks);
 
+    status::emit(StatusEvent::CargoFinish { status });
     Ok(status)
 }
 
+/// Sets `XBUILD_SYSROOT`, `XBUILD_TARGET` and, for a custom target, `XBUILD_TARGET_SPEC_PATH`,
+/// so that build scripts can locate the sysroot and the target
+///
+/// Relative paths are resolved against `cd`.
+fn apply_build_script_env(cmd: &mut Command, cmode: &CompilationMode, home: &Home, cd: &Path) {
+    cmd.env("XBUILD_SYSROOT", cd.join(home.as_path()));
+    cmd.env("XBUILD_TARGET", cmode.triple());
+    if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+        cmd.env("XBUILD_TARGET_SPEC_PATH", cd.join(json));
+    }
+}
+
+/// Checks that the `rustc` invocations of the crate build, as printed by `cargo --verbose`,
+/// passed the sysroot of `home`, see `--verify-sysroot`
+///
+/// Otherwise something between cargo-xbuild and `rustc` replaced the rustflags, and the crate
+/// was silently compiled against the prebuilt sysroot of the toolchain.
+fn verify_sysroot(invocations: &[String], home: &Home) -> Result<()> {
+    for invocation in invocations {
+        let args = shell_words(invocation);
+        let value = |flag: &str| {
+            let prefix = format!("{}=", flag);
+            args.iter().enumerate().find_map(|(i, arg)| {
+                if arg == flag {
+                    args.get(i + 1).cloned()
+                } else if arg.starts_with(&prefix) {
+                    Some(arg[prefix.len()..].to_owned())
+                } else {
+                    None
+                }
+            })
+        };
+
+        // Build scripts and proc macros are compiled for the host without the rustflags
+        let sysroot = value("--sysroot");
+        if value("--target").is_none() || sysroot.as_ref().map(Path::new) == Some(home.as_path()) {
+            continue;
+        }
+
+        let found = match sysroot {
+            Some(sysroot) => format!("`--sysroot {}`", sysroot),
+            None => "no `--sysroot`".to_owned(),
+        };
+        let vars = ["RUSTC", "RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"]
+            .iter()
+            .filter_map(|var| {
+                env::var(var)
+                    .ok()
+                    .map(|value| format!("`{}={}`", var, value))
+            })
+            .collect::<Vec<_>>();
+        let culprit = if vars.is_empty() {
+            "something between cargo-xbuild and `rustc` replaced them".to_owned()
+        } else {
+            format!("they were likely replaced through {}", vars.join(", "))
+        };
+        Err(ErrorKind::SysrootNotUsed(format!(
+            "`{}` was compiled with {} instead of `--sysroot {}`, i.e. against the prebuilt \
+             sysroot of the toolchain. cargo-xbuild passes the sysroot in \
+             `CARGO_ENCODED_RUSTFLAGS` (or `RUSTFLAGS` before Rust 1.55), but {}.",
+            value("--crate-name").unwrap_or_default(),
+            found,
+            home.display(),
+            culprit
+        )))?
+    }
+
+    Ok(())
+}
+
+/// Splits a command line as cargo prints it into its arguments, undoing the quoting of
+/// arguments that contain spaces or special characters
+fn shell_words(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                word.extend(chars.by_ref().take_while(|&q| q != c));
+            }
+            '\\' if cfg!(unix) => word.get_or_insert_with(String::new).extend(chars.next()),
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Passes `flags` for `tool` (`rustflags` or `rustdocflags`) and the `--sysroot` of `home`
+/// to the `cargo` of `cmd`, see `set_flags`
+fn apply_flags(
+    cmd: &mut Command,
+    tool: &str,
+    flags: &Rustflags,
+    home: &Home,
+    cargo_version: &str,
+    verbose: bool,
+) -> Result<()> {
+    let sysroot = format!("{}", home.display());
+    let allow_spaces = env::var_os("XBUILD_ALLOW_SYSROOT_SPACES").is_some();
+    if !cargo::supports_encoded_flags(cargo_version)
+        && !allow_spaces
+        && sysroot.contains(char::is_whitespace)
+    {
+        Err(ErrorKind::SysrootContainsSpaces(sysroot))?
+    }
+    set_flags(cmd, tool, &flags.for_xargo(home), cargo_version, verbose)
+}
+
+/// Passes `flags` for `tool` (`rustflags` or `rustdocflags`) to the `cargo` of `cmd`
+///
+/// `CARGO_ENCODED_RUSTFLAGS` separates the flags by `0x1f`, so that flags containing spaces,
+/// e.g. a `-C link-arg=..` or a sysroot path, arrive at `rustc` as one argument. Cargos before
+/// 1.55, as `cargo_version` tells, only understand `RUSTFLAGS`, which they split on
+/// whitespace, so such flags can't be passed to them at all, unless
+/// `XBUILD_ALLOW_SYSROOT_SPACES` is set. The same applies to `RUSTDOCFLAGS`.
+pub fn set_flags(
+    cmd: &mut Command,
+    tool: &str,
+    flags: &[String],
+    cargo_version: &str,
+    verbose: bool,
+) -> Result<()> {
+    let var = tool.to_uppercase();
+    let encoded_var = format!("CARGO_ENCODED_{}", var);
+    if cargo::supports_encoded_flags(cargo_version) {
+        let flags = flags.join("\x1f");
+        if verbose {
+            writeln!(io::stderr(), "+ {}={:?}", encoded_var, flags).ok();
+        }
+        cmd.env(&encoded_var, flags);
+        // cargo ignores `RUSTFLAGS` in favor of `CARGO_ENCODED_RUSTFLAGS`
+        cmd.env_remove(&var);
+        return Ok(());
+    }
+
+    let allow_spaces = env::var_os("XBUILD_ALLOW_SYSROOT_SPACES").is_some();
+    if let Some(flag) = flags
+        .iter()
+        .find(|f| !allow_spaces && f.contains(char::is_whitespace))
+    {
+        Err(ErrorKind::InvalidConfig(format!(
+            "the {} contain `{}`, which contains whitespace, but cargo {} splits `{}` on \
+             whitespace; use a cargo with `{}` support (1.55 or newer)",
+            tool, flag, cargo_version, var, encoded_var
+        )))?
+    }
+    let flags = flags.join(" ");
+    if verbose {
+        writeln!(io::stderr(), "+ {}={:?}", var, flags).ok();
+    }
+    cmd.env(&var, flags);
+    cmd.env_remove(&encoded_var);
+    Ok(())
+}
+
 pub struct Home {
     path: Filesystem,
+    project: PathBuf,
 }
 
 impl Home {
-    pub fn display(&self) -> Display {
+    pub fn display(&self) -> Display<'_> {
         self.path.display()
     }
 
+    /// The sysroot directory, which can be passed to `rustc` through `--sysroot`
+    pub fn as_path(&self) -> &Path {
+        self.path.as_path_unlocked()
+    }
+
+    /// The root of the project, usually the workspace, that uses the sysroot
+    pub fn project(&self) -> &Path {
+        &self.project
+    }
+
+    /// The file in which the outputs of `rustc --print target-list` and `rustc --print cfg`
+    /// are cached
+    pub fn queries_file(&self) -> PathBuf {
+        self.as_path().join(".rustc-queries.json")
+    }
+
     fn path(&self, triple: &str) -> Filesystem {
         self.path.join("lib").join("rustlib").join(triple)
     }
@@ -60,16 +347,199 @@ impl Home {
     pub fn lock_ro(&self, triple: &str) -> Result<FileLock> {
         let fs = self.path(triple);
 
-        fs.open_ro(".sentinel", &format!("{}'s sysroot", triple))
-            .chain_err(|| format!("couldn't lock {}'s sysroot as read-only", triple))
+        let what = format!("{}'s sysroot", triple);
+        flock::chain_lock_err(fs.open_ro(sentinel()?, &what), &what, || {
+            format!("couldn't lock {}'s sysroot as read-only", triple)
+        })
+    }
+
+    /// Checks that the sysroot contains the artifacts for `triple`, so that a sysroot that was
+    /// built for another target, e.g. a prebuilt one, fails early instead of with missing crates
+    pub fn check_triple(&self, triple: &str) -> Result<()> {
+        fn has_core(dir: &Path) -> bool {
+            dir.join("lib").read_dir().is_ok_and(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .any(|e| e.file_name().to_string_lossy().starts_with("libcore-"))
+            })
+        }
+
+        if has_core(self.path(triple).as_path_unlocked()) {
+            return Ok(());
+        }
+
+        let rustlib = self.path.join("lib").join("rustlib");
+        let mut found = rustlib
+            .as_path_unlocked()
+            .read_dir()
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter(|e| has_core(&e.path()))
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        found.sort();
+        Err(ErrorKind::SysrootTargetMismatch(
+            self.as_path().to_owned(),
+            triple.to_owned(),
+            found,
+        ))?
+    }
+
+    /// Returns the `sysroot-info.json` of the sysroot for `triple`, if it was built
+    pub fn sysroot_info(&self, triple: &str) -> Result<Option<String>> {
+        let lock = self.lock_ro(triple)?;
+        let file = lock.parent().join(info::FILE);
+        if file.exists() {
+            util::read(&file).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Records that the sysroot for `triple` was used just now
+    ///
+    /// A shared lock suffices because `gc` only evicts sysroots under the exclusive one. A
+    /// read-only sysroot can't be evicted either, so it's fine that its use isn't recorded.
+    pub fn touch(&self, triple: &str) -> Result<()> {
+        let lock = self.lock_ro(triple)?;
+        match gc::touch(&lock.parent().join(LAST_USED)) {
+            Err(_) if !util::is_writable(lock.parent()) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Evicts the sysroots selected by `policy`, except the ones for the triples in `keep`
+    pub fn gc(&self, policy: &Policy, keep: &[&str]) -> Result<Vec<Entry>> {
+        let rustlib = self.path.join("lib").join("rustlib");
+        let dir = rustlib.as_path_unlocked();
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut entries = vec![];
+        for entry in dir
+            .read_dir()
+            .chain_err(|| format!("couldn't read {}", dir.display()))?
+        {
+            let entry = entry.chain_err(|| format!("couldn't read {}", dir.display()))?;
+            let triple = entry.file_name().to_string_lossy().into_owned();
+            if !entry.path().is_dir() || keep.contains(&&*triple) {
+                continue;
+            }
+            // An evicted sysroot only keeps its lock file and the files that xbuild didn't
+            // write, so it isn't reported again
+            let evicted = entry.path().read_dir().ok().is_none_or(|mut files| {
+                files.all(|file| {
+                    file.ok().is_some_and(|f| {
+                        !is_sysroot_entry(&f.file_name()) && f.file_name() != LAST_USED
+                    })
+                })
+            });
+            if evicted {
+                continue;
+            }
+            entries.push(Entry {
+                last_used: gc::last_used(&entry.path().join(LAST_USED)),
+                size: util::dir_size(&entry.path()),
+                name: triple,
+            });
+        }
+
+        // Sysroots in a read-only home, e.g. a prebuilt one, are kept
+        let evict = policy.select(&entries, gc::now());
+        if !evict.is_empty() && !util::is_writable(dir) {
+            return Ok(vec![]);
+        }
+
+        let mut removed = vec![];
+        for i in evict {
+            let triple = &entries[i].name;
+            let lock = self.lock_rw(triple)?;
+            lock.remove_siblings(|name| is_sysroot_entry(name) || name == LAST_USED)
+                .chain_err(|| format!("couldn't clear {}", lock.path().display()))?;
+            removed.push(Entry {
+                name: format!("{}'s sysroot in {}", triple, self.display()),
+                last_used: entries[i].last_used,
+                size: entries[i].size,
+            });
+        }
+
+        Ok(removed)
     }
 
     pub fn lock_rw(&self, triple: &str) -> Result<FileLock> {
         let fs = self.path(triple);
 
-        fs.open_rw(".sentinel", &format!("{}'s sysroot", triple))
-            .chain_err(|| format!("couldn't lock {}'s sysroot in {} as read-write", triple, fs.display()))
+        let what = format!("{}'s sysroot", triple);
+        flock::chain_lock_err(fs.open_rw(sentinel()?, &what), &what, || {
+            format!(
+                "couldn't lock {}'s sysroot in {} as read-write",
+                triple,
+                fs.display()
+            )
+        })
+    }
+}
+
+/// The entries of the directory of a sysroot that xbuild writes when it builds the sysroot,
+/// see `is_sysroot_entry`
+const SYSROOT_ENTRIES: &[&str] = &[
+    "lib",
+    ".hash",
+    ".manifest",
+    ".fingerprint",
+    ".target-spec",
+    info::FILE,
+];
+
+/// The prefix of the directories that a sysroot is built in before it's moved to `lib`
+pub const TMP_LIB_PREFIX: &str = ".tmp-lib-";
+
+/// The file in the directory of a sysroot that records when it was last used, see `Home::gc`
+const LAST_USED: &str = ".last-used";
+
+/// Whether the entry `name` of the directory of a sysroot belongs to its build, which replaces
+/// it
+///
+/// Only these entries are removed before the sysroot is rebuilt, so that other files, e.g.
+/// ones that a `sysroot_post_build` hook added next to `lib`, are kept.
+pub fn is_sysroot_entry(name: &OsStr) -> bool {
+    SYSROOT_ENTRIES.iter().any(|e| name == *e)
+        || name.to_str().is_some_and(|n| n.starts_with(TMP_LIB_PREFIX))
+}
+
+/// The name of the lock file of a sysroot, from `XBUILD_SENTINEL_NAME`, e.g. to avoid a
+/// collision with other tools that keep a `.sentinel` in the same directory
+fn sentinel() -> Result<String> {
+    let name = match env::var("XBUILD_SENTINEL_NAME") {
+        Ok(name) => name,
+        Err(_) => return Ok(".sentinel".to_owned()),
+    };
+    let path = Path::new(&name);
+    if path.components().count() != 1
+        || path.file_name().is_none()
+        || is_sysroot_entry(OsStr::new(&name))
+        || name == LAST_USED
+    {
+        bail!(
+            "XBUILD_SENTINEL_NAME must be a file name that isn't used by the sysroot (found `{}`)",
+            name
+        );
     }
+    Ok(name)
+}
+
+/// Locks the sysroots of `triple` and of the `host`, if it's needed, as read-only for the
+/// duration of the build
+fn lock_sysroots(
+    home: &Home,
+    triple: &str,
+    host: Option<&str>,
+) -> (Option<Result<FileLock>>, Result<FileLock>) {
+    (host.map(|host| home.lock_ro(host)), home.lock_ro(triple))
 }
 
 pub fn home(root: &Path, config: &Config) -> Result<Home> {
@@ -82,6 +552,223 @@ pub fn home(root: &Path, config: &Config) -> Result<Home> {
     };
 
     Ok(Home {
-        path: Filesystem::new(path),
+        path: Filesystem::new(util::simplify_path(path)),
+        project: root.to_owned(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn home(dir: &Path) -> Home {
+        Home {
+            path: Filesystem::new(dir.to_owned()),
+            project: dir.to_owned(),
+        }
+    }
+
+    #[test]
+    fn gc_evicts_unused_sysroots_once() {
+        let td = TempDir::new("xbuild").unwrap();
+        let home = home(td.path());
+        let rustlib = td.path().join("lib").join("rustlib");
+        for triple in &["old-target", "kept-target"] {
+            let lib = rustlib.join(triple).join("lib");
+            fs::create_dir_all(&lib).unwrap();
+            fs::write(lib.join("libcore.rlib"), "").unwrap();
+        }
+        let policy = Policy {
+            max_age: Some(60),
+            max_size: None,
+        };
+
+        let removed = home.gc(&policy, &["kept-target"]).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].name.starts_with("old-target"));
+        assert!(!rustlib.join("old-target").join("lib").exists());
+        assert!(rustlib.join("kept-target").join("lib").exists());
+
+        // Only the lock file is left, which isn't evicted again
+        assert!(home.gc(&policy, &["kept-target"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn link_args_with_spaces_stay_one_argument() {
+        let home = home(Path::new("target/sysroot"));
+        let flags = Rustflags::new(vec![
+            "-C".to_owned(),
+            "link-arg=--defsym=STACK_SIZE=64 K".to_owned(),
+        ]);
+
+        let mut cmd = Command::new("cargo");
+        apply_flags(&mut cmd, "rustflags", &flags, &home, "1.78.0", false).unwrap();
+        let encoded = cmd
+            .get_envs()
+            .find(|&(k, _)| k == "CARGO_ENCODED_RUSTFLAGS")
+            .and_then(|(_, v)| v)
+            .and_then(|v| v.to_str())
+            .unwrap()
+            .to_owned();
+        let args = encoded.split('\x1f').collect::<Vec<_>>();
+        assert!(
+            args.contains(&"link-arg=--defsym=STACK_SIZE=64 K"),
+            "{:?}",
+            args
+        );
+        assert!(cmd.get_envs().any(|(k, v)| k == "RUSTFLAGS" && v.is_none()));
+
+        // Older cargos split `RUSTFLAGS` on whitespace
+        let mut cmd = Command::new("cargo");
+        let err = apply_flags(&mut cmd, "rustflags", &flags, &home, "1.54.0", false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("use a cargo with `CARGO_ENCODED_RUSTFLAGS` support"));
+    }
+
+    #[test]
+    fn build_scripts_see_sysroot_and_target() {
+        let build_script_env = |cmode: &CompilationMode| {
+            let mut cmd = Command::new("cargo");
+            let home = home(Path::new("target/sysroot"));
+            apply_build_script_env(&mut cmd, cmode, &home, Path::new("/project"));
+            cmd.get_envs()
+                .map(|(k, v)| (k.to_owned(), v.map(|v| v.to_owned())))
+                .collect::<Vec<_>>()
+        };
+
+        let custom = CompilationMode::Cross(Target::Custom {
+            json: PathBuf::from("x86_64-my_os.json"),
+            triple: "x86_64-my_os".to_owned(),
+            orig_triple: "x86_64-my_os".to_owned(),
+        });
+        let envs = build_script_env(&custom);
+        assert!(envs.contains(&(
+            "XBUILD_SYSROOT".into(),
+            Some("/project/target/sysroot".into())
+        )));
+        assert!(envs.contains(&("XBUILD_TARGET".into(), Some("x86_64-my_os".into()))));
+        assert!(envs.contains(&(
+            "XBUILD_TARGET_SPEC_PATH".into(),
+            Some("/project/x86_64-my_os.json".into())
+        )));
+
+        let native = CompilationMode::Native("x86_64-unknown-linux-gnu".to_owned());
+        let envs = build_script_env(&native);
+        assert!(envs.contains(&(
+            "XBUILD_TARGET".into(),
+            Some("x86_64-unknown-linux-gnu".into())
+        )));
+        assert!(!envs.iter().any(|(k, _)| k == "XBUILD_TARGET_SPEC_PATH"));
+    }
+
+    #[test]
+    fn sysroot_without_the_triple_is_an_error() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let home = home(td.path());
+        let lib = td.path().join("lib/rustlib/x86_64-unknown-none/lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(lib.join("libcore-0123abcd.rlib"), "").unwrap();
+
+        home.check_triple("x86_64-unknown-none").unwrap();
+        let err = home.check_triple("thumbv7m-none-eabi").unwrap_err();
+        match *err.kind() {
+            ErrorKind::SysrootTargetMismatch(_, ref triple, ref found) => {
+                assert_eq!(triple, "thumbv7m-none-eabi");
+                assert_eq!(found, &["x86_64-unknown-none"]);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(err.to_string().ends_with(
+            "contains no artifacts for `thumbv7m-none-eabi`, only for `x86_64-unknown-none`"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_sysroot_is_used_without_writing() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = TempDir::new("xbuild-test").unwrap();
+        let home = home(td.path());
+        let dir = td.path().join("lib/rustlib/thumbv7m-none-eabi");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/libcore-0123abcd.rlib"), "").unwrap();
+        fs::write(dir.join(".hash"), "42").unwrap();
+        let files = |dir: &Path| {
+            let mut files = fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let before = files(&dir);
+        let set_mode = |mode| {
+            for dir in &[&*dir, &*dir.join("lib")] {
+                fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+            }
+        };
+
+        set_mode(0o555);
+        let result = home.touch("thumbv7m-none-eabi").and_then(|()| {
+            let lock = home.lock_ro("thumbv7m-none-eabi")?;
+            sysroot::old_hash(lock.parent())
+        });
+        // Root ignores the permissions, so the directory is only read-only for other users
+        let read_only = !util::is_writable(&dir);
+        let after = files(&dir);
+        set_mode(0o755);
+
+        assert_eq!(result.unwrap(), Some(42));
+        if read_only {
+            assert_eq!(before, after);
+        }
+    }
+
+    #[test]
+    fn cross_build_without_host_crates_skips_the_host_lock() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let home = home(td.path());
+        let rustlib = td.path().join("lib/rustlib");
+
+        let (host, target) = lock_sysroots(&home, "thumbv7m-none-eabi", None);
+        assert!(host.is_none());
+        target.unwrap();
+        assert!(!rustlib.join("x86_64-unknown-linux-gnu").exists());
+
+        let (host, _) = lock_sysroots(
+            &home,
+            "thumbv7m-none-eabi",
+            Some("x86_64-unknown-linux-gnu"),
+        );
+        host.unwrap().unwrap();
+        assert!(rustlib.join("x86_64-unknown-linux-gnu").exists());
+    }
+
+    #[test]
+    fn clearing_a_sysroot_keeps_files_that_xbuild_did_not_write() {
+        let td = TempDir::new("xbuild-test").unwrap();
+        let home = home(td.path());
+        let lock = home.lock_rw("thumbv7m-none-eabi").unwrap();
+        let dir = lock.parent();
+        for d in &["lib", ".tmp-lib-1234"] {
+            fs::create_dir(dir.join(d)).unwrap();
+        }
+        for f in &[".hash", ".manifest", info::FILE, ".last-used", "notes.txt"] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+
+        lock.remove_siblings(is_sysroot_entry).unwrap();
+        let mut left = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, [".last-used", ".sentinel", "notes.txt"]);
+    }
+}